@@ -1,23 +1,57 @@
 // filesystem.rs
+use aes_gcm::aead::{Aead, AeadCore, KeyInit, OsRng};
+use aes_gcm::{Aes256Gcm, Nonce};
 use flate2::Compression;
 use flate2::read::DeflateDecoder;
 use flate2::write::DeflateEncoder;
+use fs2::FileExt;
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::fmt;
 use std::fs::{File, OpenOptions};
 use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::ops::BitOr;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{SystemTime, UNIX_EPOCH};
 
 const SECTOR_SIZE: usize = 512;
-const CLUSTER_SIZE: usize = 4 * SECTOR_SIZE; // 2KB
-const MAX_CLUSTERS: usize = 1024; // 支持最多1024个簇
 const FAT_ENTRIES_PER_SECTOR: usize = SECTOR_SIZE / 4; // 每个FAT项4字节
-const FAT_SIZE_SECTORS: usize =
-    (MAX_CLUSTERS + FAT_ENTRIES_PER_SECTOR - 1) / FAT_ENTRIES_PER_SECTOR;
 
 const BOOT_SECTOR_COUNT: usize = 1;
 const FAT_START_SECTOR: usize = BOOT_SECTOR_COUNT;
-const ROOT_DIR_START_SECTOR: usize = FAT_START_SECTOR + FAT_SIZE_SECTORS;
-const ROOT_DIR_SECTORS: usize = 4;
-const DATA_START_SECTOR: usize = ROOT_DIR_START_SECTOR + ROOT_DIR_SECTORS;
-const DATA_SECTORS: usize = MAX_CLUSTERS * (CLUSTER_SIZE / SECTOR_SIZE);
+
+// 默认布局，格式化时可通过 FormatOptions 修改，挂载时从启动扇区读回
+const DEFAULT_CLUSTER_SIZE: usize = 4 * SECTOR_SIZE; // 2KB
+const DEFAULT_MAX_CLUSTERS: usize = 1024; // 支持最多1024个簇
+const DEFAULT_ROOT_DIR_SECTORS: usize = 4;
+const MAX_SECTORS_PER_CLUSTER: usize = 128; // 启动扇区中每簇扇区数只占1字节
+
+// AES-GCM nonce长度，加密文件的簇链以nonce开头
+const NONCE_SIZE: usize = 12;
+
+// 启动扇区校验和（CRC32）的位置，为0表示旧版镜像，跳过校验
+const BOOT_CHECKSUM_OFFSET: usize = 23;
+
+// 卷标的位置和长度，与文件系统标识一样用空格补齐；旧版镜像此处为0，视为没有卷标
+const LABEL_OFFSET: usize = 27;
+const LABEL_SIZE: usize = 11;
+
+// 格式版本号的位置。旧版镜像此处为0，按版本1处理；挂载时拒绝比当前版本新的镜像。
+// 磁盘格式发生不兼容的变化时递增 FORMAT_VERSION，旧版本镜像在 mount 中按版本号分别处理。
+// 版本2：有备份FAT，根目录后移；只有一份FAT的镜像仍写版本1，旧版tinyfs照常读取
+// 版本3：多字节整数按大端存储；小端镜像仍按上面的规则写版本1或2
+const FORMAT_VERSION_OFFSET: usize = 38;
+const FORMAT_VERSION: u8 = 3;
+
+// FAT份数（第14字节）为2时，主FAT的CRC32存放在这里，每次修改FAT都会更新
+const FAT_COPIES_OFFSET: usize = 14;
+const FAT_CHECKSUM_OFFSET: usize = 39;
+
+// 脏标记：以读写方式格式化或挂载时置1，正常关闭时清0。挂载时为1说明上次没有正常关闭
+const DIRTY_OFFSET: usize = 43;
+
+// 字节序：0为小端（旧版镜像），1为大端。单字节字段，不受字节序影响，挂载时最先读取
+const ENDIANNESS_OFFSET: usize = 44;
 
 // FAT特殊标记
 const FAT_EOC: u32 = 0xFFFFFFFF; // End of Chain
@@ -28,18 +62,115 @@ const FAT_FREE: u32 = 0x00000000; // 空闲簇
 const DIR_ENTRY_SIZE: usize = 64;
 const MAX_FILENAME_LENGTH: usize = 32;
 
+// 内联文件：数据直接存放在目录项中，首簇号记为1（簇号从2开始，1不会出现在正常的簇链中）。
+// 可用的是压缩后大小字段（36..40，内联时等于原始大小，不必记录）和目录项末尾的空闲字节（56..64）
+const INLINE_CLUSTER: u32 = 1;
+const MAX_INLINE_SIZE: usize = 12;
+
+// 备注：非内联文件目录项末尾的空闲字节（56..64），UTF-8，不足8字节补0；内联文件的这几个字节存放数据
+const MAX_COMMENT_SIZE: usize = 8;
+
 fn compress_data(data: &[u8]) -> io::Result<Vec<u8>> {
     let mut encoder = DeflateEncoder::new(Vec::new(), Compression::best());
     encoder.write_all(data)?;
     encoder.finish()
 }
 
-fn decompress_data(compressed_data: &[u8]) -> io::Result<Vec<u8>> {
+// 复用已有的编码器压缩：reset 结束当前的流并取回输出，同时换上 out 作为下一次的输出缓冲区，
+// 编码器内部的状态和缓冲区都不需要重新分配
+fn compress_data_reusing(
+    encoder: &mut DeflateEncoder<Vec<u8>>,
+    data: &[u8],
+    out: Vec<u8>,
+) -> io::Result<Vec<u8>> {
+    encoder.write_all(data)?;
+    encoder.reset(out)
+}
+
+// 解压结果追加到 out 之后，调用方可以复用同一个缓冲区
+fn decompress_data(compressed_data: &[u8], out: &mut Vec<u8>) -> io::Result<()> {
     let mut decoder = DeflateDecoder::new(compressed_data);
-    let mut decompressed = Vec::new();
-    decoder.read_to_end(&mut decompressed)?;
-    Ok(decompressed)
+    decoder.read_to_end(out)?;
+    Ok(())
+}
+
+// Brotli以最高质量压缩，写入慢但压缩率高，适合文本类数据
+fn brotli_compress_data(data: &[u8]) -> io::Result<Vec<u8>> {
+    let mut compressed = Vec::new();
+    {
+        let mut encoder = brotli::CompressorWriter::new(&mut compressed, 4096, 11, 22);
+        encoder.write_all(data)?;
+    }
+    Ok(compressed)
 }
+
+fn brotli_decompress_data(compressed_data: &[u8], out: &mut Vec<u8>) -> io::Result<()> {
+    let mut decoder = brotli::Decompressor::new(compressed_data, 4096);
+    decoder.read_to_end(out)?;
+    Ok(())
+}
+
+// zstd的压缩级别与解压无关，不需要写入目录项
+const ZSTD_DEFAULT_LEVEL: i32 = 3;
+const ZSTD_MAX_LEVEL: i32 = 19;
+
+fn zstd_compress_data(data: &[u8], level: i32) -> io::Result<Vec<u8>> {
+    if !(1..=ZSTD_MAX_LEVEL).contains(&level) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "zstd压缩级别必须在1到{}之间，实际为{}",
+                ZSTD_MAX_LEVEL, level
+            ),
+        ));
+    }
+    zstd::encode_all(data, level)
+}
+
+fn zstd_decompress_data(compressed_data: &[u8], out: &mut Vec<u8>) -> io::Result<()> {
+    zstd::stream::copy_decode(compressed_data, out)
+}
+
+// 按压缩方法（1-5）解压，结果追加到 out 之后，返回方法名用于错误信息。
+// 解码中途出错时 out 中保留已经解出的部分
+fn decompress_into(
+    method: u8,
+    compressed_data: &[u8],
+    out: &mut Vec<u8>,
+) -> io::Result<&'static str> {
+    match method {
+        1 => {
+            // RLE解压
+            out.extend_from_slice(&rle_decompress_data(compressed_data));
+            Ok("RLE")
+        }
+        2 => {
+            // DEFLATE解压
+            decompress_data(compressed_data, out)?;
+            Ok("DEFLATE")
+        }
+        3 => {
+            // RLE2解压
+            rle2_decompress_data(compressed_data, out)?;
+            Ok("RLE2")
+        }
+        4 => {
+            // Brotli解压
+            brotli_decompress_data(compressed_data, out)?;
+            Ok("Brotli")
+        }
+        5 => {
+            // zstd解压
+            zstd_decompress_data(compressed_data, out)?;
+            Ok("zstd")
+        }
+        method => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("不支持的压缩方法: {}，目录项可能已损坏", method),
+        )),
+    }
+}
+
 fn rle_compress_data(data: &[u8]) -> Vec<u8> {
     if data.is_empty() {
         return Vec::new();
@@ -93,6 +224,587 @@ fn rle_decompress_data(compressed_data: &[u8]) -> Vec<u8> {
     result
 }
 
+// RLE2：每段以一个控制字节开头，最高位为1表示重复段，后跟1个字节，重复 (低7位 + 3) 次；
+// 最高位为0表示字面段，后跟 (低7位 + 1) 个原样字节。不可压缩的数据每128字节只多1字节
+const RLE2_MIN_RUN: usize = 3;
+const RLE2_MAX_RUN: usize = 0x7F + RLE2_MIN_RUN;
+const RLE2_MAX_LITERAL: usize = 0x80;
+
+fn rle2_compress_data(data: &[u8]) -> Vec<u8> {
+    let mut result = Vec::new();
+    let mut literal_start = 0;
+    let mut i = 0;
+
+    // 把 [literal_start, end) 之间积累的字面字节按最大长度分段输出
+    let flush_literals = |result: &mut Vec<u8>, start: usize, end: usize| {
+        for chunk in data[start..end].chunks(RLE2_MAX_LITERAL) {
+            result.push((chunk.len() - 1) as u8);
+            result.extend_from_slice(chunk);
+        }
+    };
+
+    while i < data.len() {
+        let byte = data[i];
+        let mut run = 1;
+        while i + run < data.len() && data[i + run] == byte && run < RLE2_MAX_RUN {
+            run += 1;
+        }
+
+        if run >= RLE2_MIN_RUN {
+            flush_literals(&mut result, literal_start, i);
+            result.push(0x80 | (run - RLE2_MIN_RUN) as u8);
+            result.push(byte);
+            i += run;
+            literal_start = i;
+        } else {
+            i += run;
+        }
+    }
+    flush_literals(&mut result, literal_start, data.len());
+
+    result
+}
+
+fn rle2_decompress_data(compressed_data: &[u8], out: &mut Vec<u8>) -> io::Result<()> {
+    let truncated = || io::Error::new(io::ErrorKind::InvalidData, "RLE2数据被截断");
+    let mut i = 0;
+
+    while i < compressed_data.len() {
+        let control = compressed_data[i] as usize;
+        i += 1;
+
+        if control & 0x80 != 0 {
+            let byte = *compressed_data.get(i).ok_or_else(truncated)?;
+            let run = (control & 0x7F) + RLE2_MIN_RUN;
+            out.resize(out.len() + run, byte);
+            i += 1;
+        } else {
+            let literal = compressed_data
+                .get(i..i + control + 1)
+                .ok_or_else(truncated)?;
+            out.extend_from_slice(literal);
+            i += control + 1;
+        }
+    }
+
+    Ok(())
+}
+
+// 通配符匹配：'*' 匹配任意长度字符，'?' 匹配单个字符，区分大小写
+fn glob_match(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+
+    let (mut p, mut n) = (0, 0);
+    let mut star: Option<usize> = None;
+    let mut star_n = 0;
+
+    while n < name.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == name[n]) {
+            p += 1;
+            n += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            star = Some(p);
+            star_n = n;
+            p += 1;
+        } else if let Some(star_p) = star {
+            // 回溯：让上一个 '*' 多吞一个字符
+            p = star_p + 1;
+            star_n += 1;
+            n = star_n;
+        } else {
+            return false;
+        }
+    }
+
+    while p < pattern.len() && pattern[p] == '*' {
+        p += 1;
+    }
+
+    p == pattern.len()
+}
+
+// 计算启动扇区的CRC32，校验和字段本身按0参与计算
+fn boot_checksum(boot_sector: &[u8]) -> u32 {
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(&boot_sector[..BOOT_CHECKSUM_OFFSET]);
+    hasher.update(&[0u8; 4]);
+    hasher.update(&boot_sector[BOOT_CHECKSUM_OFFSET + 4..]);
+    hasher.finalize()
+}
+
+/// 磁盘上多字节整数（启动扇区字段、FAT项、目录项中的大小和簇号等）的字节序，格式化时选择。
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Endianness {
+    #[default]
+    Little,
+    Big,
+}
+
+impl Endianness {
+    fn u16_bytes(self, value: u16) -> [u8; 2] {
+        match self {
+            Endianness::Little => value.to_le_bytes(),
+            Endianness::Big => value.to_be_bytes(),
+        }
+    }
+
+    fn u32_bytes(self, value: u32) -> [u8; 4] {
+        match self {
+            Endianness::Little => value.to_le_bytes(),
+            Endianness::Big => value.to_be_bytes(),
+        }
+    }
+
+    fn read_u16(self, bytes: &[u8]) -> u16 {
+        let bytes = bytes[..2].try_into().unwrap();
+        match self {
+            Endianness::Little => u16::from_le_bytes(bytes),
+            Endianness::Big => u16::from_be_bytes(bytes),
+        }
+    }
+
+    fn read_u32(self, bytes: &[u8]) -> u32 {
+        let bytes = bytes[..4].try_into().unwrap();
+        match self {
+            Endianness::Little => u32::from_le_bytes(bytes),
+            Endianness::Big => u32::from_be_bytes(bytes),
+        }
+    }
+
+    // 从启动扇区读取，旧版镜像此处为0，即小端
+    fn from_boot_sector(boot_sector: &[u8]) -> io::Result<Self> {
+        match boot_sector[ENDIANNESS_OFFSET] {
+            0 => Ok(Endianness::Little),
+            1 => Ok(Endianness::Big),
+            other => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("启动扇区中的字节序标记({})无效", other),
+            )),
+        }
+    }
+}
+
+// 目录项在内存中总是按小端解析（见 FileEntry::from_bytes），大端镜像在读写根目录时整体转换。
+// 各字段只是颠倒字节顺序，两个方向的转换相同；`from` 是转换前的字节序，用来识别内联文件，
+// 内联文件压缩后大小字段里存的是数据，不能颠倒
+fn convert_dir_entries(root_dir_data: &mut [u8], from: Endianness) {
+    for entry in root_dir_data.chunks_exact_mut(DIR_ENTRY_SIZE) {
+        let inline = from.read_u32(&entry[40..44]) == INLINE_CLUSTER;
+        entry[32..36].reverse();
+        if !inline {
+            entry[36..40].reverse();
+        }
+        entry[40..44].reverse();
+        entry[50..54].reverse();
+        entry[54..56].reverse();
+    }
+}
+
+/// 压缩方法，转换为 `u8` 即写入目录项的编号。默认为DEFLATE。
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum CompressionMethod {
+    None = 0,
+    Rle = 1,
+    #[default]
+    Deflate = 2,
+    Rle2 = 3,   // 不可压缩的数据几乎不会变大
+    Brotli = 4, // 压缩率高，写入较慢
+    Zstd = 5,
+    // 写入时按内容选择：看起来随机的数据不压缩，其余用DEFLATE。目录项记录实际选择的方法，不会出现250
+    Smart = 250,
+}
+
+impl CompressionMethod {
+    pub fn name(self) -> &'static str {
+        match self {
+            CompressionMethod::None => "无压缩",
+            CompressionMethod::Rle => "RLE",
+            CompressionMethod::Deflate => "DEFLATE",
+            CompressionMethod::Rle2 => "RLE2",
+            CompressionMethod::Brotli => "Brotli",
+            CompressionMethod::Zstd => "zstd",
+            CompressionMethod::Smart => "智能选择",
+        }
+    }
+
+    // 写入时实际使用的方法：`Smart` 根据数据开头一段的字节熵选择，其余原样返回。
+    // 能内联的小文件同样记录这里选出的方法，是否内联由 `store_file_checked` 决定
+    fn resolve(self, data: &[u8]) -> CompressionMethod {
+        if self != CompressionMethod::Smart {
+            return self;
+        }
+        let sample = &data[..data.len().min(SMART_SAMPLE_SIZE)];
+        if byte_entropy(sample) >= SMART_RANDOM_ENTROPY {
+            CompressionMethod::None
+        } else {
+            CompressionMethod::Deflate
+        }
+    }
+}
+
+// Smart 只统计开头这么多字节，大文件也只花常数时间
+const SMART_SAMPLE_SIZE: usize = 4096;
+// 每字节熵（比特）不低于此值视为随机数据（已压缩或加密），DEFLATE几乎无法再缩小
+const SMART_RANDOM_ENTROPY: f64 = 7.5;
+
+// 按字节频率计算的香农熵，单位为比特/字节，范围0~8
+fn byte_entropy(data: &[u8]) -> f64 {
+    if data.is_empty() {
+        return 0.0;
+    }
+    let mut counts = [0usize; 256];
+    for &byte in data {
+        counts[byte as usize] += 1;
+    }
+    let len = data.len() as f64;
+    counts
+        .iter()
+        .filter(|&&count| count > 0)
+        .map(|&count| {
+            let p = count as f64 / len;
+            -p * p.log2()
+        })
+        .sum()
+}
+
+impl TryFrom<u8> for CompressionMethod {
+    type Error = io::Error;
+
+    fn try_from(method: u8) -> io::Result<Self> {
+        match method {
+            0 => Ok(CompressionMethod::None),
+            1 => Ok(CompressionMethod::Rle),
+            2 => Ok(CompressionMethod::Deflate),
+            3 => Ok(CompressionMethod::Rle2),
+            4 => Ok(CompressionMethod::Brotli),
+            5 => Ok(CompressionMethod::Zstd),
+            250 => Ok(CompressionMethod::Smart),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("不支持的压缩方法: {}", method),
+            )),
+        }
+    }
+}
+
+impl From<CompressionMethod> for u8 {
+    fn from(method: CompressionMethod) -> u8 {
+        method as u8
+    }
+}
+
+// 目录项中可能出现的压缩方法，其余取值说明目录项已损坏；Smart 只在写入时使用，不会被记录
+fn is_known_method(compression_method: u8) -> bool {
+    CompressionMethod::try_from(compression_method)
+        .is_ok_and(|method| method != CompressionMethod::Smart)
+}
+
+fn compress_with(data: &[u8], compression_method: CompressionMethod) -> io::Result<Vec<u8>> {
+    match compression_method {
+        CompressionMethod::None => Ok(data.to_vec()),
+        CompressionMethod::Rle => Ok(rle_compress_data(data)),
+        CompressionMethod::Deflate => compress_data(data),
+        CompressionMethod::Rle2 => Ok(rle2_compress_data(data)),
+        CompressionMethod::Brotli => brotli_compress_data(data),
+        CompressionMethod::Zstd => zstd_compress_data(data, ZSTD_DEFAULT_LEVEL),
+        CompressionMethod::Smart => compress_with(data, compression_method.resolve(data)),
+    }
+}
+
+// 把几段数据依次交给流式压缩器，结果与压缩拼接后的数据相同，但不必先拼接；
+// RLE/RLE2只有整块压缩，仍需拼接
+fn compress_slices_with(
+    slices: &[&[u8]],
+    compression_method: CompressionMethod,
+) -> io::Result<Vec<u8>> {
+    match compression_method {
+        CompressionMethod::None => Ok(slices.concat()),
+        CompressionMethod::Deflate => {
+            let mut encoder = DeflateEncoder::new(Vec::new(), Compression::best());
+            for slice in slices {
+                encoder.write_all(slice)?;
+            }
+            encoder.finish()
+        }
+        CompressionMethod::Brotli => {
+            let mut compressed = Vec::new();
+            {
+                let mut encoder = brotli::CompressorWriter::new(&mut compressed, 4096, 11, 22);
+                for slice in slices {
+                    encoder.write_all(slice)?;
+                }
+            }
+            Ok(compressed)
+        }
+        CompressionMethod::Zstd => {
+            let mut encoder = zstd::Encoder::new(Vec::new(), ZSTD_DEFAULT_LEVEL)?;
+            for slice in slices {
+                encoder.write_all(slice)?;
+            }
+            encoder.finish()
+        }
+        CompressionMethod::Rle | CompressionMethod::Rle2 | CompressionMethod::Smart => {
+            compress_with(&slices.concat(), compression_method)
+        }
+    }
+}
+
+/// 目录项中压缩方法编号对应的显示名称，未知编号返回"未知"。
+pub fn method_name(method: u8) -> &'static str {
+    CompressionMethod::try_from(method).map_or("未知", CompressionMethod::name)
+}
+
+// 默认时钟；系统时间早于1970年时记为0，即未知
+fn system_time_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_secs())
+}
+
+// 镜像所在的存储。正常情况下是镜像文件；测试中换成内存里的模拟后端，用来注入故障、统计读写
+trait Storage: Read + Write + Seek + Send {
+    fn size(&self) -> io::Result<u64>;
+    fn set_len(&mut self, len: u64) -> io::Result<()>;
+    fn sync_data(&mut self) -> io::Result<()>;
+    fn sync_all(&mut self) -> io::Result<()>;
+}
+
+impl Storage for File {
+    fn size(&self) -> io::Result<u64> {
+        Ok(self.metadata()?.len())
+    }
+
+    fn set_len(&mut self, len: u64) -> io::Result<()> {
+        File::set_len(self, len)
+    }
+
+    fn sync_data(&mut self) -> io::Result<()> {
+        File::sync_data(self)
+    }
+
+    fn sync_all(&mut self) -> io::Result<()> {
+        File::sync_all(self)
+    }
+}
+
+// 对镜像文件加建议锁，锁随文件句柄关闭而释放；已被其他进程锁定时立即失败而不是等待
+fn lock_image(file: &File, exclusive: bool) -> io::Result<()> {
+    let result = if exclusive {
+        FileExt::try_lock_exclusive(file)
+    } else {
+        FileExt::try_lock_shared(file)
+    };
+    result.map_err(|e| {
+        if e.kind() == fs2::lock_contended_error().kind() {
+            io::Error::new(
+                io::ErrorKind::ResourceBusy,
+                "镜像已被其他进程锁定（AlreadyLocked）",
+            )
+        } else {
+            e
+        }
+    })
+}
+
+// 只读文件不能被覆盖或删除
+fn check_not_read_only(entry: &FileEntry) -> io::Result<()> {
+    if entry.attributes.contains(FileAttributes::READ_ONLY) {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!("文件 {} 是只读的", entry.name),
+        ));
+    }
+    Ok(())
+}
+
+// 把卷标编码为启动扇区中的定长字段，不足部分补空格
+fn encode_label(label: &str) -> io::Result<[u8; LABEL_SIZE]> {
+    if label.len() > LABEL_SIZE {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("卷标过长：{}字节，最多{}字节", label.len(), LABEL_SIZE),
+        ));
+    }
+    if label.chars().any(|c| c.is_control()) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "卷标不能包含控制字符",
+        ));
+    }
+
+    let mut field = [b' '; LABEL_SIZE];
+    field[..label.len()].copy_from_slice(label.as_bytes());
+    Ok(field)
+}
+
+/// 检查文件名能否存入目录项。
+///
+/// 拒绝空文件名、以NUL开头或含控制字符的文件名（NUL会被当作文件名结束或空闲目录项）、
+/// 含路径分隔符 `/` 或 `\` 的文件名，以及超过32字节的文件名（截断会导致不同文件名互相覆盖）。
+pub fn validate_filename(name: &str) -> io::Result<()> {
+    let invalid = |msg: String| Err(io::Error::new(io::ErrorKind::InvalidInput, msg));
+
+    if name.is_empty() {
+        return invalid("文件名不能为空".to_string());
+    }
+    if name.starts_with('\0') {
+        return invalid("文件名不能以NUL字符开头".to_string());
+    }
+    if name.chars().any(char::is_control) {
+        return invalid("文件名不能包含控制字符".to_string());
+    }
+    if name.contains(['/', '\\']) {
+        return invalid("文件名不能包含路径分隔符".to_string());
+    }
+    if name.len() > MAX_FILENAME_LENGTH {
+        return invalid(format!(
+            "文件名过长：{}字节，最多{}字节",
+            name.len(),
+            MAX_FILENAME_LENGTH
+        ));
+    }
+
+    Ok(())
+}
+
+// 某种压缩方法在整个镜像中的使用情况
+#[derive(Debug, Clone, Default)]
+pub struct MethodUsage {
+    pub files: usize,
+    pub original_bytes: u64,
+    pub compressed_bytes: u64,
+}
+
+// 单个文件的逻辑大小与实际占用的磁盘空间
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Footprint {
+    pub size: u32,            // 原始大小
+    pub compressed_size: u32, // 压缩后大小
+    pub clusters: usize,      // 簇链长度
+    pub physical_bytes: u64,  // 簇链长度 * 簇大小，包含最后一个簇中未用满的部分
+}
+
+// 整个镜像的压缩统计
+#[derive(Debug, Clone, Default)]
+pub struct ImageSummary {
+    pub total_original_bytes: u64,
+    pub total_compressed_bytes: u64,
+    pub ratio: f32, // 压缩率（压缩后/原始 * 100），与 get_compression_stats 一致
+    pub by_method: BTreeMap<u8, MethodUsage>,
+}
+
+/// 根目录槽位的使用情况和空闲簇数，由 `FileSystem::directory_stats` 返回。
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct DirStats {
+    pub total_slots: usize,
+    pub live: usize,          // 有效文件（含隐藏文件，每个链接各占一个槽位）
+    pub deleted: usize,       // 已删除文件留下的目录项，写入新文件时会被复用
+    pub free: usize,          // 从未使用过的槽位，无法解析的损坏目录项也算在这里
+    pub free_clusters: usize, // 与 `free_cluster_count` 相同
+}
+
+impl DirStats {
+    /// 已删除目录项占全部槽位的比例（0~1），较高时 `compact_directory` 能让目录更紧凑。
+    pub fn tombstone_ratio(&self) -> f32 {
+        if self.total_slots == 0 {
+            return 0.0;
+        }
+        self.deleted as f32 / self.total_slots as f32
+    }
+
+    /// 还能新建多少个目录项：已删除和从未使用的槽位都可以复用。
+    pub fn slots_available(&self) -> usize {
+        self.deleted + self.free
+    }
+
+    /// 每个新文件至少占一个簇时还能新建多少个文件，取槽位和空闲簇中较小的一个。
+    ///
+    /// 等于 `slots_available` 说明先满的是根目录（写入报“根目录已满”），否则先满的是数据区
+    /// （报“磁盘空间不足”）。不超过12字节的文件存放在目录项中不占簇，只受槽位限制。
+    pub fn remaining_files(&self) -> usize {
+        std::cmp::min(self.slots_available(), self.free_clusters)
+    }
+}
+
+// repack_all 中重新压缩过的一个文件
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RepackedFile {
+    pub name: String,
+    pub old_method: u8,
+    pub old_size: u32, // 重新压缩前后的压缩后大小
+    pub new_size: u32,
+}
+
+// repack_all 的结果，大小均为压缩后的字节数，包括未重新压缩的文件
+#[derive(Debug, Clone, Default)]
+pub struct RepackReport {
+    pub before_bytes: u64,
+    pub after_bytes: u64,
+    pub files: Vec<RepackedFile>,
+    pub skipped: Vec<(String, io::ErrorKind)>, // 未处理的文件及原因：空间不足或加密/只读
+}
+
+/// 长时间操作的进度：共 `total` 个单位，已处理 `done` 个。单位是文件还是簇由具体操作决定。
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Progress {
+    pub done: usize,
+    pub total: usize,
+}
+
+impl Progress {
+    pub fn percent(&self) -> u32 {
+        if self.total == 0 {
+            return 100;
+        }
+        (self.done * 100 / self.total) as u32
+    }
+}
+
+/// `list_files_sorted` 的排序依据。
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SortKey {
+    Name,
+    Size,
+    CompressedSize,
+    Ratio,    // 压缩后大小 / 原始大小，空文件按0计
+    Modified, // 修改时间未知（0）的文件排在最前
+}
+
+// 一致性检查发现的问题
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CheckProblem {
+    // 同一个簇出现在多条簇链中（交叉链接）
+    CrossLinked {
+        cluster: u32,
+        files: Vec<String>,
+    },
+    // 簇链中出现无效簇号、空闲簇或环
+    BadChain {
+        file: String,
+        cluster: u32,
+    },
+    // 目录项记录的压缩方法不在已知范围内，文件无法解压
+    UnknownMethod {
+        file: String,
+        method: u8,
+    },
+    // 簇链长度与压缩后大小所需的簇数不一致（写入失败留下的多余簇或簇链被截断）
+    ChainLength {
+        file: String,
+        expected: usize,
+        actual: usize,
+    },
+    // 主FAT与启动扇区中记录的校验和不符（只在有备份FAT时检查），见 `repair_fat_from_backup`
+    FatChecksum,
+    // 目录项记录的链接数与实际共享这条簇链的目录项数不一致
+    LinkCount {
+        file: String,
+        expected: u8,
+        actual: usize,
+    },
+}
+
 // FileEntry
 #[derive(Debug, Clone)]
 pub struct FileEntry {
@@ -102,7 +814,14 @@ pub struct FileEntry {
     pub first_cluster: u32,
     pub is_deleted: bool,
     pub is_compressed: bool,
-    pub compression_method: u8, // 压缩方法: 0=无压缩, 1=RLE, 2=DEFLATE
+    pub compression_method: u8, // 压缩方法: 0=无压缩, 1=RLE, 2=DEFLATE, 3=RLE2, 4=Brotli, 5=zstd
+    pub is_encrypted: bool,     // 数据经AES-256-GCM加密，簇链开头是12字节的nonce
+    pub attributes: FileAttributes,
+    pub links: u8,              // 共享同一簇链的目录项数（含自身），见 `link`
+    pub modified: u32,          // 最后写入内容的时间（Unix秒），0表示未知（旧版镜像）
+    pub reserved_clusters: u16, // `reserve_file` 预留的簇链长度，0表示没有预留
+    pub inline_data: Vec<u8>,   // 内联文件的内容，见 `is_inline`；其他文件为空
+    pub comment: String,        // 备注，最多8字节，见 `set_comment`；没有备注时为空
 }
 
 impl FileEntry {
@@ -121,7 +840,30 @@ impl FileEntry {
             is_deleted: false,
             is_compressed: compression_method > 0,
             compression_method,
+            is_encrypted: false,
+            attributes: FileAttributes::NONE,
+            links: 1,
+            modified: 0,
+            reserved_clusters: 0,
+            inline_data: Vec::new(),
+            comment: String::new(),
+        }
+    }
+
+    /// 数据直接存放在目录项中、不占用任何簇的小文件（不超过12字节、不加密）。
+    ///
+    /// 内联数据总是不压缩存放（`is_compressed` 为 false），`compression_method` 仍记录写入时指定的方法。
+    pub fn is_inline(&self) -> bool {
+        self.first_cluster == INLINE_CLUSTER
+    }
+
+    // 簇链应有的长度：按压缩后大小计算，至少一个簇；有预留时为预留的簇数；内联文件没有簇链
+    fn chain_clusters(&self, cluster_size: usize) -> usize {
+        if self.is_inline() {
+            return 0;
         }
+        let needed = std::cmp::max((self.compressed_size as usize).div_ceil(cluster_size), 1);
+        std::cmp::max(needed, self.reserved_clusters as usize)
     }
 
     fn to_bytes(&self) -> [u8; DIR_ENTRY_SIZE] {
@@ -136,9 +878,20 @@ impl FileEntry {
         let size_bytes = self.size.to_le_bytes();
         entry[32..36].copy_from_slice(&size_bytes);
 
-        // 写入压缩后大小
-        let compressed_size_bytes = self.compressed_size.to_le_bytes();
-        entry[36..40].copy_from_slice(&compressed_size_bytes);
+        // 写入压缩后大小；内联文件在这里和目录项末尾存放数据
+        if self.is_inline() {
+            let mut inline = [0u8; MAX_INLINE_SIZE];
+            inline[..self.inline_data.len()].copy_from_slice(&self.inline_data);
+            entry[36..40].copy_from_slice(&inline[..4]);
+            entry[56..64].copy_from_slice(&inline[4..]);
+        } else {
+            let compressed_size_bytes = self.compressed_size.to_le_bytes();
+            entry[36..40].copy_from_slice(&compressed_size_bytes);
+
+            // 写入备注
+            let comment_bytes = self.comment.as_bytes();
+            entry[56..56 + comment_bytes.len()].copy_from_slice(comment_bytes);
+        }
 
         // 写入第一个簇号
         let cluster_bytes = self.first_cluster.to_le_bytes();
@@ -153,6 +906,21 @@ impl FileEntry {
         // 写入压缩方法
         entry[46] = self.compression_method;
 
+        // 写入加密标志
+        entry[47] = if self.is_encrypted { 1 } else { 0 };
+
+        // 写入属性位
+        entry[48] = self.attributes.0;
+
+        // 写入链接数
+        entry[49] = self.links;
+
+        // 写入修改时间
+        entry[50..54].copy_from_slice(&self.modified.to_le_bytes());
+
+        // 写入预留簇数
+        entry[54..56].copy_from_slice(&self.reserved_clusters.to_le_bytes());
+
         entry
     }
 
@@ -167,13 +935,48 @@ impl FileEntry {
             name_end += 1;
         }
 
-        let name = String::from_utf8_lossy(&bytes[0..name_end]).to_string();
+        // 损坏的目录项按空槽位处理：文件名之后必须全是0，文件名必须是不含控制字符的UTF-8。
+        // 未知的压缩方法仍然保留，由 check 报告，读取时返回错误
+        if bytes[name_end..MAX_FILENAME_LENGTH].iter().any(|&b| b != 0) {
+            return None;
+        }
+        let name = std::str::from_utf8(&bytes[0..name_end]).ok()?;
+        if name.chars().any(char::is_control) {
+            return None;
+        }
+        let name = name.to_string();
         let size = u32::from_le_bytes([bytes[32], bytes[33], bytes[34], bytes[35]]);
-        let compressed_size = u32::from_le_bytes([bytes[36], bytes[37], bytes[38], bytes[39]]);
+        let mut compressed_size = u32::from_le_bytes([bytes[36], bytes[37], bytes[38], bytes[39]]);
         let first_cluster = u32::from_le_bytes([bytes[40], bytes[41], bytes[42], bytes[43]]);
+        let mut inline_data = Vec::new();
+        let mut comment = String::new();
+        if first_cluster == INLINE_CLUSTER {
+            if size as usize > MAX_INLINE_SIZE {
+                return None;
+            }
+            inline_data.extend_from_slice(&bytes[36..40]);
+            inline_data.extend_from_slice(&bytes[56..64]);
+            inline_data.truncate(size as usize);
+            compressed_size = size;
+        } else {
+            // 旧版镜像此处为0，即没有备注；无法解析的备注同样忽略
+            let comment_bytes = &bytes[56..64];
+            let comment_end = comment_bytes
+                .iter()
+                .position(|&b| b == 0)
+                .unwrap_or(MAX_COMMENT_SIZE);
+            if let Ok(text) = std::str::from_utf8(&comment_bytes[..comment_end]) {
+                comment = text.to_string();
+            }
+        }
         let is_deleted = bytes[44] != 0;
         let is_compressed = bytes[45] != 0;
         let compression_method = bytes[46];
+        let is_encrypted = bytes[47] != 0;
+        let attributes = FileAttributes(bytes[48]);
+        let links = std::cmp::max(bytes[49], 1); // 旧版镜像此处为0，即只有一个名字
+        let modified = u32::from_le_bytes([bytes[50], bytes[51], bytes[52], bytes[53]]);
+        let reserved_clusters = u16::from_le_bytes([bytes[54], bytes[55]]);
 
         Some(FileEntry {
             name,
@@ -183,98 +986,489 @@ impl FileEntry {
             is_deleted,
             is_compressed,
             compression_method,
+            is_encrypted,
+            attributes,
+            links,
+            modified,
+            reserved_clusters,
+            inline_data,
+            comment,
         })
     }
 }
 
-pub struct FileSystem {
-    disk_image: File,
-    path: String,
+// 与 `method_name` 一致的单行描述，CLI的各种文件列表都用它输出
+impl fmt::Display for FileEntry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} (原始大小: {} 字节, 压缩后: {} 字节, 方式: {}",
+            self.name,
+            self.size,
+            self.compressed_size,
+            method_name(self.compression_method)
+        )?;
+        if !self.comment.is_empty() {
+            write!(f, ", 备注: {}", self.comment)?;
+        }
+        write!(f, ")")
+    }
 }
 
-impl FileSystem {
-    pub fn format(path: &str) -> io::Result<Self> {
-        let total_size = (DATA_START_SECTOR + DATA_SECTORS) * SECTOR_SIZE;
+/// 文件属性位，存放在目录项第48字节，旧版镜像此处为0，即没有任何属性。
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FileAttributes(u8);
 
-        let file = OpenOptions::new()
-            .create(true)
-            .read(true)
-            .write(true)
-            .truncate(true)
-            .open(path)?;
+impl FileAttributes {
+    pub const NONE: FileAttributes = FileAttributes(0);
+    /// 只读：`write_file` 覆盖和 `delete_file` 都会返回 `PermissionDenied`
+    pub const READ_ONLY: FileAttributes = FileAttributes(1);
+    /// 隐藏：不出现在 `list_files` 和 `list_files_matching` 的结果中
+    pub const HIDDEN: FileAttributes = FileAttributes(1 << 1);
 
-        file.set_len(total_size as u64)?;
-        let mut fs = FileSystem {
-            disk_image: file,
-            path: path.to_string(),
-        };
+    pub fn contains(self, other: FileAttributes) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
 
-        let mut boot_sector = vec![0u8; SECTOR_SIZE];
-        boot_sector[0] = 0xEB; // Simulate x86 jump instruction
-        boot_sector[1] = 0x3C;
-        boot_sector[2] = 0x90;
+impl BitOr for FileAttributes {
+    type Output = FileAttributes;
 
-        // Filesystem identifier "MINIFAT "
-        let fs_name = b"MINIFAT ";
-        boot_sector[3..11].copy_from_slice(fs_name);
+    fn bitor(self, rhs: FileAttributes) -> FileAttributes {
+        FileAttributes(self.0 | rhs.0)
+    }
+}
 
-        boot_sector[11] = (CLUSTER_SIZE / SECTOR_SIZE) as u8;
+/// 格式化参数，默认值即 `format` 使用的布局：2KB簇、1024个FAT项、4个根目录扇区（32个目录项）。
+///
+/// 小簇减少大量小文件的空间浪费，大簇减少大文件的FAT开销。
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FormatOptions {
+    pub cluster_size: usize, // 簇大小（字节），必须是扇区大小的2的幂倍，如512/1K/2K/4K
+    pub max_clusters: usize, // FAT项数（含保留的簇0和簇1），必须是128的倍数
+    pub root_dir_sectors: usize, // 根目录扇区数，每个扇区8个目录项
+    pub label: String,       // 卷标，最多11字节，默认为空
+    pub base_offset: u64,    // 镜像在文件中的起始偏移（字节），非0时不截断文件，用于嵌入其他文件
+    pub backup_fat: bool,    // 在主FAT之后保留一份备份FAT，见 `repair_fat_from_backup`
+    pub endianness: Endianness, // 多字节整数的字节序，默认小端；大端镜像旧版tinyfs无法挂载
+}
 
-        let reserved_sectors = BOOT_SECTOR_COUNT as u16;
-        boot_sector[12..14].copy_from_slice(&reserved_sectors.to_le_bytes());
+impl Default for FormatOptions {
+    fn default() -> Self {
+        FormatOptions {
+            cluster_size: DEFAULT_CLUSTER_SIZE,
+            max_clusters: DEFAULT_MAX_CLUSTERS,
+            root_dir_sectors: DEFAULT_ROOT_DIR_SECTORS,
+            label: String::new(),
+            base_offset: 0,
+            backup_fat: false,
+            endianness: Endianness::Little,
+        }
+    }
+}
 
-        boot_sector[14] = 1;
+/// 镜像布局：启动扇区 | FAT | [备份FAT] | 根目录 | 数据区。由 `FileSystem::geometry` 返回，挂载时从启动扇区读出。
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Geometry {
+    pub cluster_size: usize,     // 簇大小（字节）
+    pub max_clusters: usize,     // FAT项数，簇0和簇1保留
+    pub fat_sectors: usize,      // 每份FAT占用的扇区数
+    pub fat_copies: usize,       // FAT份数：1，或者2（有备份FAT）
+    pub root_dir_sectors: usize, // 根目录占用的扇区数
+    pub endianness: Endianness,  // 多字节整数的字节序
+}
 
-        let root_entries = ROOT_DIR_SECTORS * SECTOR_SIZE / DIR_ENTRY_SIZE;
-        boot_sector[15..17].copy_from_slice(&(root_entries as u16).to_le_bytes());
+impl Geometry {
+    fn new(opts: &FormatOptions) -> io::Result<Self> {
+        let invalid = |msg: String| io::Error::new(io::ErrorKind::InvalidInput, msg);
+
+        let sectors_per_cluster = opts.cluster_size / SECTOR_SIZE;
+        if !opts.cluster_size.is_multiple_of(SECTOR_SIZE)
+            || !sectors_per_cluster.is_power_of_two()
+            || sectors_per_cluster > MAX_SECTORS_PER_CLUSTER
+        {
+            return Err(invalid(format!(
+                "无效的簇大小 {}：必须是{}字节的2的幂倍，且不超过{}个扇区",
+                opts.cluster_size, SECTOR_SIZE, MAX_SECTORS_PER_CLUSTER
+            )));
+        }
 
-        let total_sectors = (DATA_START_SECTOR + DATA_SECTORS) as u32;
-        boot_sector[17..21].copy_from_slice(&total_sectors.to_le_bytes());
+        let fat_sectors = opts.max_clusters / FAT_ENTRIES_PER_SECTOR;
+        if opts.max_clusters == 0
+            || !opts.max_clusters.is_multiple_of(FAT_ENTRIES_PER_SECTOR)
+            || fat_sectors > u16::MAX as usize
+        {
+            return Err(invalid(format!(
+                "无效的簇数 {}：必须是{}的正整数倍，且FAT不超过{}个扇区",
+                opts.max_clusters,
+                FAT_ENTRIES_PER_SECTOR,
+                u16::MAX
+            )));
+        }
 
-        boot_sector[21..23].copy_from_slice(&(FAT_SIZE_SECTORS as u16).to_le_bytes());
+        let root_entries = opts.root_dir_sectors * SECTOR_SIZE / DIR_ENTRY_SIZE;
+        if opts.root_dir_sectors == 0 || root_entries > u16::MAX as usize {
+            return Err(invalid(format!(
+                "无效的根目录扇区数 {}：目录项数必须在1到{}之间",
+                opts.root_dir_sectors,
+                u16::MAX
+            )));
+        }
 
-        boot_sector[SECTOR_SIZE - 2] = 0x55;
-        boot_sector[SECTOR_SIZE - 1] = 0xAA;
+        let geometry = Geometry {
+            cluster_size: opts.cluster_size,
+            max_clusters: opts.max_clusters,
+            fat_sectors,
+            fat_copies: if opts.backup_fat { 2 } else { 1 },
+            root_dir_sectors: opts.root_dir_sectors,
+            endianness: opts.endianness,
+        };
+
+        // 总扇区数记录在启动扇区的u32字段里
+        let total_sectors = geometry.data_start_sector() as u64
+            + (geometry.max_clusters * sectors_per_cluster) as u64;
+        if total_sectors > u32::MAX as u64 {
+            return Err(invalid(format!(
+                "镜像过大：共需{}个扇区，超出u32范围",
+                total_sectors
+            )));
+        }
+
+        Ok(geometry)
+    }
+
+    // 从启动扇区的每簇扇区数、FAT份数、根目录项数和FAT扇区数字段恢复布局
+    fn from_boot_sector(boot_sector: &[u8]) -> io::Result<Self> {
+        let sectors_per_cluster = boot_sector[11] as usize;
+        let backup_fat = match boot_sector[FAT_COPIES_OFFSET] {
+            0 | 1 => false,
+            2 => true,
+            copies => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("启动扇区中的FAT份数({})无效，只支持1或2", copies),
+                ));
+            }
+        };
+        let endianness = Endianness::from_boot_sector(boot_sector)?;
+        let root_entries = endianness.read_u16(&boot_sector[15..17]) as usize;
+        let fat_sectors = endianness.read_u16(&boot_sector[21..23]) as usize;
+
+        let entries_per_sector = SECTOR_SIZE / DIR_ENTRY_SIZE;
+        if !root_entries.is_multiple_of(entries_per_sector) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("启动扇区中的根目录项数({})不是整数个扇区", root_entries),
+            ));
+        }
+
+        Self::new(&FormatOptions {
+            cluster_size: sectors_per_cluster * SECTOR_SIZE,
+            max_clusters: fat_sectors * FAT_ENTRIES_PER_SECTOR,
+            root_dir_sectors: root_entries / entries_per_sector,
+            backup_fat,
+            endianness,
+            ..FormatOptions::default()
+        })
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("启动扇区中的{}", e)))
+    }
+
+    pub fn sector_size(&self) -> usize {
+        SECTOR_SIZE
+    }
+
+    pub fn sectors_per_cluster(&self) -> usize {
+        self.cluster_size / SECTOR_SIZE
+    }
+
+    // 第 copy 份FAT的起始扇区，0为主FAT
+    fn fat_start_sector(&self, copy: usize) -> usize {
+        FAT_START_SECTOR + copy * self.fat_sectors
+    }
+
+    fn root_dir_start_sector(&self) -> usize {
+        self.fat_start_sector(self.fat_copies)
+    }
+
+    pub fn data_start_sector(&self) -> usize {
+        self.root_dir_start_sector() + self.root_dir_sectors
+    }
+
+    /// 根目录最多能容纳的文件数
+    pub fn root_entries(&self) -> usize {
+        self.root_dir_sectors * SECTOR_SIZE / DIR_ENTRY_SIZE
+    }
+
+    // 簇号上界为 cluster_limit 时镜像的总扇区数
+    fn total_sectors(&self, cluster_limit: u32) -> usize {
+        self.data_start_sector() + (cluster_limit as usize - 2) * self.sectors_per_cluster()
+    }
+
+    /// 单个文件压缩后最多能存储的字节数：受数据区大小和目录项中u32大小字段的共同限制
+    pub fn max_file_size(&self) -> u64 {
+        let data_bytes = (self.max_clusters as u64 - 2) * self.cluster_size as u64;
+        std::cmp::min(data_bytes, u32::MAX as u64)
+    }
+
+    pub fn max_filename_length(&self) -> usize {
+        MAX_FILENAME_LENGTH
+    }
+
+    fn cluster_offset(&self, cluster: u32) -> u64 {
+        (self.data_start_sector() * SECTOR_SIZE + (cluster as usize - 2) * self.cluster_size) as u64
+    }
+}
+
+/// `write_file_with_flags` 的写入语义，类似 `OpenOptions`，可用 `|` 组合。
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WriteFlags(u8);
+
+impl WriteFlags {
+    /// 文件不存在时创建；单独使用时文件已存在则报错
+    pub const CREATE: WriteFlags = WriteFlags(1);
+    /// 文件已存在时覆盖原内容（`write_file` 的行为为 `CREATE | TRUNCATE`）
+    pub const TRUNCATE: WriteFlags = WriteFlags(1 << 1);
+    /// 只创建新文件，文件已存在时报错
+    pub const CREATE_NEW: WriteFlags = WriteFlags(1 << 2);
+    /// 文件已存在时把数据追加到原内容之后，沿用原压缩方法；`reserve_file` 预留的文件交给 `append_file`
+    pub const APPEND: WriteFlags = WriteFlags(1 << 3);
+
+    pub fn contains(self, other: WriteFlags) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl BitOr for WriteFlags {
+    type Output = WriteFlags;
+
+    fn bitor(self, rhs: WriteFlags) -> WriteFlags {
+        WriteFlags(self.0 | rhs.0)
+    }
+}
+
+// 空闲簇位图：每个簇一位，置位表示已分配。由FAT推导，只存在于内存中，
+// 所有FAT写入都经过 set_next_cluster / write_fat 同步更新
+struct ClusterBitmap {
+    words: Vec<u64>,
+}
+
+impl ClusterBitmap {
+    fn from_fat(fat: &[u32]) -> Self {
+        let mut bitmap = ClusterBitmap {
+            words: vec![0; fat.len().div_ceil(64)],
+        };
+        for (cluster, &next) in fat.iter().enumerate() {
+            bitmap.set(cluster as u32, next != FAT_FREE);
+        }
+        bitmap
+    }
+
+    fn set(&mut self, cluster: u32, allocated: bool) {
+        let (word, bit) = (cluster as usize / 64, cluster % 64);
+        if let Some(word) = self.words.get_mut(word) {
+            if allocated {
+                *word |= 1 << bit;
+            } else {
+                *word &= !(1 << bit);
+            }
+        }
+    }
+
+    // 在 [start, end) 中查找第一个空闲簇，整字跳过已全部分配的64个簇
+    fn first_free(&self, start: u32, end: u32) -> Option<u32> {
+        let mut cluster = start;
+        while cluster < end {
+            let word = self.words[cluster as usize / 64] >> (cluster % 64);
+            let used = word.trailing_ones();
+            if used < 64 - cluster % 64 {
+                let free = cluster + used;
+                return (free < end).then_some(free);
+            }
+            cluster = (cluster / 64 + 1) * 64;
+        }
+        None
+    }
+
+    fn free_count(&self) -> usize {
+        let allocated: u32 = self.words.iter().map(|word| word.count_ones()).sum();
+        self.words.len() * 64 - allocated as usize
+    }
+}
 
-        fs.disk_image.seek(SeekFrom::Start(0))?;
+pub struct FileSystem {
+    disk_image: Box<dyn Storage>,
+    path: String,
+    geometry: Geometry,
+    cluster_limit: u32, // 可用簇号的上界（不含），收缩镜像后小于 max_clusters
+    bitmap: ClusterBitmap,
+    write_barriers: bool,     // 写文件时在数据和目录项之间调用 sync_data
+    overwrite_in_place: bool, // 覆盖文件时复用旧簇链，见 `set_overwrite_in_place`
+    read_only: bool,          // 通过 mount_shared 挂载，镜像以只读方式打开
+    base_offset: u64,         // 镜像在文件中的起始偏移，所有定位都加上它
+    default_method: CompressionMethod, // write_file 未指定压缩方法时使用，不写入镜像
+    was_dirty: bool,          // 挂载时脏标记已经是1，见 `is_dirty`
+    marked_dirty: bool,       // 本次挂载置了脏标记，关闭时需要清除
+    clock: fn() -> u64,       // 写入目录项的修改时间（Unix秒），见 `set_time_source`
+    fat_checksum_stale: bool, // 主FAT改动后还没有重新计算校验和，见 `sync_fat_checksum`
+}
+
+impl FileSystem {
+    pub fn format(path: &str) -> io::Result<Self> {
+        Self::format_with(path, FormatOptions::default())
+    }
+
+    /// 按指定的簇大小、簇数和根目录大小格式化镜像，布局写入启动扇区，挂载时读回。
+    pub fn format_with(path: &str, opts: FormatOptions) -> io::Result<Self> {
+        // 参数无效时不创建也不清空文件
+        Geometry::new(&opts)?;
+        encode_label(&opts.label)?;
+
+        // 先加锁再清空，不能截断其他进程正在使用的镜像
+        let file = OpenOptions::new()
+            .create(true)
+            .read(true)
+            .write(true)
+            .truncate(false)
+            .open(path)?;
+        lock_image(&file, true)?;
+        Self::format_storage(Box::new(file), path, opts)
+    }
+
+    fn format_storage(
+        mut storage: Box<dyn Storage>,
+        path: &str,
+        opts: FormatOptions,
+    ) -> io::Result<Self> {
+        let geometry = Geometry::new(&opts)?;
+        let label = encode_label(&opts.label)?;
+        let total_sectors =
+            geometry.data_start_sector() + geometry.max_clusters * geometry.sectors_per_cluster();
+
+        // 镜像独占整个文件时重新建立文件；嵌入其他文件时只在不够长时补齐，不动偏移之前和镜像之后的内容
+        let image_len = (total_sectors * SECTOR_SIZE) as u64;
+        if opts.base_offset == 0 {
+            storage.set_len(0)?;
+            storage.set_len(image_len)?;
+        } else if storage.size()? < opts.base_offset + image_len {
+            storage.set_len(opts.base_offset + image_len)?;
+        }
+        let mut fs = FileSystem {
+            disk_image: storage,
+            path: path.to_string(),
+            geometry,
+            cluster_limit: geometry.max_clusters as u32,
+            bitmap: ClusterBitmap::from_fat(&[]),
+            write_barriers: true,
+            overwrite_in_place: false,
+            read_only: false,
+            base_offset: opts.base_offset,
+            default_method: CompressionMethod::default(),
+            was_dirty: false,
+            marked_dirty: false,
+            clock: system_time_secs,
+            fat_checksum_stale: false,
+        };
+
+        let mut boot_sector = vec![0u8; SECTOR_SIZE];
+        boot_sector[0] = 0xEB; // Simulate x86 jump instruction
+        boot_sector[1] = 0x3C;
+        boot_sector[2] = 0x90;
+
+        // Filesystem identifier "MINIFAT "
+        let fs_name = b"MINIFAT ";
+        boot_sector[3..11].copy_from_slice(fs_name);
+
+        boot_sector[11] = geometry.sectors_per_cluster() as u8;
+
+        let endianness = geometry.endianness;
+        boot_sector[ENDIANNESS_OFFSET] = (endianness == Endianness::Big) as u8;
+
+        let reserved_sectors = BOOT_SECTOR_COUNT as u16;
+        boot_sector[12..14].copy_from_slice(&endianness.u16_bytes(reserved_sectors));
+
+        boot_sector[FAT_COPIES_OFFSET] = geometry.fat_copies as u8;
+
+        let root_entries = geometry.root_entries();
+        boot_sector[15..17].copy_from_slice(&endianness.u16_bytes(root_entries as u16));
+
+        boot_sector[17..21].copy_from_slice(&endianness.u32_bytes(total_sectors as u32));
+
+        boot_sector[21..23].copy_from_slice(&endianness.u16_bytes(geometry.fat_sectors as u16));
+
+        boot_sector[LABEL_OFFSET..LABEL_OFFSET + LABEL_SIZE].copy_from_slice(&label);
+
+        // 只有一份FAT的小端镜像布局与版本1相同，写版本1让旧版tinyfs也能挂载
+        boot_sector[FORMAT_VERSION_OFFSET] = if endianness == Endianness::Big {
+            3
+        } else if geometry.fat_copies > 1 {
+            2
+        } else {
+            1
+        };
+
+        boot_sector[SECTOR_SIZE - 2] = 0x55;
+        boot_sector[SECTOR_SIZE - 1] = 0xAA;
+
+        let checksum = boot_checksum(&boot_sector);
+        boot_sector[BOOT_CHECKSUM_OFFSET..BOOT_CHECKSUM_OFFSET + 4]
+            .copy_from_slice(&endianness.u32_bytes(checksum));
+
+        fs.seek_to(0)?;
         fs.disk_image.write_all(&boot_sector)?;
 
         let mut fat_sector = vec![0u8; SECTOR_SIZE];
-        fat_sector[0..4].copy_from_slice(&FAT_EOC.to_le_bytes());
-        fat_sector[4..8].copy_from_slice(&FAT_EOC.to_le_bytes());
-
-        fs.disk_image
-            .seek(SeekFrom::Start((FAT_START_SECTOR * SECTOR_SIZE) as u64))?;
-        fs.disk_image.write_all(&fat_sector)?;
+        fat_sector[0..4].copy_from_slice(&endianness.u32_bytes(FAT_EOC));
+        fat_sector[4..8].copy_from_slice(&endianness.u32_bytes(FAT_EOC));
 
         let zero_sector = vec![0u8; SECTOR_SIZE];
-        for i in 1..FAT_SIZE_SECTORS {
-            fs.disk_image.seek(SeekFrom::Start(
-                ((FAT_START_SECTOR + i) * SECTOR_SIZE) as u64,
-            ))?;
-            fs.disk_image.write_all(&zero_sector)?;
+        for copy in 0..geometry.fat_copies {
+            let fat_start = geometry.fat_start_sector(copy);
+            fs.seek_to((fat_start * SECTOR_SIZE) as u64)?;
+            fs.disk_image.write_all(&fat_sector)?;
+
+            for i in 1..geometry.fat_sectors {
+                fs.seek_to(((fat_start + i) * SECTOR_SIZE) as u64)?;
+                fs.disk_image.write_all(&zero_sector)?;
+            }
         }
+        fs.update_fat_checksum()?;
+        fs.set_dirty_flag(true)?;
 
-        for i in 0..ROOT_DIR_SECTORS {
-            fs.disk_image.seek(SeekFrom::Start(
-                ((ROOT_DIR_START_SECTOR + i) * SECTOR_SIZE) as u64,
-            ))?;
+        for i in 0..geometry.root_dir_sectors {
+            fs.seek_to(((geometry.root_dir_start_sector() + i) * SECTOR_SIZE) as u64)?;
             fs.disk_image.write_all(&zero_sector)?;
         }
 
         fs.disk_image.flush()?;
+        fs.bitmap = ClusterBitmap::from_fat(&fs.read_fat()?);
 
         Ok(fs)
     }
 
+    /// 以读写方式挂载镜像，并对镜像文件加独占锁，直到 `FileSystem` 被丢弃或关闭。
+    /// 其他进程已挂载时返回 `ErrorKind::ResourceBusy`。
     pub fn mount(path: &str) -> io::Result<Self> {
+        Self::mount_at(path, 0)
+    }
+
+    /// 挂载从文件第 `base_offset` 字节开始的镜像，例如附加在其他数据之后或位于分区中的镜像，
+    /// 用 `FormatOptions::base_offset` 格式化。其余与 `mount` 相同。
+    pub fn mount_at(path: &str, base_offset: u64) -> io::Result<Self> {
         let file = OpenOptions::new().read(true).write(true).open(path)?;
+        lock_image(&file, true)?;
+        Self::mount_storage(Box::new(file), path, false, base_offset)
+    }
 
-        let mut boot_sector = [0u8; SECTOR_SIZE];
-        let mut file_clone = file.try_clone()?; // Clone the file handle to avoid moving it
-        file_clone.seek(SeekFrom::Start(0))?;
-        file_clone.read_exact(&mut boot_sector)?;
+    /// 以只读方式挂载镜像并加共享锁，多个进程可以同时读取同一个镜像，
+    /// 但不能与 `mount` 同时持有。通过它进行的写操作都会失败。
+    pub fn mount_shared(path: &str) -> io::Result<Self> {
+        let file = OpenOptions::new().read(true).open(path)?;
+        lock_image(&file, false)?;
+        Self::mount_storage(Box::new(file), path, true, 0)
+    }
 
+    // 检查标识、签名、校验和与格式版本，返回启动扇区记录的布局
+    fn parse_boot_sector(boot_sector: &[u8]) -> io::Result<Geometry> {
         let fs_identifier = &boot_sector[3..11];
         if fs_identifier != b"MINIFAT " {
             return Err(io::Error::new(
@@ -290,82 +1484,477 @@ impl FileSystem {
             ));
         }
 
-        Ok(FileSystem {
-            disk_image: file,
+        let endianness = Endianness::from_boot_sector(boot_sector)?;
+        let stored_checksum = endianness.read_u32(&boot_sector[BOOT_CHECKSUM_OFFSET..]);
+        if stored_checksum != 0 && stored_checksum != boot_checksum(boot_sector) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "启动扇区校验和不匹配",
+            ));
+        }
+
+        let version = boot_sector[FORMAT_VERSION_OFFSET];
+        if version > FORMAT_VERSION {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!(
+                    "不支持镜像格式版本{}（当前最高支持版本{}），请升级tinyfs",
+                    version, FORMAT_VERSION
+                ),
+            ));
+        }
+
+        Geometry::from_boot_sector(boot_sector)
+    }
+
+    fn mount_storage(
+        mut storage: Box<dyn Storage>,
+        path: &str,
+        read_only: bool,
+        base_offset: u64,
+    ) -> io::Result<Self> {
+        if storage.size()? < base_offset + SECTOR_SIZE as u64 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "镜像文件过短，无法读取启动扇区",
+            ));
+        }
+
+        let mut boot_sector = [0u8; SECTOR_SIZE];
+        storage.seek(SeekFrom::Start(base_offset))?;
+        storage.read_exact(&mut boot_sector)?;
+
+        let geometry = Self::parse_boot_sector(&boot_sector)?;
+
+        // 按启动扇区记录的总扇区数检查文件长度，避免之后读取FAT或数据区时才遇到EOF
+        let total_sectors = geometry.endianness.read_u32(&boot_sector[17..21]);
+        let expected_len = total_sectors as u64 * SECTOR_SIZE as u64;
+        let actual_len = storage.size()?.saturating_sub(base_offset);
+        if actual_len < expected_len {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "镜像文件被截断：应为{}字节，实际{}字节，缺少{}字节",
+                    expected_len,
+                    actual_len,
+                    expected_len - actual_len
+                ),
+            ));
+        }
+
+        let data_clusters = (total_sectors as usize).saturating_sub(geometry.data_start_sector())
+            / geometry.sectors_per_cluster();
+
+        let mut fs = FileSystem {
+            disk_image: storage,
             path: path.to_string(),
+            geometry,
+            cluster_limit: std::cmp::min(geometry.max_clusters, data_clusters + 2) as u32,
+            bitmap: ClusterBitmap::from_fat(&[]),
+            write_barriers: true,
+            overwrite_in_place: false,
+            read_only,
+            base_offset,
+            default_method: CompressionMethod::default(),
+            was_dirty: boot_sector[DIRTY_OFFSET] != 0,
+            marked_dirty: false,
+            clock: system_time_secs,
+            fat_checksum_stale: false,
+        };
+        fs.bitmap = ClusterBitmap::from_fat(&fs.read_fat()?);
+        if !read_only {
+            fs.set_dirty_flag(true)?;
+        }
+
+        Ok(fs)
+    }
+
+    /// 刷新并同步到磁盘后清除脏标记、关闭镜像。直接丢弃 `FileSystem` 时落盘错误只能被忽略，
+    /// 脏标记也会保留下来，下次挂载时 `is_dirty` 返回 true。
+    pub fn close(mut self) -> io::Result<()> {
+        self.sync_fat_checksum()?;
+        if self.marked_dirty {
+            self.set_dirty_flag(false)?;
+        }
+        self.disk_image.flush()?;
+        self.disk_image.sync_all()
+    }
+
+    /// 挂载时镜像是否带着脏标记，即上次以读写方式使用后没有通过 `close` 正常关闭
+    /// （直接丢弃了句柄、进程崩溃、被杀死或发生panic）。为 true 时建议先运行 `check`。
+    ///
+    /// 反映的是挂载那一刻的状态，本次挂载期间不会改变。
+    pub fn is_dirty(&self) -> bool {
+        self.was_dirty
+    }
+
+    fn set_dirty_flag(&mut self, dirty: bool) -> io::Result<()> {
+        self.update_boot_sector(|boot_sector| boot_sector[DIRTY_OFFSET] = dirty as u8)?;
+        self.marked_dirty = dirty;
+        Ok(())
+    }
+
+    /// 把整个镜像逐字节复制到 `dest_path`（必须是不存在的文件），返回挂载好的副本。
+    ///
+    /// 复制前先刷新，副本经过正常的挂载检查；嵌入其他文件的镜像只复制镜像本身，副本从偏移0开始。
+    pub fn clone_image(&mut self, dest_path: &str) -> io::Result<FileSystem> {
+        self.sync_fat_checksum()?;
+        self.disk_image.flush()?;
+
+        // 按启动扇区记录的总扇区数复制，副本的挂载检查与原镜像相同
+        let boot_sector = self.dump_sector(0)?;
+        let total_sectors = self.geometry.endianness.read_u32(&boot_sector[17..21]);
+        let image_len = total_sectors as u64 * SECTOR_SIZE as u64;
+        let mut dest = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(dest_path)?;
+        let result = self.copy_image_to(&mut dest, image_len);
+        drop(dest);
+
+        // 复制失败或副本无法挂载时删除半成品，不留下看似有效的备份
+        // 副本复制的是挂载中的镜像，带着本次挂载置的脏标记；副本是一致的，沿用原镜像挂载时的状态
+        match result.and_then(|_| Self::mount(dest_path)) {
+            Ok(mut fs) => {
+                fs.was_dirty = self.was_dirty;
+                Ok(fs)
+            }
+            Err(e) => {
+                let _ = std::fs::remove_file(dest_path);
+                Err(e)
+            }
+        }
+    }
+
+    fn copy_image_to(&mut self, dest: &mut File, image_len: u64) -> io::Result<()> {
+        self.seek_to(0)?;
+        let copied = io::copy(&mut (&mut self.disk_image).take(image_len), dest)?;
+        if copied != image_len {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!("镜像只复制了{}字节，应为{}字节", copied, image_len),
+            ));
+        }
+        dest.sync_all()
+    }
+
+    /// 读取卷标，没有卷标时返回空字符串。
+    pub fn label(&mut self) -> io::Result<String> {
+        let boot_sector = self.dump_sector(0)?;
+        let field = &boot_sector[LABEL_OFFSET..LABEL_OFFSET + LABEL_SIZE];
+        Ok(String::from_utf8_lossy(field)
+            .trim_end_matches([' ', '\0'])
+            .to_string())
+    }
+
+    /// 修改卷标（最多11字节），启动扇区校验和随之更新。
+    pub fn set_label(&mut self, label: &str) -> io::Result<()> {
+        let field = encode_label(label)?;
+        self.update_boot_sector(|boot_sector| {
+            boot_sector[LABEL_OFFSET..LABEL_OFFSET + LABEL_SIZE].copy_from_slice(&field);
         })
     }
 
+    /// 镜像的布局参数，可用于估算容量或按簇对齐缓冲区
+    pub fn geometry(&self) -> Geometry {
+        self.geometry
+    }
+
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    /// 开关写入屏障（默认开启）。关闭后不再调用 `sync_data`，写入更快，
+    /// 但操作系统可能调整落盘顺序，崩溃后目录项可能指向尚未写入的数据；适合测试等场景。
+    pub fn set_write_barriers(&mut self, enabled: bool) {
+        self.write_barriers = enabled;
+    }
+
+    /// 开关原地覆盖（默认关闭）。开启后覆盖同名文件时复用旧文件的簇链：共有的簇直接覆盖，
+    /// 新数据更长时在尾部接上新簇，更短时释放多出的簇，首簇号保持不变，FAT的改动和碎片都更少，
+    /// 适合反复改写的小文件。代价是放弃影子写入：覆盖中途崩溃，旧内容已被部分覆盖，文件可能损坏。
+    /// 旧文件有其他链接、存放在目录项中或簇链损坏时，以及 `write_file_verified`，仍使用影子写入。
+    pub fn set_overwrite_in_place(&mut self, enabled: bool) {
+        self.overwrite_in_place = enabled;
+    }
+
+    /// 设置本次挂载中 `write_file` 未指定压缩方法时使用的方法，不写入镜像。
+    /// 从未设置时为DEFLATE；存放已压缩数据的镜像可以改为不压缩。
+    pub fn set_default_compression(&mut self, method: CompressionMethod) {
+        self.default_method = method;
+    }
+
+    pub fn default_compression(&self) -> CompressionMethod {
+        self.default_method
+    }
+
+    /// 替换写入文件时记录修改时间所用的时钟（返回Unix秒），默认为系统时间。
+    /// 用于测试或需要可重复生成镜像的场景。
+    pub fn set_time_source(&mut self, now: fn() -> u64) {
+        self.clock = now;
+    }
+
+    // 目录项只有4字节存放修改时间，2106年之后的时间按u32上限记录
+    fn now(&self) -> u32 {
+        u32::try_from((self.clock)()).unwrap_or(u32::MAX)
+    }
+
+    // 写入屏障：确保之前的写入都已落盘，再进行后续写入
+    fn barrier(&mut self) -> io::Result<()> {
+        self.sync_fat_checksum()?;
+        if self.write_barriers {
+            self.disk_image.sync_data()?;
+        }
+        Ok(())
+    }
+
+    /// 挂载镜像，文件不存在时才按默认布局格式化一个新镜像。
+    ///
+    /// 其他挂载错误（版本不支持、校验和不符、镜像被截断、被其他进程锁定等）原样返回，
+    /// 不会把无法挂载的镜像格式化掉。
     pub fn get_or_create(path: &str) -> io::Result<Self> {
         match Self::mount(path) {
-            Ok(fs) => Ok(fs),
-            Err(_) => Self::format(path),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Self::format(path),
+            result => result,
+        }
+    }
+
+    // FAT项下标越界时偏移会落到根目录甚至数据区
+    fn check_fat_index(&self, cluster: u32) -> io::Result<()> {
+        if cluster as usize >= self.geometry.max_clusters {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "无效的簇号 {}：超出FAT范围（{}项）",
+                    cluster, self.geometry.max_clusters
+                ),
+            ));
         }
+        Ok(())
     }
+
     fn get_next_cluster(&mut self, cluster: u32) -> io::Result<u32> {
+        self.check_fat_index(cluster)?;
         let fat_offset = FAT_START_SECTOR * SECTOR_SIZE + (cluster as usize * 4);
-        self.disk_image.seek(SeekFrom::Start(fat_offset as u64))?;
+        self.seek_to(fat_offset as u64)?;
 
         let mut next_cluster_bytes = [0u8; 4];
         self.disk_image.read_exact(&mut next_cluster_bytes)?;
 
-        let next_cluster = u32::from_le_bytes(next_cluster_bytes);
+        let next_cluster = self.geometry.endianness.read_u32(&next_cluster_bytes);
         Ok(next_cluster)
     }
 
+    // 所有对镜像的定位都经过这里，加上镜像在文件中的起始偏移
+    fn seek_to(&mut self, offset: u64) -> io::Result<()> {
+        self.disk_image
+            .seek(SeekFrom::Start(self.base_offset + offset))?;
+        Ok(())
+    }
+
+    // 所有写镜像的操作都经过这里，只读挂载时在修改任何内容之前失败
+    fn check_writable(&self) -> io::Result<()> {
+        if self.read_only {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "镜像以只读方式挂载（mount_shared），不能修改",
+            ));
+        }
+        Ok(())
+    }
+
     fn set_next_cluster(&mut self, cluster: u32, next_cluster: u32) -> io::Result<()> {
-        let fat_offset = FAT_START_SECTOR * SECTOR_SIZE + (cluster as usize * 4);
-        self.disk_image.seek(SeekFrom::Start(fat_offset as u64))?;
+        self.check_writable()?;
+        self.check_fat_index(cluster)?;
+        for copy in 0..self.geometry.fat_copies {
+            let fat_offset =
+                self.geometry.fat_start_sector(copy) * SECTOR_SIZE + (cluster as usize * 4);
+            self.seek_to(fat_offset as u64)?;
+            self.disk_image
+                .write_all(&self.geometry.endianness.u32_bytes(next_cluster))?;
+        }
+        self.bitmap.set(cluster, next_cluster != FAT_FREE);
+        // 每改一项都重算要读一遍整个FAT，留到操作的提交点统一计算
+        self.fat_checksum_stale = true;
+        Ok(())
+    }
+
+    // 读取第 copy 份FAT的原始字节
+    fn read_fat_bytes(&mut self, copy: usize) -> io::Result<Vec<u8>> {
+        let mut fat_data = vec![0u8; self.geometry.max_clusters * 4];
+        self.seek_to((self.geometry.fat_start_sector(copy) * SECTOR_SIZE) as u64)?;
+        self.disk_image.read_exact(&mut fat_data)?;
+        Ok(fat_data)
+    }
+
+    // 主FAT改动过时重新计算校验和。在写入屏障、释放簇链、关闭等提交点调用，每个操作只读一遍FAT
+    fn sync_fat_checksum(&mut self) -> io::Result<()> {
+        if self.fat_checksum_stale {
+            self.update_fat_checksum()?;
+            self.fat_checksum_stale = false;
+        }
+        Ok(())
+    }
+
+    // 有备份FAT时重新计算主FAT的CRC32写入启动扇区；只有一份FAT时不记录
+    fn update_fat_checksum(&mut self) -> io::Result<()> {
+        if self.geometry.fat_copies < 2 {
+            return Ok(());
+        }
+        let checksum = crc32fast::hash(&self.read_fat_bytes(0)?);
+        let checksum_bytes = self.geometry.endianness.u32_bytes(checksum);
+        self.update_boot_sector(|boot_sector| {
+            boot_sector[FAT_CHECKSUM_OFFSET..FAT_CHECKSUM_OFFSET + 4]
+                .copy_from_slice(&checksum_bytes);
+        })
+    }
 
-        self.disk_image.write_all(&next_cluster.to_le_bytes())?;
+    // 第 copy 份FAT与启动扇区中记录的校验和是否一致；只有一份FAT时没有校验和，总是一致
+    fn fat_checksum_matches(&mut self, copy: usize) -> io::Result<bool> {
+        if self.geometry.fat_copies < 2 {
+            return Ok(true);
+        }
+        self.sync_fat_checksum()?;
+        let boot_sector = self.dump_sector(0)?;
+        let stored = self
+            .geometry
+            .endianness
+            .read_u32(&boot_sector[FAT_CHECKSUM_OFFSET..]);
+        Ok(crc32fast::hash(&self.read_fat_bytes(copy)?) == stored)
+    }
+
+    // 读出启动扇区，修改后重新计算校验和并写回；旧版镜像的校验和保持为0
+    fn update_boot_sector(&mut self, update: impl FnOnce(&mut [u8])) -> io::Result<()> {
+        self.check_writable()?;
+        let mut boot_sector = [0u8; SECTOR_SIZE];
+        self.seek_to(0)?;
+        self.disk_image.read_exact(&mut boot_sector)?;
+
+        update(&mut boot_sector);
+
+        let checksum_field = BOOT_CHECKSUM_OFFSET..BOOT_CHECKSUM_OFFSET + 4;
+        if boot_sector[checksum_field.clone()] != [0u8; 4] {
+            let checksum = boot_checksum(&boot_sector);
+            boot_sector[checksum_field]
+                .copy_from_slice(&self.geometry.endianness.u32_bytes(checksum));
+        }
+
+        self.seek_to(0)?;
+        self.disk_image.write_all(&boot_sector)
+    }
+
+    // 调整数据区大小，使簇号上界变为 cluster_limit，同步更新文件长度和启动扇区中的总扇区数
+    fn set_cluster_limit(&mut self, cluster_limit: u32) -> io::Result<()> {
+        self.check_writable()?;
+        // 改变文件长度会截掉镜像之后属于外层文件的数据
+        if self.base_offset != 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "嵌入在其他文件中的镜像不能改变大小",
+            ));
+        }
+        let total_sectors = self.geometry.total_sectors(cluster_limit);
+        let total_sectors_bytes = self.geometry.endianness.u32_bytes(total_sectors as u32);
+
+        self.disk_image
+            .set_len((total_sectors * SECTOR_SIZE) as u64)?;
+        self.update_boot_sector(|boot_sector| {
+            boot_sector[17..21].copy_from_slice(&total_sectors_bytes);
+        })?;
+        self.cluster_limit = cluster_limit;
         Ok(())
     }
 
+    fn read_fat(&mut self) -> io::Result<Vec<u32>> {
+        let endianness = self.geometry.endianness;
+        Ok(self
+            .read_fat_bytes(0)?
+            .chunks_exact(4)
+            .map(|entry| endianness.read_u32(entry))
+            .collect())
+    }
+
+    fn write_fat(&mut self, fat: &[u32]) -> io::Result<()> {
+        self.check_writable()?;
+        let endianness = self.geometry.endianness;
+        let fat_data: Vec<u8> = fat
+            .iter()
+            .flat_map(|&next| endianness.u32_bytes(next))
+            .collect();
+        for copy in 0..self.geometry.fat_copies {
+            self.seek_to((self.geometry.fat_start_sector(copy) * SECTOR_SIZE) as u64)?;
+            self.disk_image.write_all(&fat_data)?;
+        }
+        self.bitmap = ClusterBitmap::from_fat(fat);
+        self.fat_checksum_stale = true;
+        self.sync_fat_checksum()
+    }
+
     // 分配新簇
     fn allocate_cluster(&mut self) -> io::Result<u32> {
-        // 从FAT表中查找空闲簇
-        for cluster in 2..MAX_CLUSTERS as u32 {
-            let next = self.get_next_cluster(cluster)?;
-            if next == FAT_FREE {
-                // 将此簇标记为文件结束
-                self.set_next_cluster(cluster, FAT_EOC)?;
-                return Ok(cluster);
+        // 从空闲簇位图中查找空闲簇，不必逐项读取FAT
+        if let Some(cluster) = self.bitmap.first_free(2, self.cluster_limit) {
+            // 只在调试构建中核对位图；读取失败时跳过核对，不改变错误的返回路径
+            #[cfg(debug_assertions)]
+            if let Ok(next) = self.get_next_cluster(cluster) {
+                assert_eq!(next, FAT_FREE, "空闲簇位图与FAT不一致：簇{}", cluster);
             }
+            // 将此簇标记为文件结束
+            self.set_next_cluster(cluster, FAT_EOC)?;
+            return Ok(cluster);
         }
 
+        // 镜像被收缩过：按需把数据区扩展一个簇
+        if (self.cluster_limit as usize) < self.geometry.max_clusters {
+            let cluster = self.cluster_limit;
+            self.set_cluster_limit(cluster + 1)?;
+            self.set_next_cluster(cluster, FAT_EOC)?;
+            return Ok(cluster);
+        }
+
+        // 数据区已满：删除文件可释放空间
         Err(io::Error::new(
-            io::ErrorKind::Other,
+            io::ErrorKind::StorageFull,
             "磁盘空间不足，没有可用簇",
         ))
     }
 
     // 释放簇链
+    // 先完整走一遍簇链再释放：簇链成环或越界时直接报错，FAT保持原样
     fn free_cluster_chain(&mut self, start_cluster: u32) -> io::Result<()> {
         if start_cluster < 2 {
             return Ok(());
         }
 
-        let mut current = start_cluster;
-        while current != FAT_EOC && current >= 2 {
-            let next = self.get_next_cluster(current)?;
-            self.set_next_cluster(current, FAT_FREE)?;
-            current = next;
+        for cluster in self.walk_chain(start_cluster)? {
+            self.set_next_cluster(cluster, FAT_FREE)?;
         }
 
+        // 删除文件时释放簇链就是最后一步，之后没有写入屏障
+        self.sync_fat_checksum()
+    }
+
+    // 簇号必须落在数据区内，损坏的目录项或FAT可能给出任意值，不能直接拿来计算偏移
+    fn check_data_cluster(&self, cluster: u32) -> io::Result<()> {
+        if !(2..self.cluster_limit).contains(&cluster) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "无效的簇号 {}：数据区簇号范围为 2..{}",
+                    cluster, self.cluster_limit
+                ),
+            ));
+        }
         Ok(())
     }
 
     fn read_cluster(&mut self, cluster: u32) -> io::Result<Vec<u8>> {
-        if cluster < 2 {
-            return Err(io::Error::new(io::ErrorKind::InvalidInput, "无效的簇号"));
-        }
+        self.check_data_cluster(cluster)?;
 
-        let cluster_offset =
-            DATA_START_SECTOR * SECTOR_SIZE + (cluster as usize - 2) * CLUSTER_SIZE;
-        self.disk_image
-            .seek(SeekFrom::Start(cluster_offset as u64))?;
+        let cluster_offset = self.geometry.cluster_offset(cluster);
+        self.seek_to(cluster_offset)?;
 
-        let mut cluster_data = vec![0u8; CLUSTER_SIZE];
+        let mut cluster_data = vec![0u8; self.geometry.cluster_size];
         self.disk_image.read_exact(&mut cluster_data)?;
 
         Ok(cluster_data)
@@ -373,23 +1962,20 @@ impl FileSystem {
 
     // 写入一个簇的数据
     fn write_cluster(&mut self, cluster: u32, data: &[u8]) -> io::Result<()> {
-        if cluster < 2 {
-            return Err(io::Error::new(io::ErrorKind::InvalidInput, "无效的簇号"));
-        }
+        self.check_writable()?;
+        self.check_data_cluster(cluster)?;
 
-        if data.len() > CLUSTER_SIZE {
+        if data.len() > self.geometry.cluster_size {
             return Err(io::Error::new(
                 io::ErrorKind::InvalidInput,
                 "数据大于簇大小",
             ));
         }
 
-        let cluster_offset =
-            DATA_START_SECTOR * SECTOR_SIZE + (cluster as usize - 2) * CLUSTER_SIZE;
-        self.disk_image
-            .seek(SeekFrom::Start(cluster_offset as u64))?;
+        let cluster_offset = self.geometry.cluster_offset(cluster);
+        self.seek_to(cluster_offset)?;
 
-        let mut cluster_data = vec![0u8; CLUSTER_SIZE];
+        let mut cluster_data = vec![0u8; self.geometry.cluster_size];
         cluster_data[0..data.len()].copy_from_slice(data);
 
         self.disk_image.write_all(&cluster_data)?;
@@ -397,185 +1983,191 @@ impl FileSystem {
         Ok(())
     }
 
-    fn read_directory_entries(&mut self) -> io::Result<Vec<FileEntry>> {
-        let root_dir_size = ROOT_DIR_SECTORS * SECTOR_SIZE;
-        let mut root_dir_data = vec![0u8; root_dir_size];
+    fn read_root_dir(&mut self) -> io::Result<Vec<u8>> {
+        let mut root_dir_data = vec![0u8; self.geometry.root_dir_sectors * SECTOR_SIZE];
 
-        self.disk_image.seek(SeekFrom::Start(
-            (ROOT_DIR_START_SECTOR * SECTOR_SIZE) as u64,
-        ))?;
+        self.seek_to((self.geometry.root_dir_start_sector() * SECTOR_SIZE) as u64)?;
         self.disk_image.read_exact(&mut root_dir_data)?;
+        if self.geometry.endianness == Endianness::Big {
+            convert_dir_entries(&mut root_dir_data, Endianness::Big);
+        }
 
-        let mut entries = Vec::new();
-        let entry_count = root_dir_size / DIR_ENTRY_SIZE;
+        Ok(root_dir_data)
+    }
 
-        for i in 0..entry_count {
-            let offset = i * DIR_ENTRY_SIZE;
-            let entry_data = &root_dir_data[offset..offset + DIR_ENTRY_SIZE];
+    fn write_root_dir(&mut self, root_dir_data: &[u8]) -> io::Result<()> {
+        self.check_writable()?;
+        self.seek_to((self.geometry.root_dir_start_sector() * SECTOR_SIZE) as u64)?;
+        if self.geometry.endianness == Endianness::Big {
+            let mut converted = root_dir_data.to_vec();
+            convert_dir_entries(&mut converted, Endianness::Little);
+            self.disk_image.write_all(&converted)?;
+        } else {
+            self.disk_image.write_all(root_dir_data)?;
+        }
+        Ok(())
+    }
 
-            // 检查是否是有效的文件项
-            if entry_data[0] != 0 {
-                if let Some(entry) = FileEntry::from_bytes(entry_data) {
-                    if !entry.is_deleted {
-                        entries.push(entry);
-                    }
-                }
-            }
+    // 解析某个槽位的目录项，首字节为0表示从未使用过的空槽位
+    fn slot_entry(root_dir_data: &[u8], slot: usize) -> Option<FileEntry> {
+        let offset = slot * DIR_ENTRY_SIZE;
+        let entry_data = &root_dir_data[offset..offset + DIR_ENTRY_SIZE];
+        if entry_data[0] == 0 {
+            return None;
         }
+        FileEntry::from_bytes(entry_data)
+    }
 
-        Ok(entries)
+    // 查找同名有效文件所在的槽位
+    fn find_live_slot(root_dir_data: &[u8], filename: &str) -> Option<usize> {
+        (0..root_dir_data.len() / DIR_ENTRY_SIZE).find(|&slot| {
+            matches!(
+                Self::slot_entry(root_dir_data, slot),
+                Some(entry) if !entry.is_deleted && entry.name == filename
+            )
+        })
     }
 
-    fn write_directory_entry(&mut self, entry: &FileEntry) -> io::Result<()> {
-        let root_dir_size = ROOT_DIR_SECTORS * SECTOR_SIZE;
-        let mut root_dir_data = vec![0u8; root_dir_size];
+    // 查找可复用的槽位：空槽位或已删除文件留下的槽位
+    fn find_free_slot(root_dir_data: &[u8]) -> Option<usize> {
+        (0..root_dir_data.len() / DIR_ENTRY_SIZE).find(|&slot| {
+            match Self::slot_entry(root_dir_data, slot) {
+                None => true,
+                Some(entry) => entry.is_deleted,
+            }
+        })
+    }
 
-        self.disk_image.seek(SeekFrom::Start(
-            (ROOT_DIR_START_SECTOR * SECTOR_SIZE) as u64,
-        ))?;
-        self.disk_image.read_exact(&mut root_dir_data)?;
+    fn put_slot(root_dir_data: &mut [u8], slot: usize, entry: &FileEntry) {
+        let offset = slot * DIR_ENTRY_SIZE;
+        root_dir_data[offset..offset + DIR_ENTRY_SIZE].copy_from_slice(&entry.to_bytes());
+    }
 
-        let entry_count = root_dir_size / DIR_ENTRY_SIZE;
-        let entry_bytes = entry.to_bytes();
+    // 链接数记录在每个共享簇链的目录项中，增减时全部一起更新
+    fn set_link_count(root_dir_data: &mut [u8], first_cluster: u32, links: u8) {
+        for slot in 0..root_dir_data.len() / DIR_ENTRY_SIZE {
+            if let Some(mut entry) = Self::slot_entry(root_dir_data, slot)
+                && !entry.is_deleted
+                && entry.first_cluster == first_cluster
+            {
+                entry.links = links;
+                Self::put_slot(root_dir_data, slot, &entry);
+            }
+        }
+    }
 
-        for i in 0..entry_count {
-            let offset = i * DIR_ENTRY_SIZE;
+    // 单独读取一个槽位的目录项
+    fn read_slot(&mut self, slot: usize) -> io::Result<Option<FileEntry>> {
+        let mut entry_data = [0u8; DIR_ENTRY_SIZE];
 
-            if root_dir_data[offset] == 0 || {
-                if let Some(existing) =
-                    FileEntry::from_bytes(&root_dir_data[offset..offset + DIR_ENTRY_SIZE])
-                {
-                    existing.name == entry.name || existing.is_deleted
-                } else {
-                    false
-                }
-            } {
-                root_dir_data[offset..offset + DIR_ENTRY_SIZE].copy_from_slice(&entry_bytes);
+        self.seek_to(
+            (self.geometry.root_dir_start_sector() * SECTOR_SIZE + slot * DIR_ENTRY_SIZE) as u64,
+        )?;
+        self.disk_image.read_exact(&mut entry_data)?;
+        if self.geometry.endianness == Endianness::Big {
+            convert_dir_entries(&mut entry_data, Endianness::Big);
+        }
 
-                // 写回根目录区
-                self.disk_image.seek(SeekFrom::Start(
-                    (ROOT_DIR_START_SECTOR * SECTOR_SIZE) as u64,
-                ))?;
-                self.disk_image.write_all(&root_dir_data)?;
+        Ok(Self::slot_entry(&entry_data, 0))
+    }
 
-                return Ok(());
-            }
-        }
+    /// 逐个槽位读取根目录，惰性地产生有效文件的目录项，跳过空槽位和已删除的文件。
+    pub fn entries(&mut self) -> impl Iterator<Item = io::Result<FileEntry>> + '_ {
+        let slot_count = self.geometry.root_entries();
 
-        Err(io::Error::new(
-            io::ErrorKind::Other,
-            "根目录已满，无法创建更多文件",
-        ))
+        (0..slot_count).filter_map(move |slot| match self.read_slot(slot) {
+            Ok(Some(entry)) if !entry.is_deleted => Some(Ok(entry)),
+            Ok(_) => None,
+            Err(e) => Some(Err(e)),
+        })
     }
 
-    fn find_file(&mut self, filename: &str) -> io::Result<Option<FileEntry>> {
-        let entries = self.read_directory_entries()?;
+    /// 列出根目录的所有槽位，包括已删除文件留下的目录项；从未使用过的槽位为 `None`。
+    pub fn list_all_slots(&mut self) -> io::Result<Vec<(usize, Option<FileEntry>)>> {
+        let root_dir_data = self.read_root_dir()?;
+        Ok((0..self.geometry.root_entries())
+            .map(|slot| (slot, Self::slot_entry(&root_dir_data, slot)))
+            .collect())
+    }
 
-        for entry in entries {
-            if entry.name == filename && !entry.is_deleted {
-                return Ok(Some(entry));
-            }
+    // 分配簇链并写入数据，返回首簇号；中途失败时释放已分配的簇
+    fn write_chain(&mut self, compressed_data: &[u8]) -> io::Result<u32> {
+        let first_cluster = self.allocate_cluster()?;
+        if let Err(e) = self.fill_chain(first_cluster, compressed_data) {
+            self.free_cluster_chain(first_cluster)?;
+            return Err(e);
         }
-
-        Ok(None)
+        Ok(first_cluster)
     }
 
-    pub fn write_file(
-        &mut self,
-        filename: &str,
-        data: &[u8],
-        compression_method: Option<u8>,
-    ) -> io::Result<()> {
-        let compression_method = compression_method.unwrap_or(2); // 默认使用DEFLATE(2)
+    // 原地覆盖 `chain`：共有的簇直接覆盖，不够时在尾部接上新簇，多出的簇释放。
+    // 新增的簇先单独写好再接上，空间不足时旧簇链还没有被改动
+    fn overwrite_chain(&mut self, chain: &[u32], compressed_data: &[u8]) -> io::Result<()> {
+        let cluster_size = self.geometry.cluster_size;
+        let needed = compressed_data.len().div_ceil(cluster_size).max(1);
+        let common = chain.len().min(needed);
 
-        let (compressed_data, original_size, compressed_size) = match compression_method {
-            0 => {
-                // 不压缩
-                (data.to_vec(), data.len(), data.len())
-            }
-            1 => {
-                // RLE压缩
-                let compressed = rle_compress_data(data);
-                (compressed.clone(), data.len(), compressed.len())
-            }
-            2 => {
-                // DEFLATE压缩
-                let compressed = compress_data(data)?;
-                (compressed.clone(), data.len(), compressed.len())
-            }
-            _ => {
-                return Err(io::Error::new(
-                    io::ErrorKind::InvalidInput,
-                    "不支持的压缩方法",
-                ));
-            }
+        let extension = if needed > common {
+            self.write_chain(&compressed_data[common * cluster_size..])?
+        } else {
+            FAT_EOC
         };
 
-        // 存在同名文件则删除
-        if let Ok(Some(_)) = self.find_file(filename) {
-            self.delete_file(filename)?;
+        // 最后一个簇不满的部分由 write_cluster 补零，不会残留旧数据
+        let mut chunks = compressed_data.chunks(cluster_size);
+        for &cluster in &chain[..common] {
+            self.write_cluster(cluster, chunks.next().unwrap_or(&[]))?;
         }
 
-        let clusters_needed = (compressed_size + CLUSTER_SIZE - 1) / CLUSTER_SIZE;
-
-        // 空文件至少分配一个簇
-        let clusters_needed = std::cmp::max(clusters_needed, 1);
+        self.set_next_cluster(chain[common - 1], extension)?;
+        for &cluster in &chain[common..] {
+            self.set_next_cluster(cluster, FAT_FREE)?;
+        }
+        Ok(())
+    }
 
-        let first_cluster = self.allocate_cluster()?;
+    fn fill_chain(&mut self, first_cluster: u32, compressed_data: &[u8]) -> io::Result<()> {
+        let mut chunks = compressed_data.chunks(self.geometry.cluster_size);
         let mut current_cluster = first_cluster;
 
-        // 按块写入压缩数据
-        for chunk_index in 0..clusters_needed {
-            let start = chunk_index * CLUSTER_SIZE;
-            let end = std::cmp::min(start + CLUSTER_SIZE, compressed_size);
-
-            if start < compressed_size {
-                let chunk = if start < compressed_data.len() {
-                    if end <= compressed_data.len() {
-                        &compressed_data[start..end]
-                    } else {
-                        &compressed_data[start..compressed_data.len()]
-                    }
-                } else {
-                    &[]
-                };
-
-                self.write_cluster(current_cluster, chunk)?;
+        // 空文件没有数据块，也要写入第一个簇，把其中残留的旧数据清零
+        self.write_cluster(current_cluster, chunks.next().unwrap_or(&[]))?;
 
-                if chunk_index < clusters_needed - 1 {
-                    let next_cluster = self.allocate_cluster()?;
-                    self.set_next_cluster(current_cluster, next_cluster)?;
-                    current_cluster = next_cluster;
-                }
-            }
+        // 其余每个数据块分配并链接一个簇
+        for chunk in chunks {
+            let next_cluster = self.allocate_cluster()?;
+            self.set_next_cluster(current_cluster, next_cluster)?;
+            current_cluster = next_cluster;
+            self.write_cluster(current_cluster, chunk)?;
         }
 
         // 标记文件结尾
         self.set_next_cluster(current_cluster, FAT_EOC)?;
 
-        let entry = FileEntry::new(
-            filename,
-            original_size as u32,
-            compressed_size as u32,
-            first_cluster,
-            compression_method,
-        );
-
-        self.write_directory_entry(&entry)?;
-
         Ok(())
     }
 
-    pub fn read_file(&mut self, filename: &str) -> io::Result<Vec<u8>> {
-        let file_entry = match self.find_file(filename)? {
-            Some(entry) => entry,
-            None => return Err(io::Error::new(io::ErrorKind::NotFound, "文件不存在")),
-        };
+    // 沿簇链读取目录项记录的 compressed_size 字节，不解压
+    fn read_chain(&mut self, file_entry: &FileEntry) -> io::Result<Vec<u8>> {
+        if file_entry.is_inline() {
+            return Ok(file_entry.inline_data.clone());
+        }
 
         let mut compressed_data = Vec::new();
         let mut current_cluster = file_entry.first_cluster;
+        let mut visited = vec![false; self.geometry.max_clusters];
 
         while current_cluster != FAT_EOC && current_cluster >= 2 {
+            // 簇链成环时重复读取同一批簇会拼出错误的数据
+            let cluster = current_cluster as usize;
+            if cluster >= visited.len() || visited[cluster] {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("簇链损坏：簇 {} 越界或重复出现", current_cluster),
+                ));
+            }
+            visited[cluster] = true;
+
             let cluster_data = self.read_cluster(current_cluster)?;
 
             let remaining = file_entry.compressed_size as usize - compressed_data.len();
@@ -592,102 +2184,5883 @@ impl FileSystem {
             current_cluster = self.get_next_cluster(current_cluster)?;
         }
 
-        if file_entry.is_compressed {
-            match file_entry.compression_method {
-                0 => Ok(compressed_data),
-                1 => {
-                    // RLE解压
-                    let decompressed = rle_decompress_data(&compressed_data);
-
-                    if decompressed.len() != file_entry.size as usize {
-                        return Err(io::Error::new(
-                            io::ErrorKind::InvalidData,
-                            format!(
-                                "RLE解压错误：解压后大小({})与预期大小({})不匹配",
-                                decompressed.len(),
-                                file_entry.size
-                            ),
-                        ));
-                    }
+        Ok(compressed_data)
+    }
 
-                    Ok(decompressed)
-                }
-                2 => {
-                    // DEFLATE解压
-                    let decompressed = decompress_data(&compressed_data)?;
-
-                    if decompressed.len() != file_entry.size as usize {
-                        return Err(io::Error::new(
-                            io::ErrorKind::InvalidData,
-                            format!(
-                                "DEFLATE解压错误：解压后大小({})与预期大小({})不匹配",
-                                decompressed.len(),
-                                file_entry.size
-                            ),
-                        ));
-                    }
+    fn read_directory_entries(&mut self) -> io::Result<Vec<FileEntry>> {
+        self.entries().collect()
+    }
 
-                    Ok(decompressed)
-                }
-                _ => Err(io::Error::new(
-                    io::ErrorKind::InvalidData,
-                    "不支持的压缩方法",
-                )),
+    // 选择写入目录项的槽位：同名有效文件的槽位优先，否则使用第一个可复用的槽位
+    fn entry_slot(root_dir_data: &[u8], filename: &str) -> io::Result<usize> {
+        if let Some(slot) = Self::find_live_slot(root_dir_data, filename) {
+            return Ok(slot);
+        }
+
+        // 目录项已用完：与数据区满区分开，删除文件或整理目录才能解决
+        Self::find_free_slot(root_dir_data).ok_or_else(|| Self::directory_full(root_dir_data))
+    }
+
+    // 目录项数在格式化时确定，报错时提示如何增大
+    fn directory_full(root_dir_data: &[u8]) -> io::Error {
+        io::Error::new(
+            io::ErrorKind::QuotaExceeded,
+            format!(
+                "根目录已满（{}个目录项），无法创建更多文件；格式化时可用 FormatOptions::root_dir_sectors 增大根目录",
+                root_dir_data.len() / DIR_ENTRY_SIZE
+            ),
+        )
+    }
+
+    fn find_file(&mut self, filename: &str) -> io::Result<Option<FileEntry>> {
+        for entry in self.entries() {
+            let entry = entry?;
+            if entry.name == filename {
+                return Ok(Some(entry));
             }
-        } else {
-            Ok(compressed_data)
         }
+
+        Ok(None)
     }
-    pub fn write_file_with_compression(
+
+    /// 读取单个文件的目录项（大小、压缩方法、首簇号、属性等），不读取文件内容。
+    pub fn stat(&mut self, filename: &str) -> io::Result<FileEntry> {
+        match self.find_file(filename)? {
+            Some(entry) => Ok(entry),
+            None => Err(io::Error::new(io::ErrorKind::NotFound, "文件不存在")),
+        }
+    }
+
+    /// 判断文件是否存在，找到第一个匹配项即返回。
+    pub fn exists(&mut self, filename: &str) -> io::Result<bool> {
+        Ok(self.find_file(filename)?.is_some())
+    }
+
+    /// 压缩并写入文件，同名文件会被覆盖。`compression_method` 为 `None` 时使用
+    /// `set_default_compression` 设置的方法，默认DEFLATE。
+    ///
+    /// 写入顺序：先把数据写进新分配的簇链（FAT链接随之建立），再提交目录项，
+    /// 覆盖时最后才释放旧簇链。每一步之间有 `sync_data` 屏障（见 `set_write_barriers`），
+    /// 操作系统不会把目录项先于数据写到磁盘。任何一步中断后，目录项要么仍指向完整的旧数据，
+    /// 要么指向完整的新数据，最多留下未被引用的无主簇，可用 `repair_lost_clusters` 回收。
+    /// 因此覆盖文件时需要新旧两份数据同时放得下；`set_overwrite_in_place` 可改为复用旧簇链。
+    ///
+    /// 原始数据超过 `u32::MAX` 字节时返回 `InvalidInput`，压缩后超过
+    /// `Geometry::max_file_size` 时返回 `StorageFull`，都不会改动镜像。
+    ///
+    /// 返回刚写入的目录项，调用方无需再查找一次就能得到压缩后大小和首簇号。
+    /// 不超过12字节的数据不论 `compression_method` 都以原始字节直接存放在目录项中（见 `FileEntry::is_inline`），
+    /// 目录项仍记录所选的方法。
+    pub fn write_file(
+        &mut self,
+        filename: &str,
+        data: &[u8],
+        compression_method: Option<CompressionMethod>,
+    ) -> io::Result<FileEntry> {
+        validate_filename(filename)?;
+
+        let compression_method = compression_method
+            .unwrap_or(self.default_method)
+            .resolve(data);
+
+        let compressed_data = compress_with(data, compression_method)?;
+        self.store_file(
+            filename,
+            data.len(),
+            &compressed_data,
+            compression_method.into(),
+            false,
+        )
+    }
+
+    /// 与 `write_file` 相同，内容是 `slices` 按顺序拼接的结果。DEFLATE、Brotli和zstd
+    /// 依次压缩各段，调用方不必先把它们拼成一整块；RLE和RLE2仍会在内部拼接。
+    pub fn write_file_vectored(
+        &mut self,
+        filename: &str,
+        slices: &[&[u8]],
+        compression_method: Option<CompressionMethod>,
+    ) -> io::Result<FileEntry> {
+        validate_filename(filename)?;
+
+        // 选择方法只看开头一段（Smart的采样、小文件内联），不必拼接全部数据
+        let total_len = slices.iter().map(|slice| slice.len()).sum();
+        let mut sample = Vec::new();
+        for slice in slices {
+            let take = slice.len().min(SMART_SAMPLE_SIZE - sample.len());
+            sample.extend_from_slice(&slice[..take]);
+        }
+        let compression_method = compression_method
+            .unwrap_or(self.default_method)
+            .resolve(&sample);
+
+        let compressed_data = compress_slices_with(slices, compression_method)?;
+        self.store_file(
+            filename,
+            total_len,
+            &compressed_data,
+            compression_method.into(),
+            false,
+        )
+    }
+
+    /// 与 `write_file` 相同，但在提交目录项之前把刚写入的簇链读回并解压，
+    /// 确认与 `data` 完全一致，不一致时释放新簇链并返回 `InvalidData`，原文件保持不变。
+    ///
+    /// 能在写入时发现坏介质或编码错误，而不是等到以后读取时才发现；代价是写入耗时大约翻倍。
+    /// 读回可能来自操作系统的页缓存，并不保证数据已经落到物理介质上。
+    pub fn write_file_verified(
         &mut self,
         filename: &str,
         data: &[u8],
+        compression_method: CompressionMethod,
+    ) -> io::Result<FileEntry> {
+        validate_filename(filename)?;
+
+        let compression_method = compression_method.resolve(data);
+        let compressed_data = compress_with(data, compression_method)?;
+        self.store_file_checked(
+            filename,
+            data.len(),
+            &compressed_data,
+            compression_method.into(),
+            false,
+            Some(data),
+        )
+    }
+
+    // 将已编码（压缩、加密）的数据写入簇链并提交目录项
+    fn store_file(
+        &mut self,
+        filename: &str,
+        original_size: usize,
+        compressed_data: &[u8],
         compression_method: u8,
-    ) -> io::Result<()> {
-        self.write_file(filename, data, Some(compression_method))
+        is_encrypted: bool,
+    ) -> io::Result<FileEntry> {
+        self.store_file_checked(
+            filename,
+            original_size,
+            compressed_data,
+            compression_method,
+            is_encrypted,
+            None,
+        )
+    }
+
+    // 同 store_file；`expected` 非空时提交目录项之前读回新簇链，解码结果必须与之相同
+    fn store_file_checked(
+        &mut self,
+        filename: &str,
+        original_size: usize,
+        compressed_data: &[u8],
+        compression_method: u8,
+        is_encrypted: bool,
+        expected: Option<&[u8]>,
+    ) -> io::Result<FileEntry> {
+        let compressed_size = compressed_data.len();
+
+        // 目录项中的大小字段是u32，超出时直接拒绝，不能截断后写入；
+        // 压缩后超过数据区的文件也在分配簇之前拒绝，免得写满整个数据区才失败
+        let original_size = u32::try_from(original_size).map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "文件过大：{}字节，目录项最多记录{}字节",
+                    original_size,
+                    u32::MAX
+                ),
+            )
+        })?;
+        let max_file_size = self.geometry.max_file_size();
+        if compressed_size as u64 > max_file_size {
+            return Err(io::Error::new(
+                io::ErrorKind::StorageFull,
+                format!(
+                    "压缩后{}字节，超过单个文件的上限{}字节",
+                    compressed_size, max_file_size
+                ),
+            ));
+        }
+
+        // 根目录区只读一次：查找同名文件、选择槽位和最终写回共用这一份数据
+        let mut root_dir_data = self.read_root_dir()?;
+        let slot = Self::entry_slot(&root_dir_data, filename)?;
+
+        let replaced = Self::slot_entry(&root_dir_data, slot).filter(|entry| !entry.is_deleted);
+        if let Some(replaced) = &replaced {
+            check_not_read_only(replaced)?;
+        }
+
+        // 影子写入：新数据写进新分配的簇链，旧簇链在目录项切换之前保持不动；
+        // 原始数据放得进目录项时不分配簇，不论压缩方法都解压回原始字节存放
+        // 原文件有备注时不内联存放，否则覆盖后备注无处保存
+        let inline = !is_encrypted
+            && original_size as usize <= MAX_INLINE_SIZE
+            && replaced
+                .as_ref()
+                .is_none_or(|replaced| replaced.comment.is_empty());
+        let inline_data = if !inline {
+            Vec::new()
+        } else if compression_method == 0 || compressed_data.is_empty() {
+            compressed_data.to_vec()
+        } else {
+            let mut raw_data = Vec::new();
+            decompress_into(compression_method, compressed_data, &mut raw_data)?;
+            raw_data
+        };
+        let reuse = replaced.as_ref().filter(|replaced| {
+            self.overwrite_in_place
+                && !inline
+                && expected.is_none()
+                && replaced.links <= 1
+                && replaced.first_cluster >= 2
+        });
+        let reused_chain = match reuse {
+            Some(replaced) => match self.walk_chain(replaced.first_cluster) {
+                Ok(chain) => Some(chain),
+                // 旧簇链损坏时不复用，仍按影子写入处理
+                Err(e) if e.kind() == io::ErrorKind::InvalidData => None,
+                Err(e) => return Err(e),
+            },
+            None => None,
+        };
+        let first_cluster = if inline {
+            INLINE_CLUSTER
+        } else if let Some(chain) = &reused_chain {
+            self.overwrite_chain(chain, compressed_data)?;
+            chain[0]
+        } else {
+            self.write_chain(compressed_data)?
+        };
+        self.barrier()?;
+
+        let mut entry = FileEntry::new(
+            filename,
+            original_size,
+            compressed_size as u32,
+            first_cluster,
+            compression_method,
+        );
+        entry.is_encrypted = is_encrypted;
+        entry.modified = self.now();
+        if inline {
+            entry.compressed_size = original_size;
+            entry.is_compressed = false;
+            entry.inline_data = inline_data;
+        }
+
+        if let Some(expected) = expected {
+            let verified = self
+                .read_chain(&entry)
+                .and_then(|raw_data| Self::decode_file(&entry, raw_data))
+                .is_ok_and(|decoded| decoded == expected);
+            if !verified {
+                self.free_cluster_chain(first_cluster)?;
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "写入校验失败：文件 {} 读回的内容与写入的数据不一致",
+                        filename
+                    ),
+                ));
+            }
+        }
+
+        // 覆盖时保留原文件的属性和备注，例如隐藏文件覆盖后仍然隐藏；
+        // 原文件还有其他链接时，这个名字改指向新簇链，旧簇链留给其余的链接
+        if let Some(replaced) = &replaced {
+            entry.attributes = replaced.attributes;
+            entry.comment = replaced.comment.clone();
+            if replaced.links > 1 {
+                Self::set_link_count(
+                    &mut root_dir_data,
+                    replaced.first_cluster,
+                    replaced.links - 1,
+                );
+            }
+        }
+
+        // 目录项最后提交：切换之前崩溃，旧文件原样保留，新簇链只是成了无主簇
+        Self::put_slot(&mut root_dir_data, slot, &entry);
+        self.write_root_dir(&root_dir_data)?;
+        self.barrier()?;
+
+        // 切换之后才释放旧簇链：在此之前崩溃，旧簇链成为无主簇
+        let replaced = replaced.filter(|replaced| replaced.links <= 1 && reused_chain.is_none());
+        if let Some(replaced) = replaced {
+            match self.free_cluster_chain(replaced.first_cluster) {
+                // 新文件已经提交，损坏的旧簇链留作无主簇，不影响这次写入
+                Err(e) if e.kind() == io::ErrorKind::InvalidData => {}
+                result => result?,
+            }
+        }
+
+        Ok(entry)
+    }
+
+    pub fn read_file(&mut self, filename: &str) -> io::Result<Vec<u8>> {
+        let mut data = Vec::new();
+        self.read_file_into(filename, &mut data)?;
+        Ok(data)
     }
-    pub fn get_compression_stats(&mut self, filename: &str) -> io::Result<(u32, u32, f32, &str)> {
+
+    /// 把文件内容读进调用方提供的缓冲区，先清空 `buf`，返回写入的字节数。
+    /// 循环读取大量文件时可复用同一个缓冲区，避免每次都分配。
+    pub fn read_file_into(&mut self, filename: &str, buf: &mut Vec<u8>) -> io::Result<usize> {
         let file_entry = match self.find_file(filename)? {
             Some(entry) => entry,
             None => return Err(io::Error::new(io::ErrorKind::NotFound, "文件不存在")),
         };
 
-        let compression_name = match file_entry.compression_method {
-            0 => "无压缩",
-            1 => "RLE压缩",
-            2 => "DEFLATE压缩",
-            _ => "未知压缩方法",
+        self.read_entry_into(&file_entry, buf)
+    }
+
+    /// 按根目录槽位读取文件，槽位号与 `list_all_slots` 返回的一致。
+    ///
+    /// 不经过文件名查找，同名的多个目录项也能分别读取。槽位超出范围时返回 `InvalidInput`，
+    /// 槽位为空或文件已删除时返回 `NotFound`。
+    pub fn read_file_at(&mut self, slot_index: usize) -> io::Result<Vec<u8>> {
+        let root_entries = self.geometry.root_entries();
+        if slot_index >= root_entries {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "槽位 {} 超出范围：根目录共{}个槽位",
+                    slot_index, root_entries
+                ),
+            ));
+        }
+
+        let root_dir_data = self.read_root_dir()?;
+        let file_entry = match Self::slot_entry(&root_dir_data, slot_index) {
+            Some(entry) if !entry.is_deleted => entry,
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("槽位 {} 中没有文件", slot_index),
+                ));
+            }
         };
 
-        let ratio = if file_entry.size > 0 {
-            (file_entry.compressed_size as f32 / file_entry.size as f32) * 100.0
+        let mut data = Vec::new();
+        self.read_entry_into(&file_entry, &mut data)?;
+        Ok(data)
+    }
+
+    fn read_entry_into(&mut self, file_entry: &FileEntry, buf: &mut Vec<u8>) -> io::Result<usize> {
+        // 加密文件需要密钥，不能走普通读取路径
+        if file_entry.is_encrypted {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "文件已加密，请使用 read_file_encrypted 读取",
+            ));
+        }
+
+        let compressed_data = self.read_chain(file_entry)?;
+        Self::decode_file_into(file_entry, &compressed_data, buf)?;
+        Ok(buf.len())
+    }
+
+    /// 用于恢复部分损坏的文件：返回解码器能解出的全部数据，以及解压后大小是否与目录项一致。
+    ///
+    /// 解压中途出错或大小不符时不报错，只把第二个返回值置为 false，数据可能不完整；
+    /// 簇链损坏、压缩方法未知或文件已加密时仍然返回错误。正常读取请使用 `read_file`。
+    pub fn read_file_lenient(&mut self, filename: &str) -> io::Result<(Vec<u8>, bool)> {
+        let file_entry = match self.find_file(filename)? {
+            Some(entry) => entry,
+            None => return Err(io::Error::new(io::ErrorKind::NotFound, "文件不存在")),
+        };
+
+        if file_entry.is_encrypted {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "文件已加密，请使用 read_file_encrypted 读取",
+            ));
+        }
+
+        let compressed_data = self.read_chain(&file_entry)?;
+        let mut data = Vec::new();
+        let decoded = if compressed_data.is_empty() && file_entry.size == 0 {
+            true
+        } else if !file_entry.is_compressed || file_entry.compression_method == 0 {
+            data.extend_from_slice(&compressed_data);
+            true
         } else {
-            0.0
+            match decompress_into(file_entry.compression_method, &compressed_data, &mut data) {
+                Ok(_) => true,
+                // 解码中途出错：保留已解出的部分
+                Err(_) if is_known_method(file_entry.compression_method) => false,
+                Err(e) => return Err(e),
+            }
         };
 
-        Ok((
-            file_entry.size,
-            file_entry.compressed_size,
-            ratio,
-            compression_name,
-        ))
+        let complete = decoded && data.len() == file_entry.size as usize;
+        Ok((data, complete))
     }
-    pub fn list_files(&mut self) -> io::Result<Vec<FileEntry>> {
-        self.read_directory_entries()
+
+    /// 把文件解压后写入 `out`，返回写入的字节数。
+    ///
+    /// 未压缩、DEFLATE、Brotli和zstd文件逐簇读取、边读边解压，不会把整个文件读进内存；
+    /// RLE和RLE2文件仍会先完整解压。解压后的大小要写完才能核对，
+    /// 不一致时返回 `InvalidData`，但 `out` 已经收到了部分数据。
+    pub fn copy_file_to<W: Write>(&mut self, filename: &str, out: &mut W) -> io::Result<u64> {
+        let file_entry = match self.find_file(filename)? {
+            Some(entry) => entry,
+            None => return Err(io::Error::new(io::ErrorKind::NotFound, "文件不存在")),
+        };
+
+        if file_entry.is_encrypted {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "文件已加密，请使用 read_file_encrypted 读取",
+            ));
+        }
+
+        // 空文件不论压缩方法都可能没有存储任何字节
+        if file_entry.compressed_size == 0 && file_entry.size == 0 {
+            return Ok(0);
+        }
+        if file_entry.is_inline() {
+            out.write_all(&file_entry.inline_data)?;
+            return Ok(file_entry.inline_data.len() as u64);
+        }
+
+        let Some(method) = Self::streaming_method(&file_entry) else {
+            let data = self.read_file(filename)?;
+            out.write_all(&data)?;
+            return Ok(data.len() as u64);
+        };
+
+        // 多读一个字节，解压结果比目录项记录的更长时能够发现，也不会无限制地写下去
+        let limit = file_entry.size as u64 + 1;
+        let written = io::copy(
+            &mut self.stream_decoder(&file_entry, method)?.take(limit),
+            out,
+        )?;
+
+        if written != file_entry.size as u64 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "{}解压错误：解压后大小与预期大小({})不匹配",
+                    method.name(),
+                    file_entry.size
+                ),
+            ));
+        }
+
+        Ok(written)
     }
 
-    pub fn delete_file(&mut self, filename: &str) -> io::Result<()> {
+    /// 只读取文件解压后的前 `n` 字节（文件更短时为整个文件），用于按开头的魔数识别文件类型。
+    ///
+    /// 未压缩的文件只读需要的簇；DEFLATE、Brotli和zstd边读边解压，得到 `n` 字节就停下；
+    /// RLE和RLE2没有流式解码器，仍会完整解压后截取。
+    pub fn read_file_prefix(&mut self, filename: &str, n: usize) -> io::Result<Vec<u8>> {
         let file_entry = match self.find_file(filename)? {
             Some(entry) => entry,
             None => return Err(io::Error::new(io::ErrorKind::NotFound, "文件不存在")),
         };
 
-        self.free_cluster_chain(file_entry.first_cluster)?;
+        if file_entry.is_encrypted {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "文件已加密，请使用 read_file_encrypted 读取",
+            ));
+        }
+
+        let n = n.min(file_entry.size as usize);
+        if n == 0 {
+            return Ok(Vec::new());
+        }
+        if file_entry.is_inline() {
+            return Ok(file_entry.inline_data[..n].to_vec());
+        }
 
-        let mut entry = file_entry.clone();
-        entry.is_deleted = true;
-        self.write_directory_entry(&entry)?;
+        let Some(method) = Self::streaming_method(&file_entry) else {
+            let mut data = self.read_file(filename)?;
+            data.truncate(n);
+            return Ok(data);
+        };
 
-        Ok(())
+        let mut prefix = Vec::with_capacity(n);
+        self.stream_decoder(&file_entry, method)?
+            .take(n as u64)
+            .read_to_end(&mut prefix)?;
+        if prefix.len() != n {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "{}解压错误：只解出{}字节，目录项记录的大小为{}",
+                    method.name(),
+                    prefix.len(),
+                    file_entry.size
+                ),
+            ));
+        }
+        Ok(prefix)
+    }
+
+    // 能边读边解压的压缩方法；RLE/RLE2没有流式解码器，未知方法交给 read_file 报告错误，都返回 None
+    fn streaming_method(file_entry: &FileEntry) -> Option<CompressionMethod> {
+        if !file_entry.is_compressed {
+            return Some(CompressionMethod::None);
+        }
+        match CompressionMethod::try_from(file_entry.compression_method) {
+            Ok(CompressionMethod::Rle | CompressionMethod::Rle2 | CompressionMethod::Smart)
+            | Err(_) => None,
+            Ok(method) => Some(method),
+        }
+    }
+
+    // 在簇链上按需读取、边读边解压的读取器，`method` 须来自 `streaming_method`
+    fn stream_decoder(
+        &mut self,
+        file_entry: &FileEntry,
+        method: CompressionMethod,
+    ) -> io::Result<Box<dyn Read + '_>> {
+        let chain = self.walk_chain(file_entry.first_cluster)?;
+        if chain.len() * self.geometry.cluster_size < file_entry.compressed_size as usize {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "簇链长度不足，无法读出目录项记录的全部数据",
+            ));
+        }
+        let raw = SeekableFile {
+            fs: self,
+            chain,
+            inline_data: Vec::new(),
+            size: file_entry.compressed_size as u64,
+            position: 0,
+        };
+
+        Ok(match method {
+            CompressionMethod::Deflate => Box::new(DeflateDecoder::new(raw)),
+            CompressionMethod::Brotli => Box::new(brotli::Decompressor::new(raw, 4096)),
+            CompressionMethod::Zstd => Box::new(zstd::Decoder::new(raw)?),
+            _ => Box::new(raw),
+        })
+    }
+
+    /// 把文件内容按 `chunk_size` 字节一块交给 `f`，最后一块可能不足 `chunk_size`。
+    ///
+    /// 与 `copy_file_to` 一样边读边解压，内存占用与文件大小无关（RLE/RLE2文件除外）。
+    /// `f` 返回错误时立即停止。解压后大小与目录项不符的错误要在所有数据交付之后才能发现。
+    pub fn read_file_chunks(
+        &mut self,
+        filename: &str,
+        chunk_size: usize,
+        f: impl FnMut(&[u8]) -> io::Result<()>,
+    ) -> io::Result<()> {
+        if chunk_size == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "块大小必须大于0",
+            ));
+        }
+
+        let mut chunker = ChunkWriter {
+            buf: Vec::with_capacity(chunk_size),
+            chunk_size,
+            f,
+        };
+        self.copy_file_to(filename, &mut chunker)?;
+        chunker.flush()
+    }
+
+    /// 读取文件在簇链中存储的原始字节（压缩/加密后的数据），不做解压，用于排查损坏。
+    pub fn read_raw(&mut self, filename: &str) -> io::Result<Vec<u8>> {
+        let file_entry = match self.find_file(filename)? {
+            Some(entry) => entry,
+            None => return Err(io::Error::new(io::ErrorKind::NotFound, "文件不存在")),
+        };
+
+        let raw_data = self.read_chain(&file_entry)?;
+        if raw_data.len() != file_entry.compressed_size as usize {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "簇链长度不足，无法读出目录项记录的全部数据",
+            ));
+        }
+
+        Ok(raw_data)
+    }
+
+    /// 读取镜像中的一个扇区（扇区0为启动扇区），只读，用于检查磁盘布局。
+    pub fn dump_sector(&mut self, sector: usize) -> io::Result<[u8; SECTOR_SIZE]> {
+        let total_sectors =
+            self.disk_image.size()?.saturating_sub(self.base_offset) / SECTOR_SIZE as u64;
+        if sector as u64 >= total_sectors {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "扇区号 {} 超出范围：镜像共 {} 个扇区",
+                    sector, total_sectors
+                ),
+            ));
+        }
+
+        let mut data = [0u8; SECTOR_SIZE];
+        self.seek_to((sector * SECTOR_SIZE) as u64)?;
+        self.disk_image.read_exact(&mut data)?;
+        Ok(data)
+    }
+
+    /// 读取数据区中的一个簇，只读。
+    pub fn dump_cluster(&mut self, cluster: u32) -> io::Result<Vec<u8>> {
+        self.read_cluster(cluster)
+    }
+
+    /// 按顺序列出文件占用的簇号。簇链越界、成环或指向空闲簇时返回错误。
+    pub fn cluster_chain(&mut self, filename: &str) -> io::Result<Vec<u32>> {
+        let file_entry = match self.find_file(filename)? {
+            Some(entry) => entry,
+            None => return Err(io::Error::new(io::ErrorKind::NotFound, "文件不存在")),
+        };
+
+        self.walk_chain(file_entry.first_cluster)
+    }
+
+    // 文件最后一个簇中 `compressed_size` 之后的空余部分是否全为零。读取靠 `compressed_size` 截止，
+    // 空余部分本不会被读到；测试用它发现写簇时忘记清零的改动
+    #[cfg(test)]
+    fn debug_assert_clean_slack(&mut self, filename: &str) -> io::Result<bool> {
+        let file_entry = match self.find_file(filename)? {
+            Some(entry) => entry,
+            None => return Err(io::Error::new(io::ErrorKind::NotFound, "文件不存在")),
+        };
+        let chain = self.walk_chain(file_entry.first_cluster)?;
+        let Some(&last) = chain.last() else {
+            return Ok(true);
+        };
+
+        // 最后一个簇中有效数据的长度；恰好写满时没有空余部分。簇链长度不符时按能对上的部分算
+        let used = (file_entry.compressed_size as usize)
+            .saturating_sub((chain.len() - 1) * self.geometry.cluster_size)
+            .min(self.geometry.cluster_size);
+        let cluster_data = self.read_cluster(last)?;
+        Ok(cluster_data[used..].iter().all(|&byte| byte == 0))
+    }
+
+    /// 统计从 `first_cluster` 开始的簇链长度。簇链越界、成环或指向空闲簇时返回错误。
+    ///
+    /// 正常情况下等于 `max(ceil(compressed_size / 簇大小), 1)`（`reserve_file` 预留的文件为预留的簇数），
+    /// 不一致说明簇链损坏或有泄漏。
+    pub fn chain_length(&mut self, first_cluster: u32) -> io::Result<usize> {
+        Ok(self.walk_chain(first_cluster)?.len())
+    }
+
+    // 沿FAT收集簇链，用访问标记检测环
+    fn walk_chain(&mut self, first_cluster: u32) -> io::Result<Vec<u32>> {
+        let mut chain = Vec::new();
+        if first_cluster == INLINE_CLUSTER {
+            return Ok(chain);
+        }
+        let mut visited = vec![false; self.geometry.max_clusters];
+        let mut current = first_cluster;
+
+        while current != FAT_EOC {
+            let cluster = current as usize;
+            if !(2..self.cluster_limit as usize).contains(&cluster) || visited[cluster] {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("簇链损坏：簇 {} 越界或重复出现", current),
+                ));
+            }
+            visited[cluster] = true;
+            chain.push(current);
+
+            current = self.get_next_cluster(current)?;
+            if current == FAT_FREE {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("簇链损坏：簇 {} 之后的链接被标记为空闲", cluster),
+                ));
+            }
+        }
+
+        Ok(chain)
+    }
+
+    /// 以可随机访问的方式打开未压缩（方法0）的文件，读取时只访问需要的簇。
+    ///
+    /// 压缩或加密的文件无法按偏移定位，请使用 `read_file` / `read_file_encrypted`。
+    pub fn open_seek(&mut self, filename: &str) -> io::Result<SeekableFile<'_>> {
+        let file_entry = match self.find_file(filename)? {
+            Some(entry) => entry,
+            None => return Err(io::Error::new(io::ErrorKind::NotFound, "文件不存在")),
+        };
+
+        if file_entry.is_encrypted {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "文件已加密，请使用 read_file_encrypted 读取",
+            ));
+        }
+        if file_entry.is_compressed {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "只有未压缩的文件支持随机访问，压缩文件请使用 read_file 读取",
+            ));
+        }
+
+        let chain = self.cluster_chain(filename)?;
+        if !file_entry.is_inline()
+            && chain.len() * self.geometry.cluster_size < file_entry.size as usize
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "簇链长度不足，无法容纳目录项记录的文件大小",
+            ));
+        }
+
+        Ok(SeekableFile {
+            fs: self,
+            chain,
+            inline_data: file_entry.inline_data,
+            size: file_entry.size as u64,
+            position: 0,
+        })
+    }
+
+    // 按目录项记录的压缩方法解压数据，并核对解压后的大小
+    fn decode_file(file_entry: &FileEntry, compressed_data: Vec<u8>) -> io::Result<Vec<u8>> {
+        if !file_entry.is_compressed {
+            return Ok(compressed_data);
+        }
+
+        let mut decompressed = Vec::new();
+        Self::decode_file_into(file_entry, &compressed_data, &mut decompressed)?;
+        Ok(decompressed)
+    }
+
+    // 与 decode_file 相同，但先清空 out 再把解压结果写进去
+    fn decode_file_into(
+        file_entry: &FileEntry,
+        compressed_data: &[u8],
+        out: &mut Vec<u8>,
+    ) -> io::Result<()> {
+        out.clear();
+
+        // 空文件不论压缩方法都可能没有存储任何字节，空输入交给DEFLATE解码会报错
+        if compressed_data.is_empty() && file_entry.size == 0 {
+            return Ok(());
+        }
+
+        if !file_entry.is_compressed {
+            out.extend_from_slice(compressed_data);
+            return Ok(());
+        }
+
+        if file_entry.compression_method == 0 {
+            out.extend_from_slice(compressed_data);
+            return Ok(());
+        }
+        let method_name = decompress_into(file_entry.compression_method, compressed_data, out)?;
+
+        if out.len() != file_entry.size as usize {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "{}解压错误：解压后大小({})与预期大小({})不匹配",
+                    method_name,
+                    out.len(),
+                    file_entry.size
+                ),
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// 先压缩再用AES-256-GCM加密后写入文件。
+    ///
+    /// 随机nonce存放在簇链开头，`compressed_size` 包含nonce和16字节认证标签。
+    pub fn write_file_encrypted(
+        &mut self,
+        filename: &str,
+        data: &[u8],
+        method: CompressionMethod,
+        key: &[u8; 32],
+    ) -> io::Result<FileEntry> {
+        validate_filename(filename)?;
+
+        let method = method.resolve(data);
+        let compressed_data = compress_with(data, method)?;
+
+        let cipher = Aes256Gcm::new(key.into());
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        let ciphertext = cipher
+            .encrypt(&nonce, compressed_data.as_slice())
+            .map_err(|_| io::Error::other("加密失败"))?;
+
+        let mut stored = Vec::with_capacity(NONCE_SIZE + ciphertext.len());
+        stored.extend_from_slice(&nonce);
+        stored.extend_from_slice(&ciphertext);
+
+        self.store_file(filename, data.len(), &stored, method.into(), true)
+    }
+
+    /// 读取 `write_file_encrypted` 写入的文件，密钥错误或数据被篡改时返回 `InvalidData`。
+    pub fn read_file_encrypted(&mut self, filename: &str, key: &[u8; 32]) -> io::Result<Vec<u8>> {
+        let file_entry = match self.find_file(filename)? {
+            Some(entry) => entry,
+            None => return Err(io::Error::new(io::ErrorKind::NotFound, "文件不存在")),
+        };
+
+        if !file_entry.is_encrypted {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "文件未加密，请使用 read_file 读取",
+            ));
+        }
+
+        let stored = self.read_chain(&file_entry)?;
+        if stored.len() < NONCE_SIZE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "加密数据过短，缺少nonce",
+            ));
+        }
+
+        let (nonce, ciphertext) = stored.split_at(NONCE_SIZE);
+        let cipher = Aes256Gcm::new(key.into());
+        let compressed_data = cipher
+            .decrypt(Nonce::from_slice(nonce), ciphertext)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "密钥错误或数据已损坏"))?;
+
+        Self::decode_file(&file_entry, compressed_data)
+    }
+
+    /// 文件内容与 `data` 相同时不写入，返回 false；否则按 `write_file` 写入并返回 true。
+    ///
+    /// 先比较目录项中的大小，相同时再边解压边比较，遇到第一个不同的字节即停止。
+    /// 只比较内容，内容相同而压缩方法不同时也不会重写。加密或无法读取的文件一律重写。
+    pub fn write_file_if_changed(
+        &mut self,
+        filename: &str,
+        data: &[u8],
+        compression_method: CompressionMethod,
+    ) -> io::Result<bool> {
+        if let Some(entry) = self.find_file(filename)?
+            && entry.size as usize == data.len()
+            && !entry.is_encrypted
+        {
+            let mut offset = 0;
+            let same = self.read_file_chunks(filename, self.geometry.cluster_size, |chunk| {
+                if data.get(offset..offset + chunk.len()) != Some(chunk) {
+                    return Err(io::Error::other("内容不同"));
+                }
+                offset += chunk.len();
+                Ok(())
+            });
+            if same.is_ok() {
+                return Ok(false);
+            }
+        }
+
+        self.write_file(filename, data, Some(compression_method))?;
+        Ok(true)
+    }
+
+    /// 以目录项中的编号（0-5）指定压缩方法写入，兼容以前按 `u8` 传入方法的调用方；
+    /// 编号无效时返回 `InvalidInput`。新代码请使用 `write_file`。
+    pub fn write_file_with_compression(
+        &mut self,
+        filename: &str,
+        data: &[u8],
+        compression_method: u8,
+    ) -> io::Result<FileEntry> {
+        let compression_method = CompressionMethod::try_from(compression_method)?;
+        self.write_file(filename, data, Some(compression_method))
+    }
+
+    /// 以zstd（方法5）按指定级别（1-19）压缩写入文件。级别越高压缩率越高、写入越慢，
+    /// 读取时不需要知道级别。通过 `write_file` 使用方法5时级别为3。
+    pub fn write_file_zstd(
+        &mut self,
+        filename: &str,
+        data: &[u8],
+        level: i32,
+    ) -> io::Result<FileEntry> {
+        validate_filename(filename)?;
+
+        let compressed_data = zstd_compress_data(data, level)?;
+        self.store_file(
+            filename,
+            data.len(),
+            &compressed_data,
+            CompressionMethod::Zstd.into(),
+            false,
+        )
+    }
+
+    /// 开始一个批量写入会话，写入大量小文件时复用压缩上下文，见 `WriteSession`。
+    pub fn writer_session(&mut self) -> WriteSession<'_> {
+        WriteSession {
+            fs: self,
+            encoder: DeflateEncoder::new(Vec::new(), Compression::best()),
+            spare: Vec::new(),
+        }
+    }
+
+    /// 从 `src` 读到结尾后压缩写入，调用方无需先把数据读进 `Vec`。
+    ///
+    /// 内存占用与 `write_file` 相同：源数据和压缩结果都会完整地放在内存里，
+    /// 簇链要在数据全部就绪后才写入，所以读取中途出错不会改动镜像。
+    pub fn write_file_from_reader<R: Read>(
+        &mut self,
+        filename: &str,
+        mut src: R,
+        compression_method: CompressionMethod,
+    ) -> io::Result<FileEntry> {
+        let mut data = Vec::new();
+        src.read_to_end(&mut data)?;
+        self.write_file(filename, &data, Some(compression_method))
+    }
+
+    /// 创建空文件（内联存放在目录项中，不占用簇）。文件已存在时只把修改时间更新为当前时间，不会清空内容。
+    pub fn touch(&mut self, filename: &str) -> io::Result<()> {
+        validate_filename(filename)?;
+
+        if self.exists(filename)? {
+            let now = self.now();
+            return self.update_entry(filename, |entry| entry.modified = now);
+        }
+        self.store_file(filename, 0, &[], 0, false)?;
+        Ok(())
+    }
+
+    /// 创建空文件并预先分配能容纳 `capacity_bytes` 字节（压缩后）的簇链，供之后 `append_file` 逐步填充。
+    ///
+    /// 追加的数据在预留空间内原地写入，不重新分配簇，适合日志之类不断追加的文件；
+    /// 代价是预留的簇在填满之前一直占用磁盘空间，空文件也可能占掉很多簇。
+    /// 文件已存在时返回 `AlreadyExists`。`Smart` 按DEFLATE预留。
+    pub fn reserve_file(
+        &mut self,
+        filename: &str,
+        capacity_bytes: usize,
+        method: CompressionMethod,
+    ) -> io::Result<FileEntry> {
+        validate_filename(filename)?;
+
+        let method = match method {
+            CompressionMethod::Smart => CompressionMethod::Deflate,
+            method => method,
+        };
+        let encoded = compress_with(&[], method)?;
+        let cluster_size = self.geometry.cluster_size;
+        let clusters = [
+            capacity_bytes.div_ceil(cluster_size),
+            encoded.len().div_ceil(cluster_size),
+            1,
+        ]
+        .into_iter()
+        .max()
+        .unwrap();
+        let reserved_clusters = u16::try_from(clusters).map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("预留空间过大：{}个簇，最多{}个", clusters, u16::MAX),
+            )
+        })?;
+        let max_file_size = self.geometry.max_file_size();
+        if (clusters * cluster_size) as u64 > max_file_size {
+            return Err(io::Error::new(
+                io::ErrorKind::StorageFull,
+                format!(
+                    "预留{}字节，超过单个文件的上限{}字节",
+                    clusters * cluster_size,
+                    max_file_size
+                ),
+            ));
+        }
+
+        let mut root_dir_data = self.read_root_dir()?;
+        if Self::find_live_slot(&root_dir_data, filename).is_some() {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("文件 {} 已存在", filename),
+            ));
+        }
+        let slot = Self::find_free_slot(&root_dir_data)
+            .ok_or_else(|| Self::directory_full(&root_dir_data))?;
+
+        // 预留的簇全部清零写入，之后追加时空余部分不会残留旧数据
+        let mut padded = encoded.clone();
+        padded.resize(clusters * cluster_size, 0);
+        let first_cluster = self.write_chain(&padded)?;
+        self.barrier()?;
+
+        let mut entry = FileEntry::new(
+            filename,
+            0,
+            encoded.len() as u32,
+            first_cluster,
+            method.into(),
+        );
+        entry.reserved_clusters = reserved_clusters;
+        entry.modified = self.now();
+        Self::put_slot(&mut root_dir_data, slot, &entry);
+        self.write_root_dir(&root_dir_data)?;
+        self.barrier()?;
+        Ok(entry)
+    }
+
+    /// 在文件末尾追加 `data`，压缩方法保持不变。
+    ///
+    /// `reserve_file` 创建的文件在预留空间装得下时原地写入：不压缩的文件只写入新增的字节，
+    /// 先写数据后更新目录项，中途崩溃文件仍是追加前的内容；压缩的文件需要整体重新压缩后
+    /// 原地覆盖簇链，中途崩溃可能损坏该文件。超出预留空间后改为普通的重新写入，预留随之取消。
+    /// 没有预留的文件（以及有其他链接或已加密的文件）总是读出、拼接后整体重新写入。
+    pub fn append_file(&mut self, filename: &str, data: &[u8]) -> io::Result<FileEntry> {
+        let mut root_dir_data = self.read_root_dir()?;
+        let slot = Self::find_live_slot(&root_dir_data, filename)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "文件不存在"))?;
+        let mut entry = Self::slot_entry(&root_dir_data, slot).unwrap();
+        check_not_read_only(&entry)?;
+        let method = self.compression_method(filename)?;
+
+        let new_size = u32::try_from(entry.size as usize + data.len()).map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "追加后文件过大，目录项最多记录4GiB",
+            )
+        })?;
+
+        // 不压缩时新数据直接接在原有数据后面；否则整体重新压缩，不足原长度的部分补零清掉旧数据
+        let in_place = entry.reserved_clusters > 0 && entry.links <= 1 && !entry.is_encrypted;
+        let (offset, encoded, encoded_len) = if in_place && method == CompressionMethod::None {
+            (entry.compressed_size as usize, data.to_vec(), data.len())
+        } else {
+            let mut combined = self.read_file(filename)?;
+            combined.extend_from_slice(data);
+            if !in_place {
+                return self.write_file(filename, &combined, Some(method));
+            }
+            let mut encoded = compress_with(&combined, method)?;
+            let encoded_len = encoded.len();
+            if encoded.len() < entry.compressed_size as usize {
+                encoded.resize(entry.compressed_size as usize, 0);
+            }
+            (0, encoded, encoded_len)
+        };
+
+        let chain = self.walk_chain(entry.first_cluster)?;
+        if offset + encoded.len() > chain.len() * self.geometry.cluster_size {
+            let mut combined = self.read_file(filename)?;
+            combined.extend_from_slice(data);
+            return self.write_file(filename, &combined, Some(method));
+        }
+
+        self.write_chain_at(&chain, offset, &encoded)?;
+        self.barrier()?;
+
+        entry.size = new_size;
+        entry.compressed_size = (offset + encoded_len) as u32;
+        entry.modified = self.now();
+        Self::put_slot(&mut root_dir_data, slot, &entry);
+        self.write_root_dir(&root_dir_data)?;
+        self.barrier()?;
+        Ok(entry)
+    }
+
+    // 从簇链的第 `offset` 字节开始写入 `data`，不分配新簇；首尾不满一簇的部分先读出再合并
+    fn write_chain_at(&mut self, chain: &[u32], offset: usize, data: &[u8]) -> io::Result<()> {
+        let cluster_size = self.geometry.cluster_size;
+        let mut written = 0;
+        while written < data.len() {
+            let position = offset + written;
+            let within = position % cluster_size;
+            let len = (cluster_size - within).min(data.len() - written);
+            let cluster = chain[position / cluster_size];
+
+            let mut cluster_data = self.read_cluster(cluster)?;
+            cluster_data[within..within + len].copy_from_slice(&data[written..written + len]);
+            self.write_cluster(cluster, &cluster_data)?;
+            written += len;
+        }
+        Ok(())
+    }
+
+    /// 按 `flags` 决定文件已存在或不存在时的行为，见 `WriteFlags`。
+    pub fn write_file_with_flags(
+        &mut self,
+        filename: &str,
+        data: &[u8],
+        compression_method: CompressionMethod,
+        flags: WriteFlags,
+    ) -> io::Result<FileEntry> {
+        if flags.contains(WriteFlags::TRUNCATE) && flags.contains(WriteFlags::APPEND) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "TRUNCATE 和 APPEND 不能同时使用",
+            ));
+        }
+
+        if self.exists(filename)? {
+            if flags.contains(WriteFlags::CREATE_NEW) {
+                return Err(io::Error::new(
+                    io::ErrorKind::AlreadyExists,
+                    format!("文件 {} 已存在", filename),
+                ));
+            }
+            if flags.contains(WriteFlags::APPEND) {
+                if self.stat(filename)?.reserved_clusters > 0 {
+                    return self.append_file(filename, data);
+                }
+                // 追加不改变文件的压缩方法，`compression_method` 只用于新建文件
+                let method = self.compression_method(filename)?;
+                let mut combined = self.read_file(filename)?;
+                combined.extend_from_slice(data);
+                return self.write_file(filename, &combined, Some(method));
+            }
+            if !flags.contains(WriteFlags::TRUNCATE) {
+                return Err(io::Error::new(
+                    io::ErrorKind::AlreadyExists,
+                    format!("文件 {} 已存在，覆盖需要 TRUNCATE", filename),
+                ));
+            }
+        } else if !flags.contains(WriteFlags::CREATE) && !flags.contains(WriteFlags::CREATE_NEW) {
+            return Err(io::Error::new(io::ErrorKind::NotFound, "文件不存在"));
+        }
+
+        self.write_file(filename, data, Some(compression_method))
+    }
+
+    /// 用 `new_method` 重新压缩已有文件，内容不变。
+    ///
+    /// 与 `write_file` 覆盖同名文件的流程相同：释放旧簇链、分配新簇链并更新目录项，
+    /// 压缩方法按调用者指定的存储，不会因结果变大而自动改为不压缩。返回新的目录项。
+    pub fn recompress_file(
+        &mut self,
+        filename: &str,
+        new_method: CompressionMethod,
+    ) -> io::Result<FileEntry> {
+        let modified = self.stat(filename)?.modified;
+        let data = self.read_file(filename)?;
+        let mut entry = self.write_file(filename, &data, Some(new_method))?;
+
+        // 内容没有变化，保留原来的修改时间
+        self.update_entry(filename, |entry| entry.modified = modified)?;
+        entry.modified = modified;
+        Ok(entry)
+    }
+
+    /// 把文件截断为前 `new_len` 字节，按原压缩方法重新压缩后写回。
+    ///
+    /// `new_len` 不小于文件大小时什么也不做，不会像 POSIX `ftruncate` 那样补零。
+    pub fn truncate_file(&mut self, filename: &str, new_len: usize) -> io::Result<()> {
+        let entry = self.stat(filename)?;
+        if new_len >= entry.size as usize {
+            return Ok(());
+        }
+
+        let method = self.compression_method(filename)?;
+        let mut data = self.read_file(filename)?;
+        data.truncate(new_len);
+        self.write_file(filename, &data, Some(method))?;
+        Ok(())
+    }
+
+    /// 把所有文件（包括隐藏文件）用 `target_method` 重新压缩，报告前后的总大小和每个文件的变化。
+    ///
+    /// 按压缩后大小从小到大处理。每个文件都经 `recompress_file` 影子写入，
+    /// 空间不足时原文件保持不变，记入 `skipped` 后继续处理下一个；
+    /// 加密和只读的文件也会跳过。已经使用 `target_method` 的文件不重新压缩；
+    /// `Smart` 按每个文件开头一段的内容选出具体方法，与现有方法相同的同样跳过。
+    pub fn repack_all(&mut self, target_method: CompressionMethod) -> io::Result<RepackReport> {
+        self.repack_all_with_progress(target_method, &mut |_| {})
+    }
+
+    /// 同 `repack_all`，每处理完一个文件调用一次 `progress`（单位：文件）。
+    pub fn repack_all_with_progress(
+        &mut self,
+        target_method: CompressionMethod,
+        progress: &mut dyn FnMut(Progress),
+    ) -> io::Result<RepackReport> {
+        let mut files = self.read_directory_entries()?;
+        files.sort_by_key(|entry| entry.compressed_size);
+
+        let total = files.len();
+        let mut report = RepackReport::default();
+        for (done, entry) in files.into_iter().enumerate() {
+            progress(Progress { done, total });
+            report.before_bytes += entry.compressed_size as u64;
+
+            // Smart 不会记录在目录项中，先按文件内容选出具体方法再比较；加密文件读不出内容，下面跳过
+            let target = if target_method == CompressionMethod::Smart && !entry.is_encrypted {
+                target_method.resolve(&self.read_file_prefix(&entry.name, SMART_SAMPLE_SIZE)?)
+            } else {
+                target_method
+            };
+            if entry.compression_method == u8::from(target) {
+                report.after_bytes += entry.compressed_size as u64;
+                continue;
+            }
+            if entry.is_encrypted || entry.attributes.contains(FileAttributes::READ_ONLY) {
+                report.after_bytes += entry.compressed_size as u64;
+                report
+                    .skipped
+                    .push((entry.name, io::ErrorKind::PermissionDenied));
+                continue;
+            }
+
+            match self.recompress_file(&entry.name, target) {
+                Ok(new_entry) => {
+                    report.after_bytes += new_entry.compressed_size as u64;
+                    report.files.push(RepackedFile {
+                        name: entry.name,
+                        old_method: entry.compression_method,
+                        old_size: entry.compressed_size,
+                        new_size: new_entry.compressed_size,
+                    });
+                }
+                Err(e) if e.kind() == io::ErrorKind::StorageFull => {
+                    report.after_bytes += entry.compressed_size as u64;
+                    report.skipped.push((entry.name, e.kind()));
+                }
+                Err(e) => return Err(e),
+            }
+        }
+
+        progress(Progress { done: total, total });
+        Ok(report)
+    }
+
+    /// 文件实际占用的磁盘空间：按簇分配，13字节的文件也要占满一个簇；
+    /// 12字节以内的内联文件存放在目录项中，不占用簇，`clusters` 为0。
+    pub fn file_footprint(&mut self, filename: &str) -> io::Result<Footprint> {
+        let file_entry = match self.find_file(filename)? {
+            Some(entry) => entry,
+            None => return Err(io::Error::new(io::ErrorKind::NotFound, "文件不存在")),
+        };
+
+        let clusters = self.chain_length(file_entry.first_cluster)?;
+        Ok(Footprint {
+            size: file_entry.size,
+            compressed_size: file_entry.compressed_size,
+            clusters,
+            physical_bytes: clusters as u64 * self.geometry.cluster_size as u64,
+        })
+    }
+
+    /// 返回目录项中记录的压缩方法，不读取也不解压文件内容。
+    /// 目录项中的编号无效时返回 `InvalidData`。
+    pub fn compression_method(&mut self, filename: &str) -> io::Result<CompressionMethod> {
+        let file_entry = match self.find_file(filename)? {
+            Some(entry) => entry,
+            None => return Err(io::Error::new(io::ErrorKind::NotFound, "文件不存在")),
+        };
+
+        match CompressionMethod::try_from(file_entry.compression_method) {
+            Ok(method) if method != CompressionMethod::Smart => Ok(method),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "不支持的压缩方法: {}，目录项可能已损坏",
+                    file_entry.compression_method
+                ),
+            )),
+        }
+    }
+
+    pub fn get_compression_stats(
+        &mut self,
+        filename: &str,
+    ) -> io::Result<(u32, u32, f32, &'static str)> {
+        let file_entry = match self.find_file(filename)? {
+            Some(entry) => entry,
+            None => return Err(io::Error::new(io::ErrorKind::NotFound, "文件不存在")),
+        };
+
+        let compression_name = method_name(file_entry.compression_method);
+
+        let ratio = if file_entry.size > 0 {
+            (file_entry.compressed_size as f32 / file_entry.size as f32) * 100.0
+        } else {
+            0.0
+        };
+
+        Ok((
+            file_entry.size,
+            file_entry.compressed_size,
+            ratio,
+            compression_name,
+        ))
+    }
+    pub fn image_compression_summary(&mut self) -> io::Result<ImageSummary> {
+        let mut summary = ImageSummary::default();
+
+        for file in self.read_directory_entries()? {
+            summary.total_original_bytes += file.size as u64;
+            summary.total_compressed_bytes += file.compressed_size as u64;
+
+            let usage = summary
+                .by_method
+                .entry(file.compression_method)
+                .or_default();
+            usage.files += 1;
+            usage.original_bytes += file.size as u64;
+            usage.compressed_bytes += file.compressed_size as u64;
+        }
+
+        if summary.total_original_bytes > 0 {
+            summary.ratio = (summary.total_compressed_bytes as f32
+                / summary.total_original_bytes as f32)
+                * 100.0;
+        }
+
+        Ok(summary)
+    }
+
+    /// 列出所有文件，不包括带 `HIDDEN` 属性的文件。
+    pub fn list_files(&mut self) -> io::Result<Vec<FileEntry>> {
+        self.list_files_filtered(false)
+    }
+
+    /// 列出文件，`show_hidden` 为 true 时包括隐藏文件。
+    pub fn list_files_filtered(&mut self, show_hidden: bool) -> io::Result<Vec<FileEntry>> {
+        let entries = self.read_directory_entries()?;
+        Ok(entries
+            .into_iter()
+            .filter(|entry| show_hidden || !entry.attributes.contains(FileAttributes::HIDDEN))
+            .collect())
+    }
+
+    /// 按 `by` 排序列出文件（不包括隐藏文件），`descending` 为 true 时从大到小。
+    /// 排序是稳定的，相等的文件保持目录中的顺序。
+    pub fn list_files_sorted(
+        &mut self,
+        by: SortKey,
+        descending: bool,
+    ) -> io::Result<Vec<FileEntry>> {
+        let ratio = |entry: &FileEntry| {
+            if entry.size > 0 {
+                entry.compressed_size as f32 / entry.size as f32
+            } else {
+                0.0
+            }
+        };
+
+        let mut files = self.list_files()?;
+        files.sort_by(|a, b| {
+            let ordering = match by {
+                SortKey::Name => a.name.cmp(&b.name),
+                SortKey::Size => a.size.cmp(&b.size),
+                SortKey::CompressedSize => a.compressed_size.cmp(&b.compressed_size),
+                SortKey::Ratio => ratio(a).total_cmp(&ratio(b)),
+                SortKey::Modified => a.modified.cmp(&b.modified),
+            };
+            if descending {
+                ordering.reverse()
+            } else {
+                ordering
+            }
+        });
+        Ok(files)
+    }
+
+    /// 列出修改时间晚于 `unix_secs` 的文件（包括隐藏文件），用于增量备份。
+    ///
+    /// 修改时间未知（旧版镜像写入的文件，记录为0）的文件总是包括在内，宁可多备份也不遗漏。
+    pub fn list_modified_since(&mut self, unix_secs: u64) -> io::Result<Vec<FileEntry>> {
+        Ok(self
+            .read_directory_entries()?
+            .into_iter()
+            .filter(|entry| entry.modified == 0 || entry.modified as u64 > unix_secs)
+            .collect())
+    }
+
+    /// 设置文件的属性，替换原有的全部属性位。只读文件也可以修改属性，传入 `NONE` 即可解除只读。
+    pub fn set_attributes(&mut self, filename: &str, attrs: FileAttributes) -> io::Result<()> {
+        self.update_entry(filename, |entry| entry.attributes = attrs)
+    }
+
+    /// 设置文件的备注（UTF-8，最多8字节），空字符串清除备注。每个链接各有自己的备注，覆盖文件时保留。
+    ///
+    /// 备注存放在目录项末尾的空闲字节中，只有8字节。内联文件（见 `FileEntry::is_inline`）的这几个字节
+    /// 存放的是数据，不能设置备注，返回 `InvalidInput`；清除备注总是可以的。
+    pub fn set_comment(&mut self, filename: &str, comment: &str) -> io::Result<()> {
+        self.store_comment(filename, comment, false)
+    }
+
+    // `spill_inline` 为 true 时，内联文件先把数据移到一个新分配的簇中再设置备注，之后覆盖写入也不再内联；
+    // 为 false 时拒绝给内联文件设置备注
+    fn store_comment(
+        &mut self,
+        filename: &str,
+        comment: &str,
+        spill_inline: bool,
+    ) -> io::Result<()> {
+        if comment.len() > MAX_COMMENT_SIZE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "备注过长：{}字节，最多{}字节",
+                    comment.len(),
+                    MAX_COMMENT_SIZE
+                ),
+            ));
+        }
+        if comment.chars().any(char::is_control) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "备注不能包含控制字符",
+            ));
+        }
+
+        let mut root_dir_data = self.read_root_dir()?;
+        let slot = Self::find_live_slot(&root_dir_data, filename)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "文件不存在"))?;
+        let mut entry = Self::slot_entry(&root_dir_data, slot).unwrap();
+
+        if entry.is_inline() && !comment.is_empty() && !spill_inline {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "文件 {} 的数据存放在目录项中（不超过{}字节的小文件），没有空间存放备注",
+                    filename, MAX_INLINE_SIZE
+                ),
+            ));
+        }
+
+        // 先写簇再改目录项：中途崩溃，文件仍是原来的内联文件，最多留下一个无主簇。
+        // 内联数据是原始字节，按记录的方法编码后才与普通目录项一致
+        if entry.is_inline() && !comment.is_empty() {
+            let method = CompressionMethod::try_from(entry.compression_method)?;
+            let encoded = compress_with(&entry.inline_data, method)?;
+            entry.first_cluster = self.write_chain(&encoded)?;
+            entry.compressed_size = encoded.len() as u32;
+            entry.is_compressed = method != CompressionMethod::None;
+            entry.inline_data.clear();
+            self.barrier()?;
+        }
+
+        entry.comment = comment.to_string();
+        Self::put_slot(&mut root_dir_data, slot, &entry);
+        self.write_root_dir(&root_dir_data)
+    }
+
+    /// 文件的备注，没有设置过时为 `None`。
+    pub fn comment(&mut self, filename: &str) -> io::Result<Option<String>> {
+        let entry = self
+            .find_file(filename)?
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "文件不存在"))?;
+        Ok(Some(entry.comment).filter(|comment| !comment.is_empty()))
+    }
+
+    // 只修改目录项中的元数据，不动簇链
+    fn update_entry(
+        &mut self,
+        filename: &str,
+        update: impl FnOnce(&mut FileEntry),
+    ) -> io::Result<()> {
+        let mut root_dir_data = self.read_root_dir()?;
+
+        let slot = match Self::find_live_slot(&root_dir_data, filename) {
+            Some(slot) => slot,
+            None => return Err(io::Error::new(io::ErrorKind::NotFound, "文件不存在")),
+        };
+        let mut entry = Self::slot_entry(&root_dir_data, slot).unwrap();
+
+        update(&mut entry);
+        Self::put_slot(&mut root_dir_data, slot, &entry);
+        self.write_root_dir(&root_dir_data)
+    }
+
+    /// 列出所有文件及其簇链实际占用的簇数。
+    pub fn list_files_with_clusters(&mut self) -> io::Result<Vec<(FileEntry, usize)>> {
+        let mut files = Vec::new();
+        for entry in self.list_files()? {
+            let clusters = self.chain_length(entry.first_cluster)?;
+            files.push((entry, clusters));
+        }
+        Ok(files)
+    }
+
+    /// 列出文件名匹配通配符 `pattern` 的文件，支持 `*` 和 `?`。
+    ///
+    /// 与 `find_file` 一致，按存储的文件名逐字符比较，区分大小写。与 `list_files` 一样不包括隐藏文件。
+    pub fn list_files_matching(&mut self, pattern: &str) -> io::Result<Vec<FileEntry>> {
+        let entries = self.list_files()?;
+        Ok(entries
+            .into_iter()
+            .filter(|entry| glob_match(pattern, &entry.name))
+            .collect())
+    }
+
+    /// 计算镜像的SHA-256摘要，用于快速判断镜像在两个时间点之间是否被修改过。
+    ///
+    /// 覆盖启动扇区、FAT、根目录和所有已分配的簇，空闲簇中残留的旧数据不计入。
+    /// 摘要反映的是磁盘上的布局而不只是文件内容：`defragment` 等操作移动了簇，
+    /// 即使所有文件内容不变，摘要也会改变。
+    pub fn image_digest(&mut self) -> io::Result<[u8; 32]> {
+        let mut hasher = Sha256::new();
+
+        let metadata_len = self.geometry.data_start_sector() * SECTOR_SIZE;
+        let mut metadata = vec![0u8; metadata_len];
+        self.seek_to(0)?;
+        self.disk_image.read_exact(&mut metadata)?;
+        hasher.update(&metadata);
+
+        let fat = self.read_fat()?;
+        for cluster in 2..self.cluster_limit {
+            if fat[cluster as usize] != FAT_FREE {
+                hasher.update(self.read_cluster(cluster)?);
+            }
+        }
+
+        Ok(hasher.finalize().into())
+    }
+
+    /// 导出元数据：启动扇区、FAT和根目录区原样拼接，不含数据区。用于备份镜像结构。
+    pub fn export_metadata(&mut self) -> io::Result<Vec<u8>> {
+        let mut metadata = vec![0u8; self.geometry.data_start_sector() * SECTOR_SIZE];
+        self.seek_to(0)?;
+        self.disk_image.read_exact(&mut metadata)?;
+        Ok(metadata)
+    }
+
+    /// 用 `export_metadata` 导出的数据覆盖启动扇区、FAT和根目录区，数据区不动。
+    ///
+    /// 危险操作：数据区在导出之后被改写过的话，恢复的目录项会指向已被其他文件占用的簇。
+    /// 只检查导出数据本身有效且布局和总扇区数与当前镜像相同，不检查与数据区是否一致。
+    pub fn import_metadata(&mut self, blob: &[u8]) -> io::Result<()> {
+        self.check_writable()?;
+
+        let metadata_len = self.geometry.data_start_sector() * SECTOR_SIZE;
+        if blob.len() != metadata_len {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "元数据长度为{}字节，当前镜像应为{}字节",
+                    blob.len(),
+                    metadata_len
+                ),
+            ));
+        }
+
+        let geometry = Self::parse_boot_sector(&blob[..SECTOR_SIZE])
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e.to_string()))?;
+        let boot_sector = self.dump_sector(0)?;
+        if geometry != self.geometry || blob[17..21] != boot_sector[17..21] {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "元数据的布局或总扇区数与当前镜像不同",
+            ));
+        }
+
+        self.seek_to(0)?;
+        self.disk_image.write_all(blob)?;
+        self.bitmap = ClusterBitmap::from_fat(&self.read_fat()?);
+        self.set_dirty_flag(true) // 导出时可能已经关闭过，脏标记为0
+    }
+
+    /// 估算写入 `data` 会占用的簇数（至少一个簇），只做压缩计算，不访问磁盘。
+    /// 不超过12字节的小文件实际内联存放、不占簇，这里仍按一个簇估算，结果是上限。
+    pub fn estimate_clusters(&self, data: &[u8], method: CompressionMethod) -> io::Result<usize> {
+        let compressed_size = compress_with(data, method)?.len();
+        Ok(std::cmp::max(
+            compressed_size.div_ceil(self.geometry.cluster_size),
+            1,
+        ))
+    }
+
+    /// 镜像最多能容纳的文件数，即根目录的槽位数（每个链接各占一个槽位）。
+    /// 还能新建多少个文件见 `DirStats::remaining_files`。
+    pub fn max_files(&self) -> usize {
+        self.geometry.root_entries()
+    }
+
+    /// 统计空闲簇数量，收缩镜像后可按需扩展出来的簇也计为空闲。直接读取内存中的空闲簇位图。
+    pub fn free_cluster_count(&mut self) -> io::Result<usize> {
+        let padding = self.bitmap.words.len() * 64 - self.geometry.max_clusters;
+        Ok(self.bitmap.free_count() - padding)
+    }
+
+    /// 现在能写入的新文件压缩后最多多少字节：空闲簇数乘以簇大小，不超过 `Geometry::max_file_size`；
+    /// 根目录没有空位时为0。
+    ///
+    /// 这是压缩后的上限，可压缩的数据原始大小可以远大于此，具体能否写入用 `can_store` 判断。
+    pub fn largest_writable(&mut self) -> io::Result<usize> {
+        if Self::find_free_slot(&self.read_root_dir()?).is_none() {
+            return Ok(0);
+        }
+        let free_bytes = self.free_cluster_count()? as u64 * self.geometry.cluster_size as u64;
+        Ok(std::cmp::min(free_bytes, self.geometry.max_file_size()) as usize)
+    }
+
+    /// 按 `method` 实际压缩一次，判断 `data` 现在能否作为新文件写入：簇和根目录空位都要够。
+    ///
+    /// 覆盖同名文件不需要新的目录项，但新簇链在旧簇链释放之前写入，所需的空闲簇与新文件相同。
+    pub fn can_store(&mut self, data: &[u8], method: CompressionMethod) -> io::Result<bool> {
+        let compressed_size = compress_with(data, method)?.len();
+        if compressed_size as u64 > self.geometry.max_file_size()
+            || Self::find_free_slot(&self.read_root_dir()?).is_none()
+        {
+            return Ok(false);
+        }
+        if data.len() <= MAX_INLINE_SIZE {
+            return Ok(true);
+        }
+        let clusters = std::cmp::max(compressed_size.div_ceil(self.geometry.cluster_size), 1);
+        Ok(clusters <= self.free_cluster_count()?)
+    }
+
+    /// 将主机目录下的所有普通文件（不递归子目录）以 `method` 压缩写入镜像，返回导入的文件数。
+    ///
+    /// 写入前先检查所有文件名是否合法、压缩后的数据和目录项是否放得下，
+    /// 任何一项不满足都直接报错，不会只导入一部分文件。
+    pub fn import_dir(&mut self, host_dir: &str, method: CompressionMethod) -> io::Result<usize> {
+        let mut files = Vec::new();
+        for dir_entry in std::fs::read_dir(host_dir)? {
+            let dir_entry = dir_entry?;
+            if !dir_entry.file_type()?.is_file() {
+                continue;
+            }
+
+            let name = dir_entry.file_name().into_string().map_err(|name| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("文件名不是有效的UTF-8: {:?}", name),
+                )
+            })?;
+            validate_filename(&name)
+                .map_err(|e| io::Error::new(e.kind(), format!("无法导入 {}: {}", name, e)))?;
+
+            let data = std::fs::read(dir_entry.path())?;
+            files.push((name, data));
+        }
+
+        let existing = self.read_directory_entries()?;
+        let root_dir_data = self.read_root_dir()?;
+        let mut free_slots = (0..root_dir_data.len() / DIR_ENTRY_SIZE)
+            .filter(|&slot| {
+                Self::slot_entry(&root_dir_data, slot).is_none_or(|entry| entry.is_deleted)
+            })
+            .count();
+        let mut free_clusters = self.free_cluster_count()?;
+        let mut clusters_needed = 0;
+
+        for (name, data) in &files {
+            let compressed_size = compress_with(data, method)?.len();
+            clusters_needed +=
+                std::cmp::max(compressed_size.div_ceil(self.geometry.cluster_size), 1);
+
+            // 覆盖同名文件时会先释放其占用的簇和目录项
+            match existing.iter().find(|entry| &entry.name == name) {
+                Some(entry) => {
+                    free_clusters += entry.chain_clusters(self.geometry.cluster_size);
+                }
+                None if free_slots > 0 => free_slots -= 1,
+                None => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::QuotaExceeded,
+                        "根目录空位不足，无法导入全部文件",
+                    ));
+                }
+            }
+        }
+
+        if clusters_needed > free_clusters {
+            return Err(io::Error::new(
+                io::ErrorKind::StorageFull,
+                format!(
+                    "磁盘空间不足：需要{}个簇，可用{}个簇",
+                    clusters_needed, free_clusters
+                ),
+            ));
+        }
+
+        for (name, data) in &files {
+            self.write_file(name, data, Some(method))?;
+        }
+
+        Ok(files.len())
+    }
+
+    /// 查找存储内容完全相同的文件（压缩方法、大小和簇链中的字节都一致），
+    /// 每组按目录顺序返回文件名，只包含两个及以上文件的组。
+    pub fn find_duplicates(&mut self) -> io::Result<Vec<Vec<String>>> {
+        let mut candidates: BTreeMap<(u8, u32, u32), Vec<FileEntry>> = BTreeMap::new();
+        for entry in self.read_directory_entries()? {
+            candidates
+                .entry((entry.compression_method, entry.size, entry.compressed_size))
+                .or_default()
+                .push(entry);
+        }
+
+        let mut duplicates = Vec::new();
+        for entries in candidates.into_values().filter(|entries| entries.len() > 1) {
+            // 元数据相同的文件再逐字节比较存储内容
+            let mut groups: Vec<(Vec<u8>, Vec<String>)> = Vec::new();
+            for entry in entries {
+                let data = self.read_chain(&entry)?;
+                match groups
+                    .iter_mut()
+                    .find(|(group_data, _)| *group_data == data)
+                {
+                    Some((_, names)) => names.push(entry.name),
+                    None => groups.push((data, vec![entry.name])),
+                }
+            }
+            duplicates.extend(
+                groups
+                    .into_iter()
+                    .map(|(_, names)| names)
+                    .filter(|names| names.len() > 1),
+            );
+        }
+
+        Ok(duplicates)
+    }
+
+    /// 将所有文件解压后打包为tar归档写入 `out`，归档中带着每个文件的修改时间。
+    pub fn export_tar<W: Write>(&mut self, out: W) -> io::Result<()> {
+        self.export_tar_with_progress(out, &mut |_| {})
+    }
+
+    /// 同 `export_tar`，每导出一个文件调用一次 `progress`（单位：文件）。
+    pub fn export_tar_with_progress<W: Write>(
+        &mut self,
+        out: W,
+        progress: &mut dyn FnMut(Progress),
+    ) -> io::Result<()> {
+        let mut builder = tar::Builder::new(out);
+
+        let entries = self.read_directory_entries()?;
+        let total = entries.len();
+        for (done, entry) in entries.into_iter().enumerate() {
+            progress(Progress { done, total });
+            let data = self.read_file(&entry.name)?;
+
+            let mut header = tar::Header::new_gnu();
+            header.set_size(data.len() as u64);
+            header.set_mode(0o644);
+            // 修改时间未知（旧版镜像）时为0，解出的文件日期是1970年
+            header.set_mtime(entry.modified as u64);
+            header.set_cksum();
+            builder.append_data(&mut header, &entry.name, data.as_slice())?;
+        }
+
+        progress(Progress { done: total, total });
+        builder.into_inner()?.flush()
+    }
+
+    /// 将文件从 `src_fs` 移动到 `dst_fs`：直接搬运簇链中的原始字节，不解压也不重新压缩，
+    /// 压缩方法和加密标记原样保留。写入目标镜像成功后才从源镜像删除。
+    pub fn transfer(
+        src_fs: &mut FileSystem,
+        dst_fs: &mut FileSystem,
+        filename: &str,
+    ) -> io::Result<()> {
+        if src_fs.path == dst_fs.path {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "源镜像和目标镜像是同一个文件",
+            ));
+        }
+
+        let entry = match src_fs.find_file(filename)? {
+            Some(entry) => entry,
+            None => return Err(io::Error::new(io::ErrorKind::NotFound, "文件不存在")),
+        };
+        // 只读文件无法从源镜像删除，在写入目标镜像之前就拒绝
+        check_not_read_only(&entry)?;
+        if dst_fs.exists(filename)? {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("目标镜像中已存在文件 {}", filename),
+            ));
+        }
+
+        let raw_data = src_fs.read_raw(filename)?;
+        // 内联文件存放的是未压缩的原始字节，按记录的方法重新编码后才与目录项一致
+        let raw_data = if entry.is_inline() {
+            compress_with(
+                &raw_data,
+                CompressionMethod::try_from(entry.compression_method)?,
+            )?
+        } else {
+            raw_data
+        };
+
+        // 先确认目标镜像放得下，避免写到一半才失败
+        Self::entry_slot(&dst_fs.read_root_dir()?, filename)?;
+        let needed = std::cmp::max(raw_data.len().div_ceil(dst_fs.geometry.cluster_size), 1);
+        if dst_fs.free_cluster_count()? < needed {
+            return Err(io::Error::new(
+                io::ErrorKind::StorageFull,
+                format!("目标镜像空间不足：需要 {} 个簇", needed),
+            ));
+        }
+
+        dst_fs.store_file(
+            filename,
+            entry.size as usize,
+            &raw_data,
+            entry.compression_method,
+            entry.is_encrypted,
+        )?;
+        // 属性、修改时间和备注随文件一起搬过去；小文件在目标镜像中可能内联存放，
+        // 带备注时要先移到簇中，与源镜像中一样不内联
+        dst_fs.update_entry(filename, |moved| {
+            moved.attributes = entry.attributes;
+            moved.modified = entry.modified;
+        })?;
+        if !entry.comment.is_empty() {
+            dst_fs.store_comment(filename, &entry.comment, true)?;
+        }
+        src_fs.delete_file(filename)
+    }
+
+    /// 为 `existing` 创建另一个名字 `new_name`，两个目录项共享同一条簇链和全部元数据。
+    ///
+    /// 链接数记录在每个共享簇链的目录项中，`delete_file` 删除最后一个名字时才释放簇链。
+    /// 之后覆盖写入其中一个名字时，只有这个名字指向新数据，其余名字保持原内容。
+    pub fn link(&mut self, existing: &str, new_name: &str) -> io::Result<()> {
+        validate_filename(new_name)?;
+
+        let mut root_dir_data = self.read_root_dir()?;
+        let slot = match Self::find_live_slot(&root_dir_data, existing) {
+            Some(slot) => slot,
+            None => return Err(io::Error::new(io::ErrorKind::NotFound, "文件不存在")),
+        };
+        if Self::find_live_slot(&root_dir_data, new_name).is_some() {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("文件 {} 已存在", new_name),
+            ));
+        }
+        let new_slot = Self::find_free_slot(&root_dir_data)
+            .ok_or_else(|| Self::directory_full(&root_dir_data))?;
+
+        let mut entry = Self::slot_entry(&root_dir_data, slot).unwrap();
+        if entry.links == u8::MAX {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("链接数已达上限{}", u8::MAX),
+            ));
+        }
+
+        // 内联文件没有可共享的簇链，新名字得到一份独立的副本
+        if !entry.is_inline() {
+            entry.links += 1;
+            Self::set_link_count(&mut root_dir_data, entry.first_cluster, entry.links);
+        }
+        entry.name = new_name.to_string();
+        Self::put_slot(&mut root_dir_data, new_slot, &entry);
+        self.write_root_dir(&root_dir_data)
+    }
+
+    pub fn delete_file(&mut self, filename: &str) -> io::Result<()> {
+        let mut root_dir_data = self.read_root_dir()?;
+
+        let slot = match Self::find_live_slot(&root_dir_data, filename) {
+            Some(slot) => slot,
+            None => return Err(io::Error::new(io::ErrorKind::NotFound, "文件不存在")),
+        };
+        let mut entry = Self::slot_entry(&root_dir_data, slot).unwrap();
+        check_not_read_only(&entry)?;
+
+        // 还有其他链接时只去掉这个名字，最后一个链接删除时才释放簇链
+        if entry.links > 1 {
+            Self::set_link_count(&mut root_dir_data, entry.first_cluster, entry.links - 1);
+        } else {
+            self.free_cluster_chain(entry.first_cluster)?;
+        }
+
+        // 只在原槽位上留下删除标记，槽位随后可被任何新文件复用
+        entry.is_deleted = true;
+        Self::put_slot(&mut root_dir_data, slot, &entry);
+        self.write_root_dir(&root_dir_data)
+    }
+
+    /// 与 `delete_file` 相同，但释放簇链之前先把其中每个簇清零，删除标记中也不保留大小和簇号，
+    /// 文件内容立即无法恢复，`undelete` 对它不再有效。
+    ///
+    /// 文件还有其他链接时返回 `ResourceBusy`：清零会连带毁掉其他名字的内容。
+    pub fn secure_delete(&mut self, filename: &str) -> io::Result<()> {
+        let mut root_dir_data = self.read_root_dir()?;
+
+        let slot = match Self::find_live_slot(&root_dir_data, filename) {
+            Some(slot) => slot,
+            None => return Err(io::Error::new(io::ErrorKind::NotFound, "文件不存在")),
+        };
+        let mut entry = Self::slot_entry(&root_dir_data, slot).unwrap();
+        check_not_read_only(&entry)?;
+        if entry.links > 1 {
+            return Err(io::Error::new(
+                io::ErrorKind::ResourceBusy,
+                format!(
+                    "文件 {} 还有{}个其他链接，请先删除其他名字",
+                    filename,
+                    entry.links - 1
+                ),
+            ));
+        }
+
+        // 先清零再释放：中途崩溃时文件仍在，只是内容已被破坏
+        for cluster in self.walk_chain(entry.first_cluster)? {
+            self.write_cluster(cluster, &[])?;
+        }
+        self.barrier()?;
+        self.free_cluster_chain(entry.first_cluster)?;
+
+        // 内联文件的数据就在目录项里，一并清掉
+        entry.is_deleted = true;
+        entry.size = 0;
+        entry.compressed_size = 0;
+        entry.first_cluster = 0;
+        entry.inline_data.clear();
+        entry.comment.clear();
+        Self::put_slot(&mut root_dir_data, slot, &entry);
+        self.write_root_dir(&root_dir_data)
+    }
+
+    /// 删除文件名匹配通配符 `pattern` 的所有文件（与 `list_files_matching` 一样不包括隐藏文件），
+    /// 返回删除的文件数。
+    ///
+    /// 匹配到只读文件时一个都不删除。中途出错时已删除的文件不会恢复，错误信息中会列出它们。
+    pub fn delete_matching(&mut self, pattern: &str) -> io::Result<usize> {
+        let matched = self.list_files_matching(pattern)?;
+        for entry in &matched {
+            check_not_read_only(entry)?;
+        }
+
+        let mut deleted = Vec::new();
+        for entry in &matched {
+            if let Err(e) = self.delete_file(&entry.name) {
+                return Err(io::Error::new(
+                    e.kind(),
+                    format!(
+                        "删除 {} 时出错：{}；已删除的文件：[{}]",
+                        entry.name,
+                        e,
+                        deleted.join(", ")
+                    ),
+                ));
+            }
+            deleted.push(entry.name.as_str());
+        }
+
+        Ok(deleted.len())
+    }
+
+    /// 恢复已删除的文件。删除时FAT链接已被清除，这里假定文件原本占用从首簇开始的连续簇，
+    /// 要求这些簇仍全部空闲；压缩过的文件还会先试着解码，数据已被覆盖或文件原本不连续时报错。
+    /// 不压缩和加密的文件无从校验，簇被其他文件用过又释放时恢复出的内容可能不正确。
+    /// 同名的已删除目录项有多个时恢复槽位靠前的那个。
+    pub fn undelete(&mut self, filename: &str) -> io::Result<()> {
+        let mut root_dir_data = self.read_root_dir()?;
+        if Self::find_live_slot(&root_dir_data, filename).is_some() {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("文件 {} 已存在", filename),
+            ));
+        }
+
+        let slot = (0..root_dir_data.len() / DIR_ENTRY_SIZE)
+            .find(|&slot| {
+                matches!(
+                    Self::slot_entry(&root_dir_data, slot),
+                    Some(entry) if entry.is_deleted && entry.name == filename
+                )
+            })
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::NotFound,
+                    "没有找到该文件的删除记录，槽位可能已被复用",
+                )
+            })?;
+        let mut entry = Self::slot_entry(&root_dir_data, slot).unwrap();
+
+        // 内联文件的数据还在目录项里，直接恢复
+        if entry.is_inline() {
+            entry.is_deleted = false;
+            Self::put_slot(&mut root_dir_data, slot, &entry);
+            return self.write_root_dir(&root_dir_data);
+        }
+
+        // 整理碎片会清除已删除目录项的首簇号
+        if entry.first_cluster < 2 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "删除记录中的簇信息已被清除，无法恢复",
+            ));
+        }
+
+        let cluster_count = entry.chain_clusters(self.geometry.cluster_size) as u32;
+        let chain: Vec<u32> = (entry.first_cluster..entry.first_cluster + cluster_count).collect();
+
+        let mut raw_data = Vec::new();
+        for &cluster in &chain {
+            if cluster >= self.cluster_limit || self.get_next_cluster(cluster)? != FAT_FREE {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("簇 {} 已被其他文件重新使用，无法恢复", cluster),
+                ));
+            }
+            raw_data.extend_from_slice(&self.read_cluster(cluster)?);
+        }
+        raw_data.truncate(entry.compressed_size as usize);
+
+        if !entry.is_encrypted && Self::decode_file(&entry, raw_data).is_err() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "文件数据已被覆盖或原本不连续，无法恢复",
+            ));
+        }
+
+        // 先重建簇链，再恢复目录项
+        for pair in chain.windows(2) {
+            self.set_next_cluster(pair[0], pair[1])?;
+        }
+        self.set_next_cluster(*chain.last().unwrap(), FAT_EOC)?;
+        self.barrier()?;
+
+        entry.is_deleted = false;
+        entry.links = 1; // 簇链原本空闲，说明其他链接都已删除
+        Self::put_slot(&mut root_dir_data, slot, &entry);
+        self.write_root_dir(&root_dir_data)
+    }
+
+    /// 查找簇 `cluster` 属于哪个文件，是 `cluster_chain` 的反向查询。空闲簇和无主簇返回 `None`。
+    ///
+    /// 其他文件的簇链损坏时只统计到损坏处为止，不会报错；有链接的文件返回目录中第一个名字。
+    pub fn cluster_owner(&mut self, cluster: u32) -> io::Result<Option<String>> {
+        self.check_data_cluster(cluster)?;
+        Ok(self.cluster_owner_map()?.swap_remove(cluster as usize))
+    }
+
+    // 每个簇所属的文件名。沿簇链走到越界、成环或空闲簇为止，损坏的镜像上也能使用
+    fn cluster_owner_map(&mut self) -> io::Result<Vec<Option<String>>> {
+        let fat = self.read_fat()?;
+        let mut owners: Vec<Option<String>> = vec![None; self.geometry.max_clusters];
+
+        for entry in self.read_directory_entries()? {
+            // 簇链再长也不会超过簇数，超过说明成环
+            let mut current = entry.first_cluster;
+            for _ in 2..self.cluster_limit {
+                if !(2..self.cluster_limit).contains(&current) || fat[current as usize] == FAT_FREE
+                {
+                    break;
+                }
+                owners[current as usize].get_or_insert_with(|| entry.name.clone());
+                current = fat[current as usize];
+            }
+        }
+
+        Ok(owners)
+    }
+
+    /// 把所有空闲簇清零，返回清零的簇数。已删除文件留在空闲簇中的数据从此无法读出，
+    /// `undelete` 也恢复不出原内容；已分配的簇（包括无主簇）不受影响。
+    pub fn wipe_free_space(&mut self) -> io::Result<usize> {
+        self.check_writable()?;
+
+        let fat = self.read_fat()?;
+        let mut wiped = 0;
+        for cluster in 2..self.cluster_limit {
+            if fat[cluster as usize] == FAT_FREE {
+                self.write_cluster(cluster, &[])?;
+                wiped += 1;
+            }
+        }
+        self.barrier()?;
+        Ok(wiped)
+    }
+
+    /// 回收无主簇：已分配但不属于任何文件簇链的簇（例如写入中途崩溃留下的），返回回收的簇数。
+    ///
+    /// `check` 报告任何问题时拒绝执行，以免误释放仍可能恢复的数据。
+    pub fn repair_lost_clusters(&mut self) -> io::Result<usize> {
+        if !self.check()?.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "文件系统存在错误，请先修复再回收无主簇",
+            ));
+        }
+
+        let owners = self.cluster_owner_map()?;
+
+        let mut freed = 0;
+        for cluster in 2..self.cluster_limit {
+            if owners[cluster as usize].is_none() && self.get_next_cluster(cluster)? != FAT_FREE {
+                self.set_next_cluster(cluster, FAT_FREE)?;
+                freed += 1;
+            }
+        }
+
+        self.sync_fat_checksum()?;
+        Ok(freed)
+    }
+
+    /// 根目录损坏时按FAT重建根目录（尽力而为），返回新找回的文件数。
+    ///
+    /// 仍能解析、簇链完整的目录项（以及内联文件）原样保留，其余目录项丢弃。
+    /// 没有被任何保留的目录项引用的簇链，以 `found_0001` 这样的名字重新登记：
+    /// 能完整解出DEFLATE流的记为DEFLATE压缩，原始大小准确；其余按不压缩登记，
+    /// 大小为整条簇链的字节数（末尾带着簇内的空余部分），原来的文件名、压缩方法、属性和修改时间都已丢失。
+    /// 成环、越界或与其他簇链交叉的簇链无法找回，留作无主簇。FAT本身损坏时结果不可信，
+    /// 有备份FAT时请先 `repair_fat_from_backup`。
+    pub fn reinit_directory(&mut self) -> io::Result<usize> {
+        self.check_writable()?;
+
+        let fat = self.read_fat()?;
+        let limit = self.cluster_limit as usize;
+        let root_dir_data = self.read_root_dir()?;
+        let slots = root_dir_data.len() / DIR_ENTRY_SIZE;
+        let mut claimed = vec![false; limit];
+        let mut entries: Vec<FileEntry> = Vec::new();
+
+        // 先保留还能用的目录项，共享簇链的链接一起保留
+        for slot in 0..slots {
+            let Some(entry) = Self::slot_entry(&root_dir_data, slot) else {
+                continue;
+            };
+            if entry.is_deleted || validate_filename(&entry.name).is_err() {
+                continue;
+            }
+            if entries.iter().any(|kept| kept.name == entry.name) {
+                continue;
+            }
+            if entry.is_inline() {
+                entries.push(entry);
+                continue;
+            }
+            if entries
+                .iter()
+                .any(|kept| kept.first_cluster == entry.first_cluster)
+            {
+                entries.push(entry);
+                continue;
+            }
+            let Ok(chain) = self.walk_chain(entry.first_cluster) else {
+                continue;
+            };
+            if chain.len() != entry.chain_clusters(self.geometry.cluster_size)
+                || chain.iter().any(|&cluster| claimed[cluster as usize])
+            {
+                continue;
+            }
+            for &cluster in &chain {
+                claimed[cluster as usize] = true;
+            }
+            entries.push(entry);
+        }
+
+        // 簇链的开头：已分配、且没有其他簇指向它
+        let mut pointed_to = vec![false; limit];
+        for &next in &fat[2..limit] {
+            if (2..limit).contains(&(next as usize)) {
+                pointed_to[next as usize] = true;
+            }
+        }
+
+        let mut found = 0;
+        let mut serial = 0;
+        for head in 2..limit {
+            if fat[head] == FAT_FREE || pointed_to[head] || claimed[head] {
+                continue;
+            }
+            if entries.len() == slots {
+                break; // 根目录已满，剩下的簇链留作无主簇
+            }
+            let Ok(chain) = self.walk_chain(head as u32) else {
+                continue;
+            };
+            if chain.iter().any(|&cluster| claimed[cluster as usize]) {
+                continue;
+            }
+            for &cluster in &chain {
+                claimed[cluster as usize] = true;
+            }
+
+            let mut raw_data = Vec::with_capacity(chain.len() * self.geometry.cluster_size);
+            for &cluster in &chain {
+                raw_data.extend_from_slice(&self.read_cluster(cluster)?);
+            }
+
+            // 之前找回、仍然保留着的 found_NNNN 占用的编号跳过
+            found += 1;
+            let name = loop {
+                serial += 1;
+                let name = format!("found_{:04}", serial);
+                if !entries.iter().any(|kept| kept.name == name) {
+                    break name;
+                }
+            };
+            let mut decoder = DeflateDecoder::new(raw_data.as_slice());
+            let mut decoded = Vec::new();
+            let mut entry = match decoder.read_to_end(&mut decoded) {
+                Ok(_) if u32::try_from(decoded.len()).is_ok() => FileEntry::new(
+                    &name,
+                    decoded.len() as u32,
+                    decoder.total_in() as u32,
+                    head as u32,
+                    CompressionMethod::Deflate.into(),
+                ),
+                _ => FileEntry::new(
+                    &name,
+                    raw_data.len() as u32,
+                    raw_data.len() as u32,
+                    head as u32,
+                    CompressionMethod::None.into(),
+                ),
+            };
+            // 压缩后大小用不满整条簇链时，多出的簇算作预留，免得 check 报告簇链长度不符
+            if entry.chain_clusters(self.geometry.cluster_size) != chain.len() {
+                entry.reserved_clusters = u16::try_from(chain.len()).unwrap_or(u16::MAX);
+            }
+            entries.push(entry);
+        }
+
+        // 链接数按实际保留下来的名字重新计算
+        let mut new_root_dir = vec![0u8; root_dir_data.len()];
+        for (slot, entry) in entries.iter().enumerate() {
+            let mut entry = entry.clone();
+            if !entry.is_inline() {
+                let sharers = entries
+                    .iter()
+                    .filter(|other| other.first_cluster == entry.first_cluster)
+                    .count();
+                entry.links = u8::try_from(sharers).unwrap_or(u8::MAX);
+            }
+            Self::put_slot(&mut new_root_dir, slot, &entry);
+        }
+        self.write_root_dir(&new_root_dir)?;
+        self.barrier()?;
+        Ok(found)
+    }
+
+    /// 主FAT的校验和不符时，用校验和正确的备份FAT覆盖主FAT。返回是否进行了恢复。
+    ///
+    /// 主FAT完好时不做任何修改；镜像格式化时没有启用 `FormatOptions::backup_fat` 时返回
+    /// `Unsupported`；两份FAT都不符时返回 `InvalidData`，不做任何修改。
+    pub fn repair_fat_from_backup(&mut self) -> io::Result<bool> {
+        self.check_writable()?;
+        if self.geometry.fat_copies < 2 {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "镜像没有备份FAT",
+            ));
+        }
+
+        if self.fat_checksum_matches(0)? {
+            return Ok(false);
+        }
+        if !self.fat_checksum_matches(1)? {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "主FAT和备份FAT的校验和都不匹配，无法恢复",
+            ));
+        }
+
+        let backup = self.read_fat_bytes(1)?;
+        self.seek_to((self.geometry.fat_start_sector(0) * SECTOR_SIZE) as u64)?;
+        self.disk_image.write_all(&backup)?;
+        self.bitmap = ClusterBitmap::from_fat(&self.read_fat()?);
+        Ok(true)
+    }
+
+    /// 统计根目录槽位：有效、已删除和从未使用的各有多少，只读取一次根目录区。
+    ///
+    /// 已删除的槽位和空槽位一样可以被新文件复用，根目录只有在 `live == total_slots` 时才会满。
+    pub fn directory_stats(&mut self) -> io::Result<DirStats> {
+        let root_dir_data = self.read_root_dir()?;
+        let mut stats = DirStats {
+            total_slots: self.geometry.root_entries(),
+            free_clusters: self.free_cluster_count()?,
+            ..DirStats::default()
+        };
+        for slot in 0..stats.total_slots {
+            match Self::slot_entry(&root_dir_data, slot) {
+                Some(entry) if entry.is_deleted => stats.deleted += 1,
+                Some(_) => stats.live += 1,
+                None => stats.free += 1,
+            }
+        }
+        Ok(stats)
+    }
+
+    /// 整理根目录：有效目录项按原顺序移到最前面，其余槽位全部清零。
+    ///
+    /// 不改动任何文件的数据和簇链，但已删除文件的目录项会被清除，之后无法再用 `undelete` 恢复。
+    pub fn compact_directory(&mut self) -> io::Result<()> {
+        let root_dir_data = self.read_root_dir()?;
+
+        let mut compacted = vec![0u8; root_dir_data.len()];
+        let live = (0..self.geometry.root_entries())
+            .filter_map(|slot| Self::slot_entry(&root_dir_data, slot))
+            .filter(|entry| !entry.is_deleted);
+        for (slot, entry) in live.enumerate() {
+            Self::put_slot(&mut compacted, slot, &entry);
+        }
+
+        self.write_root_dir(&compacted)
+    }
+
+    /// 整理碎片：按目录顺序把每个文件的簇链重排为从簇2开始的连续簇，空闲簇全部集中到末尾。
+    ///
+    /// 数据区、FAT和根目录依次整体重写，中途断电会使镜像不一致；
+    /// `check` 报告任何问题时拒绝执行。已删除文件的目录项会清除簇号，无法再恢复。
+    pub fn defragment(&mut self) -> io::Result<()> {
+        self.defragment_with_progress(&mut |_| {})
+    }
+
+    /// 同 `defragment`，每搬动一个簇调用一次 `progress`（单位：需要搬动的簇）。
+    pub fn defragment_with_progress(
+        &mut self,
+        progress: &mut dyn FnMut(Progress),
+    ) -> io::Result<()> {
+        if !self.check()?.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "文件系统存在错误，请先修复再整理碎片",
+            ));
+        }
+
+        let fat = self.read_fat()?;
+        let limit = self.cluster_limit as usize;
+        let mut mapping = vec![0u32; limit]; // 旧簇号 -> 新簇号，0表示未分配
+        let mut next_target = 2u32;
+
+        let mut root_dir_data = self.read_root_dir()?;
+        for slot in 0..root_dir_data.len() / DIR_ENTRY_SIZE {
+            let Some(mut entry) = Self::slot_entry(&root_dir_data, slot) else {
+                continue;
+            };
+
+            if entry.is_inline() {
+                // 没有簇链，已删除的内联文件也保留数据以便恢复
+            } else if entry.is_deleted {
+                entry.first_cluster = 0;
+            } else if mapping[entry.first_cluster as usize] != 0 {
+                // 另一个链接已经搬过这条簇链
+                entry.first_cluster = mapping[entry.first_cluster as usize];
+            } else {
+                let mut current = entry.first_cluster;
+                while current != FAT_EOC {
+                    mapping[current as usize] = next_target;
+                    next_target += 1;
+                    current = fat[current as usize];
+                }
+                entry.first_cluster = mapping[entry.first_cluster as usize];
+            }
+            Self::put_slot(&mut root_dir_data, slot, &entry);
+        }
+
+        // 没有文件引用但已分配的簇保持原有顺序，排在所有文件之后
+        for old in 2..limit {
+            if mapping[old] == 0 && fat[old] != FAT_FREE {
+                mapping[old] = next_target;
+                next_target += 1;
+            }
+        }
+
+        let mut new_fat = vec![FAT_FREE; self.geometry.max_clusters];
+        new_fat[..2].copy_from_slice(&fat[..2]);
+        let mut moved = Vec::new();
+        for old in 2..limit {
+            let new = mapping[old];
+            if new == 0 {
+                continue;
+            }
+
+            let next = fat[old] as usize;
+            new_fat[new as usize] = if next < limit && mapping[next] != 0 {
+                mapping[next]
+            } else {
+                FAT_EOC
+            };
+
+            if new as usize != old {
+                moved.push((new, self.read_cluster(old as u32)?));
+            }
+        }
+
+        let total = moved.len();
+        for (done, (new, cluster_data)) in moved.into_iter().enumerate() {
+            progress(Progress { done, total });
+            self.write_cluster(new, &cluster_data)?;
+        }
+        progress(Progress { done: total, total });
+        self.write_fat(&new_fat)?;
+        self.write_root_dir(&root_dir_data)
+    }
+
+    /// 整理碎片后截断镜像文件，去掉末尾的空闲簇并更新启动扇区中的总扇区数。
+    ///
+    /// 不会截掉任何已分配的簇；之后写入需要更多空间时，数据区会按需重新扩展到原大小。
+    pub fn shrink_to_fit(&mut self) -> io::Result<()> {
+        self.defragment()?;
+
+        // 整理后已分配的簇都排在最前面，第一个空闲簇就是新的上界
+        let mut cluster_limit = 2;
+        while cluster_limit < self.cluster_limit
+            && self.get_next_cluster(cluster_limit)? != FAT_FREE
+        {
+            cluster_limit += 1;
+        }
+
+        self.set_cluster_limit(cluster_limit)
+    }
+
+    /// 检查所有文件的簇链，报告交叉链接、损坏的簇链和长度不符的簇链，
+    /// 有备份FAT时还检查主FAT的校验和。不修改镜像。
+    pub fn check(&mut self) -> io::Result<Vec<CheckProblem>> {
+        let entries = self.read_directory_entries()?;
+        let mut owners: Vec<Vec<String>> = vec![Vec::new(); self.geometry.max_clusters];
+        let mut problems = Vec::new();
+
+        if !self.fat_checksum_matches(0)? {
+            problems.push(CheckProblem::FatChecksum);
+        }
+
+        // 按首簇统计共享簇链的目录项，链接只检查一次簇链，不算作交叉链接
+        let mut sharers: BTreeMap<u32, usize> = BTreeMap::new();
+        for entry in entries.iter().filter(|entry| !entry.is_inline()) {
+            *sharers.entry(entry.first_cluster).or_default() += 1;
+        }
+
+        let mut walked = Vec::new();
+        for entry in &entries {
+            if !is_known_method(entry.compression_method) {
+                problems.push(CheckProblem::UnknownMethod {
+                    file: entry.name.clone(),
+                    method: entry.compression_method,
+                });
+            }
+            if entry.is_inline() {
+                continue;
+            }
+
+            let actual = sharers[&entry.first_cluster];
+            if entry.links as usize != actual {
+                problems.push(CheckProblem::LinkCount {
+                    file: entry.name.clone(),
+                    expected: entry.links,
+                    actual,
+                });
+            }
+            if entry.links > 1 {
+                if walked.contains(&entry.first_cluster) {
+                    continue;
+                }
+                walked.push(entry.first_cluster);
+            }
+
+            let mut visited = vec![false; self.geometry.max_clusters];
+            let mut current = entry.first_cluster;
+            let mut length = 0;
+
+            while current != FAT_EOC {
+                let cluster = current as usize;
+                if !(2..self.cluster_limit as usize).contains(&cluster) || visited[cluster] {
+                    problems.push(CheckProblem::BadChain {
+                        file: entry.name.clone(),
+                        cluster: current,
+                    });
+                    break;
+                }
+                visited[cluster] = true;
+                owners[cluster].push(entry.name.clone());
+                length += 1;
+
+                current = self.get_next_cluster(current)?;
+                if current == FAT_FREE {
+                    // 簇链中的簇被标记为空闲，随时可能被重新分配
+                    problems.push(CheckProblem::BadChain {
+                        file: entry.name.clone(),
+                        cluster: cluster as u32,
+                    });
+                    break;
+                }
+            }
+
+            // 簇链完整时再核对长度，损坏的簇链已经报告过了
+            let expected = entry.chain_clusters(self.geometry.cluster_size);
+            if current == FAT_EOC && length != expected {
+                problems.push(CheckProblem::ChainLength {
+                    file: entry.name.clone(),
+                    expected,
+                    actual: length,
+                });
+            }
+        }
+
+        for (cluster, files) in owners.into_iter().enumerate() {
+            if files.len() > 1 {
+                problems.push(CheckProblem::CrossLinked {
+                    cluster: cluster as u32,
+                    files,
+                });
+            }
+        }
+
+        Ok(problems)
+    }
+}
+
+impl Drop for FileSystem {
+    // 所有修改都已直接写入镜像文件，这里只尽力补上FAT校验和并刷新一次。脏标记只由 close 清除：
+    // 丢弃句柄时无法确认数据已经落盘，下次挂载时仍应报告为没有正常关闭
+    fn drop(&mut self) {
+        let _ = self.sync_fat_checksum();
+        let _ = self.disk_image.flush();
+    }
+}
+
+/// 可在多个线程间共享的文件系统句柄，克隆后指向同一个镜像。
+///
+/// 所有操作都在同一把互斥锁上串行执行：多线程访问是安全的，但磁盘I/O并不会真正并发。
+/// 直接提供读写、列出、删除和统计这几类常用接口，其余 `FileSystem` 接口通过 `with` 在锁内调用。
+#[derive(Clone)]
+pub struct SharedFileSystem {
+    inner: Arc<Mutex<FileSystem>>,
+}
+
+impl SharedFileSystem {
+    pub fn new(fs: FileSystem) -> Self {
+        SharedFileSystem {
+            inner: Arc::new(Mutex::new(fs)),
+        }
+    }
+
+    fn lock(&self) -> io::Result<MutexGuard<'_, FileSystem>> {
+        self.inner
+            .lock()
+            .map_err(|_| io::Error::other("文件系统锁已失效：持有锁的线程发生了panic"))
+    }
+
+    /// 在锁内对 `FileSystem` 执行 `f`。用于这里没有直接提供的接口（包括返回借用的 `entries`、`open_seek`、
+    /// `writer_session`），以及需要连续执行、中间不能被其他线程插入的多个操作。
+    pub fn with<R>(&self, f: impl FnOnce(&mut FileSystem) -> R) -> io::Result<R> {
+        Ok(f(&mut *self.lock()?))
+    }
+
+    /// 最后一个句柄调用时关闭镜像，见 `FileSystem::close`。还有其他克隆时返回 `ResourceBusy`，
+    /// 镜像由其余句柄继续使用。
+    pub fn close(self) -> io::Result<()> {
+        let inner = Arc::try_unwrap(self.inner).map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::ResourceBusy,
+                "还有其他句柄在使用这个镜像，无法关闭",
+            )
+        })?;
+        inner
+            .into_inner()
+            .map_err(|_| io::Error::other("文件系统锁已失效：持有锁的线程发生了panic"))?
+            .close()
+    }
+
+    pub fn path(&self) -> io::Result<String> {
+        Ok(self.lock()?.path().to_string())
+    }
+
+    pub fn stat(&self, filename: &str) -> io::Result<FileEntry> {
+        self.lock()?.stat(filename)
+    }
+
+    pub fn exists(&self, filename: &str) -> io::Result<bool> {
+        self.lock()?.exists(filename)
+    }
+
+    pub fn write_file(
+        &self,
+        filename: &str,
+        data: &[u8],
+        compression_method: Option<CompressionMethod>,
+    ) -> io::Result<FileEntry> {
+        self.lock()?.write_file(filename, data, compression_method)
+    }
+
+    pub fn read_file(&self, filename: &str) -> io::Result<Vec<u8>> {
+        self.lock()?.read_file(filename)
+    }
+
+    pub fn image_compression_summary(&self) -> io::Result<ImageSummary> {
+        self.lock()?.image_compression_summary()
+    }
+
+    pub fn list_files(&self) -> io::Result<Vec<FileEntry>> {
+        self.lock()?.list_files()
+    }
+
+    pub fn free_cluster_count(&self) -> io::Result<usize> {
+        self.lock()?.free_cluster_count()
+    }
+
+    pub fn delete_file(&self, filename: &str) -> io::Result<()> {
+        self.lock()?.delete_file(filename)
+    }
+
+    pub fn directory_stats(&self) -> io::Result<DirStats> {
+        self.lock()?.directory_stats()
+    }
+}
+
+// read_file_chunks 的适配器：攒够 chunk_size 字节就交给回调，flush 时交出剩余部分
+struct ChunkWriter<F: FnMut(&[u8]) -> io::Result<()>> {
+    buf: Vec<u8>,
+    chunk_size: usize,
+    f: F,
+}
+
+impl<F: FnMut(&[u8]) -> io::Result<()>> Write for ChunkWriter<F> {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        let to_take = std::cmp::min(data.len(), self.chunk_size - self.buf.len());
+        self.buf.extend_from_slice(&data[..to_take]);
+        if self.buf.len() == self.chunk_size {
+            (self.f)(&self.buf)?;
+            self.buf.clear();
+        }
+        Ok(to_take)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if !self.buf.is_empty() {
+            (self.f)(&self.buf)?;
+            self.buf.clear();
+        }
+        Ok(())
+    }
+}
+
+/// 连续写入大量文件的会话，由 `FileSystem::writer_session` 创建。
+///
+/// DEFLATE编码器和压缩输出缓冲区在多次写入之间复用，写入几千个小文件时
+/// 省去了每次创建编码器的开销。其他压缩方法与 `write_file` 完全相同。
+pub struct WriteSession<'a> {
+    fs: &'a mut FileSystem,
+    encoder: DeflateEncoder<Vec<u8>>,
+    spare: Vec<u8>, // 上一次的压缩结果，清空后作为下一次的输出缓冲区
+}
+
+impl WriteSession<'_> {
+    /// 与 `FileSystem::write_file` 相同：压缩并写入文件，同名文件会被覆盖。
+    pub fn write(
+        &mut self,
+        filename: &str,
+        data: &[u8],
+        compression_method: Option<CompressionMethod>,
+    ) -> io::Result<FileEntry> {
+        validate_filename(filename)?;
+
+        let compression_method = compression_method
+            .unwrap_or(self.fs.default_method)
+            .resolve(data);
+        if compression_method != CompressionMethod::Deflate {
+            return self.fs.write_file(filename, data, Some(compression_method));
+        }
+
+        let out = std::mem::take(&mut self.spare);
+        let mut compressed_data = compress_data_reusing(&mut self.encoder, data, out)?;
+        let result = self.fs.store_file(
+            filename,
+            data.len(),
+            &compressed_data,
+            CompressionMethod::Deflate.into(),
+            false,
+        );
+
+        compressed_data.clear();
+        self.spare = compressed_data;
+        result
+    }
+}
+
+/// 未压缩文件的随机访问视图，由 `FileSystem::open_seek` 创建。
+///
+/// 打开时记下整条簇链，读取时按偏移换算出簇号直接读取镜像，不会把整个文件读进内存。
+pub struct SeekableFile<'a> {
+    fs: &'a mut FileSystem,
+    chain: Vec<u32>,
+    inline_data: Vec<u8>, // 内联文件没有簇链，直接从这里读
+    size: u64,
+    position: u64,
+}
+
+impl SeekableFile<'_> {
+    /// 文件的字节数
+    pub fn len(&self) -> u64 {
+        self.size
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.size == 0
+    }
+}
+
+impl Read for SeekableFile<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.position >= self.size || buf.is_empty() {
+            return Ok(0);
+        }
+        if self.chain.is_empty() {
+            let n = (&self.inline_data[self.position as usize..]).read(buf)?;
+            self.position += n as u64;
+            return Ok(n);
+        }
+
+        // 一次最多读到当前簇的末尾
+        let cluster_size = self.fs.geometry.cluster_size;
+        let cluster = self.chain[(self.position / cluster_size as u64) as usize];
+        let offset_in_cluster = (self.position % cluster_size as u64) as usize;
+        let to_read = std::cmp::min(
+            std::cmp::min(buf.len(), cluster_size - offset_in_cluster),
+            (self.size - self.position) as usize,
+        );
+
+        let offset = self.fs.geometry.cluster_offset(cluster) + offset_in_cluster as u64;
+        self.fs.seek_to(offset)?;
+        self.fs.disk_image.read_exact(&mut buf[..to_read])?;
+
+        self.position += to_read as u64;
+        Ok(to_read)
+    }
+}
+
+impl Seek for SeekableFile<'_> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let new_position = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => self.size.checked_add_signed(offset),
+            SeekFrom::Current(offset) => self.position.checked_add_signed(offset),
+        };
+
+        match new_position {
+            Some(position) => {
+                // 与 std::fs::File 一致：允许定位到文件末尾之后，读取时返回0字节
+                self.position = position;
+                Ok(position)
+            }
+            None => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "无效的定位：偏移量为负数或溢出",
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    // 临时目录下的镜像文件，每个测试一个，丢弃时删除
+    struct TempImage(String);
+
+    impl TempImage {
+        fn new() -> Self {
+            static NEXT: AtomicUsize = AtomicUsize::new(0);
+            let path = std::env::temp_dir().join(format!(
+                "tinyfs-test-{}-{}.img",
+                std::process::id(),
+                NEXT.fetch_add(1, Ordering::Relaxed)
+            ));
+            TempImage(path.to_str().unwrap().to_string())
+        }
+
+        fn path(&self) -> &str {
+            &self.0
+        }
+    }
+
+    impl Drop for TempImage {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.0);
+        }
+    }
+
+    // 临时目录下的主机目录，丢弃时连同内容删除
+    struct TempDir(std::path::PathBuf);
+
+    impl TempDir {
+        fn new() -> Self {
+            let image = TempImage::new();
+            let path = std::path::PathBuf::from(format!("{}.d", image.path()));
+            std::fs::create_dir(&path).unwrap();
+            TempDir(path)
+        }
+
+        fn path(&self) -> &str {
+            self.0.to_str().unwrap()
+        }
+
+        fn write(&self, name: &str, data: &[u8]) {
+            std::fs::write(self.0.join(name), data).unwrap();
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    // 内存中的模拟后端。镜像内容、读写记录和注入的故障放在共享状态里，FileSystem 丢弃之后
+    // 测试仍能查看和改动镜像，再用同一份内容重新挂载，相当于断电后重新上电
+    #[derive(Clone, Default)]
+    struct MockDisk {
+        state: Arc<Mutex<MockState>>,
+        position: u64,
+    }
+
+    #[derive(Default)]
+    struct MockState {
+        data: Vec<u8>,
+        reads: Vec<(u64, usize)>,                  // 每次读取的偏移和长度
+        writes: Vec<(u64, usize)>,                 // 每次成功写入的偏移和长度，按发生顺序
+        syncs: Vec<usize>,                         // 每次 sync_data 时 `writes` 的长度
+        flip_bits: Option<std::ops::Range<u64>>,   // 写入这个范围的数据落盘时第一个字节翻转最低位
+        fail_writes: Option<std::ops::Range<u64>>, // 写入与这个范围重叠时返回错误
+    }
+
+    impl MockDisk {
+        fn format(opts: FormatOptions) -> (MockDisk, FileSystem) {
+            let disk = MockDisk::default();
+            let fs = FileSystem::format_storage(Box::new(disk.clone()), "<mock>", opts).unwrap();
+            (disk, fs)
+        }
+
+        fn mount(&self) -> io::Result<FileSystem> {
+            let disk = MockDisk {
+                state: Arc::clone(&self.state),
+                position: 0,
+            };
+            FileSystem::mount_storage(Box::new(disk), "<mock>", false, 0)
+        }
+
+        fn state(&self) -> MutexGuard<'_, MockState> {
+            self.state.lock().unwrap()
+        }
+
+        // 直接改写FAT中簇 `cluster` 的表项（小端镜像的主FAT），绕过文件系统
+        fn poke_fat(&self, geometry: &Geometry, cluster: u32, next: u32) {
+            let offset = geometry.fat_start_sector(0) * SECTOR_SIZE + cluster as usize * 4;
+            self.state().data[offset..offset + 4].copy_from_slice(&next.to_le_bytes());
+        }
+
+        // 读取与 `range` 重叠的次数
+        fn reads_in(&self, range: std::ops::Range<u64>) -> usize {
+            self.state()
+                .reads
+                .iter()
+                .filter(|&&(offset, len)| offset < range.end && offset + len as u64 > range.start)
+                .count()
+        }
+    }
+
+    impl Read for MockDisk {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let mut state = self.state();
+            let start = (self.position as usize).min(state.data.len());
+            let len = buf.len().min(state.data.len() - start);
+            buf[..len].copy_from_slice(&state.data[start..start + len]);
+            state.reads.push((self.position, len));
+            drop(state);
+            self.position += len as u64;
+            Ok(len)
+        }
+    }
+
+    impl Write for MockDisk {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            let mut state = self.state();
+            let end = self.position + buf.len() as u64;
+            if let Some(range) = &state.fail_writes
+                && self.position < range.end
+                && end > range.start
+            {
+                return Err(io::Error::other("模拟的写入错误"));
+            }
+
+            let start = self.position as usize;
+            if state.data.len() < end as usize {
+                state.data.resize(end as usize, 0);
+            }
+            state.data[start..end as usize].copy_from_slice(buf);
+            if let Some(range) = state.flip_bits.clone() {
+                let first = self.position.max(range.start);
+                if first < end.min(range.end) {
+                    state.data[first as usize] ^= 1;
+                }
+            }
+            state.writes.push((self.position, buf.len()));
+            drop(state);
+            self.position = end;
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Seek for MockDisk {
+        fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+            self.position = match pos {
+                SeekFrom::Start(offset) => offset,
+                SeekFrom::End(offset) => self.state().data.len() as u64 + offset as u64,
+                SeekFrom::Current(offset) => self.position.wrapping_add(offset as u64),
+            };
+            Ok(self.position)
+        }
+    }
+
+    impl Storage for MockDisk {
+        fn size(&self) -> io::Result<u64> {
+            Ok(self.state().data.len() as u64)
+        }
+
+        fn set_len(&mut self, len: u64) -> io::Result<()> {
+            self.state().data.resize(len as usize, 0);
+            Ok(())
+        }
+
+        fn sync_data(&mut self) -> io::Result<()> {
+            let mut state = self.state();
+            let written = state.writes.len();
+            state.syncs.push(written);
+            Ok(())
+        }
+
+        fn sync_all(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    // 按默认布局格式化一个临时镜像
+    fn format_temp() -> (TempImage, FileSystem) {
+        let image = TempImage::new();
+        let fs = FileSystem::format(image.path()).unwrap();
+        (image, fs)
+    }
+
+    // 不可压缩的伪随机数据，`seed` 相同时内容相同
+    fn pseudo_random(len: usize, seed: u32) -> Vec<u8> {
+        let mut state = seed.wrapping_mul(2654435761).wrapping_add(1);
+        (0..len)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 17;
+                state ^= state << 5;
+                state as u8
+            })
+            .collect()
+    }
+
+    const ALL_METHODS: [CompressionMethod; 7] = [
+        CompressionMethod::None,
+        CompressionMethod::Rle,
+        CompressionMethod::Deflate,
+        CompressionMethod::Rle2,
+        CompressionMethod::Brotli,
+        CompressionMethod::Zstd,
+        CompressionMethod::Smart,
+    ];
+
+    fn sorted_names(entries: Vec<FileEntry>) -> Vec<String> {
+        let mut names: Vec<String> = entries.into_iter().map(|entry| entry.name).collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn list_files_matching_supports_wildcards() {
+        let (_image, mut fs) = format_temp();
+        for name in ["a.txt", "b.txt", "log1", "log22", "notes.md"] {
+            fs.write_file(name, b"data", None).unwrap();
+        }
+
+        let matching =
+            |fs: &mut FileSystem, pattern| sorted_names(fs.list_files_matching(pattern).unwrap());
+        assert_eq!(matching(&mut fs, "*.txt"), ["a.txt", "b.txt"]);
+        assert_eq!(matching(&mut fs, "log?"), ["log1"]);
+        assert_eq!(
+            matching(&mut fs, "*"),
+            ["a.txt", "b.txt", "log1", "log22", "notes.md"]
+        );
+        // 与文件名查找一致，区分大小写
+        assert!(matching(&mut fs, "*.TXT").is_empty());
+    }
+
+    #[test]
+    fn invalid_filenames_are_rejected() {
+        let (_image, mut fs) = format_temp();
+        let too_long = "x".repeat(MAX_FILENAME_LENGTH + 1);
+        for name in [
+            "",
+            "\0hidden",
+            "a/b",
+            "a\\b",
+            "tab\tname",
+            too_long.as_str(),
+        ] {
+            let err = fs.write_file(name, b"data", None).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidInput, "{:?}", name);
+        }
+        // 被拒绝的写入不会留下目录项
+        assert_eq!(fs.directory_stats().unwrap().live, 0);
+
+        let longest = "x".repeat(MAX_FILENAME_LENGTH);
+        fs.write_file(&longest, b"data", None).unwrap();
+        assert_eq!(fs.read_file(&longest).unwrap(), b"data");
+    }
+
+    #[test]
+    fn directory_full_and_disk_full_are_distinct() {
+        let (_image, mut fs) = format_temp();
+        let slots = fs.geometry().root_entries();
+        for i in 0..slots {
+            fs.write_file(&format!("f{}", i), b"x", None).unwrap();
+        }
+        let err = fs.write_file("one_more", b"x", None).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::QuotaExceeded);
+
+        // 128个簇的小镜像，数据区先满：不可压缩的数据写不下时是 StorageFull
+        let image = TempImage::new();
+        let opts = FormatOptions {
+            max_clusters: 128,
+            ..FormatOptions::default()
+        };
+        let mut fs = FileSystem::format_with(image.path(), opts).unwrap();
+        let cluster_size = fs.geometry().cluster_size;
+        fs.write_file("half", &pseudo_random(60 * cluster_size, 3), None)
+            .unwrap();
+        // 不超过单个文件的上限，确实是在分配簇时才发现空间不够
+        let free = fs.free_cluster_count().unwrap();
+        let noise = pseudo_random(free * cluster_size + 1, 7);
+        assert!((noise.len() as u64) < fs.geometry().max_file_size());
+        let err = fs
+            .write_file("big", &noise, Some(CompressionMethod::None))
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::StorageFull);
+        // 失败的写入释放了已分配的簇
+        assert_eq!(fs.free_cluster_count().unwrap(), free);
+        assert!(!fs.exists("big").unwrap());
+    }
+
+    #[test]
+    fn delete_and_recreate_never_loses_or_duplicates_entries() {
+        let (_image, mut fs) = format_temp();
+        let mut expected: BTreeMap<String, Vec<u8>> = BTreeMap::new();
+        let mut state = 12345u32;
+        for step in 0..600 {
+            state = state.wrapping_mul(1103515245).wrapping_add(12345);
+            let name = format!("file{}", (state >> 16) % 24);
+            if (state >> 8).is_multiple_of(3) {
+                let result = fs.delete_file(&name);
+                assert_eq!(result.is_ok(), expected.remove(&name).is_some());
+            } else {
+                let data = pseudo_random((state >> 4) as usize % 3000, step);
+                fs.write_file(&name, &data, None).unwrap();
+                expected.insert(name, data);
+            }
+
+            // 每个有效文件恰好占一个槽位
+            let live: Vec<String> = fs
+                .list_all_slots()
+                .unwrap()
+                .into_iter()
+                .filter_map(|(_, entry)| entry.filter(|entry| !entry.is_deleted))
+                .map(|entry| entry.name)
+                .collect();
+            let mut unique = live.clone();
+            unique.sort();
+            unique.dedup();
+            assert_eq!(unique.len(), live.len(), "同名文件占用了两个槽位");
+            assert_eq!(unique, expected.keys().cloned().collect::<Vec<_>>());
+        }
+        for (name, data) in &expected {
+            assert_eq!(&fs.read_file(name).unwrap(), data);
+        }
+        assert!(fs.check().unwrap().is_empty());
+    }
+
+    #[test]
+    fn check_detects_cross_linked_chains() {
+        let (disk, mut fs) = MockDisk::format(FormatOptions::default());
+        let cluster_size = fs.geometry().cluster_size;
+        let a = fs
+            .write_file("a", &pseudo_random(2 * cluster_size, 1), None)
+            .unwrap();
+        let b = fs
+            .write_file("b", &pseudo_random(2 * cluster_size, 2), None)
+            .unwrap();
+        assert!(fs.check().unwrap().is_empty());
+        let geometry = fs.geometry();
+        drop(fs);
+
+        // a的第一个簇改指向b的第一个簇：两条簇链从这里开始共享b的簇
+        disk.poke_fat(&geometry, a.first_cluster, b.first_cluster);
+        let mut fs = disk.mount().unwrap();
+        let problems = fs.check().unwrap();
+        assert!(
+            problems.contains(&CheckProblem::CrossLinked {
+                cluster: b.first_cluster,
+                files: vec!["a".to_string(), "b".to_string()],
+            }),
+            "{:?}",
+            problems
+        );
+    }
+
+    // 靠 allocate_cluster 中的 debug_assert 发现，release 构建中不检查
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "空闲簇位图与FAT不一致")]
+    fn allocating_a_cluster_that_is_not_free_is_caught() {
+        let (disk, mut fs) = MockDisk::format(FormatOptions::default());
+        // 绕过文件系统把第一个空闲簇标记为已用，内存中的位图仍认为它空闲
+        disk.poke_fat(&fs.geometry(), 2, FAT_EOC);
+        let _ = fs.write_file("a", &pseudo_random(100, 1), None);
+    }
+
+    #[test]
+    fn import_dir_imports_every_regular_file() {
+        let (_image, mut fs) = format_temp();
+        let dir = TempDir::new();
+        dir.write("empty", b"");
+        dir.write("notes.txt", b"hello tinyfs\n");
+        dir.write("blob.bin", &pseudo_random(5000, 1));
+        std::fs::create_dir(dir.0.join("subdir")).unwrap();
+
+        let count = fs.import_dir(dir.path(), CompressionMethod::Zstd).unwrap();
+        assert_eq!(count, 3);
+        assert_eq!(
+            sorted_names(fs.list_files().unwrap()),
+            ["blob.bin", "empty", "notes.txt"]
+        );
+        assert_eq!(fs.read_file("empty").unwrap(), b"");
+        assert_eq!(fs.read_file("notes.txt").unwrap(), b"hello tinyfs\n");
+        assert_eq!(fs.read_file("blob.bin").unwrap(), pseudo_random(5000, 1));
+        assert_eq!(
+            fs.compression_method("blob.bin").unwrap(),
+            CompressionMethod::Zstd
+        );
+    }
+
+    #[test]
+    fn import_dir_checks_names_and_capacity_before_writing() {
+        let (_image, mut fs) = format_temp();
+        let dir = TempDir::new();
+        dir.write("ok", b"data");
+        dir.write(&"x".repeat(MAX_FILENAME_LENGTH + 1), b"data");
+        let err = fs
+            .import_dir(dir.path(), CompressionMethod::Deflate)
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert!(fs.list_files().unwrap().is_empty());
+
+        // 放不下时一个文件也不写入
+        let image = TempImage::new();
+        let opts = FormatOptions {
+            max_clusters: 128,
+            ..FormatOptions::default()
+        };
+        let mut fs = FileSystem::format_with(image.path(), opts).unwrap();
+        let dir = TempDir::new();
+        let cluster_size = fs.geometry().cluster_size;
+        dir.write("a", &pseudo_random(70 * cluster_size, 1));
+        dir.write("b", &pseudo_random(70 * cluster_size, 2));
+        let err = fs
+            .import_dir(dir.path(), CompressionMethod::None)
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::StorageFull);
+        assert!(fs.list_files().unwrap().is_empty());
+    }
+
+    // 根目录区在镜像中的字节范围
+    fn root_dir_range(geometry: &Geometry) -> std::ops::Range<u64> {
+        let start = (geometry.root_dir_start_sector() * SECTOR_SIZE) as u64;
+        start..start + (geometry.root_dir_sectors * SECTOR_SIZE) as u64
+    }
+
+    #[test]
+    fn overwrite_reads_root_directory_once() {
+        let (disk, mut fs) = MockDisk::format(FormatOptions::default());
+        for i in 0..5 {
+            fs.write_file(&format!("f{}", i), &pseudo_random(3000, i), None)
+                .unwrap();
+        }
+        let root_dir = root_dir_range(&fs.geometry());
+
+        disk.state().reads.clear();
+        fs.write_file("f3", &pseudo_random(5000, 9), None).unwrap();
+        assert_eq!(disk.reads_in(root_dir), 1);
+        assert_eq!(fs.read_file("f3").unwrap(), pseudo_random(5000, 9));
+    }
+
+    #[test]
+    fn failed_directory_write_keeps_the_old_file() {
+        let (disk, mut fs) = MockDisk::format(FormatOptions::default());
+        fs.write_file("config", &pseudo_random(3000, 1), None)
+            .unwrap();
+        let free = fs.free_cluster_count().unwrap();
+
+        // 新数据已经写进簇链，提交目录项时失败
+        disk.state().fail_writes = Some(root_dir_range(&fs.geometry()));
+        let err = fs
+            .write_file("config", &pseudo_random(3000, 2), None)
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::Other);
+        drop(fs);
+
+        disk.state().fail_writes = None;
+        let mut fs = disk.mount().unwrap();
+        assert_eq!(fs.read_file("config").unwrap(), pseudo_random(3000, 1));
+        assert!(fs.check().unwrap().is_empty());
+        // 只留下新簇链成为无主簇，回收后空闲簇数与写入前相同
+        assert!(fs.repair_lost_clusters().unwrap() > 0);
+        assert_eq!(fs.free_cluster_count().unwrap(), free);
+    }
+
+    #[test]
+    fn mount_rejects_corrupted_boot_sector() {
+        let (disk, fs) = MockDisk::format(FormatOptions::default());
+        drop(fs);
+
+        disk.state().data[LABEL_OFFSET] ^= 0x20;
+        let err = disk.mount().err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn mount_accepts_legacy_zero_checksum() {
+        let (disk, fs) = MockDisk::format(FormatOptions::default());
+        drop(fs);
+
+        // 旧版镜像没有校验和，字段为0时不校验，其余字段改动也照常挂载
+        let checksum = BOOT_CHECKSUM_OFFSET..BOOT_CHECKSUM_OFFSET + 4;
+        disk.state().data[checksum].fill(0);
+        disk.state().data[LABEL_OFFSET] = b'L';
+        let mut fs = disk.mount().unwrap();
+        assert!(fs.label().unwrap().starts_with('L'));
+    }
+
+    #[test]
+    fn mount_rejects_truncated_image() {
+        let (image, fs) = format_temp();
+        drop(fs);
+        let full_len = std::fs::metadata(image.path()).unwrap().len();
+
+        // 截掉数据区的最后一个扇区，以及只剩半个启动扇区
+        for len in [full_len - SECTOR_SIZE as u64, SECTOR_SIZE as u64 / 2] {
+            let file = OpenOptions::new().write(true).open(image.path()).unwrap();
+            file.set_len(len).unwrap();
+            drop(file);
+            let err = FileSystem::mount(image.path()).err().unwrap();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData, "{}", len);
+        }
+    }
+
+    #[test]
+    fn shared_filesystem_serves_concurrent_writers() {
+        let (_image, fs) = format_temp();
+        let shared = SharedFileSystem::new(fs);
+        let threads: Vec<_> = (0..4u32)
+            .map(|t| {
+                let shared = shared.clone();
+                std::thread::spawn(move || {
+                    for i in 0..6u32 {
+                        let name = format!("t{}_{}", t, i);
+                        let data = pseudo_random(1000 + 700 * i as usize, t * 100 + i);
+                        shared.write_file(&name, &data, None).unwrap();
+                        assert_eq!(shared.read_file(&name).unwrap(), data);
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        assert_eq!(shared.list_files().unwrap().len(), 24);
+        assert_eq!(shared.directory_stats().unwrap().live, 24);
+        let summary = shared.image_compression_summary().unwrap();
+        let expected: u64 = (0..4)
+            .map(|_| (0..6).map(|i| 1000 + 700 * i as u64).sum::<u64>())
+            .sum();
+        assert_eq!(summary.total_original_bytes, expected);
+        for t in 0..4u32 {
+            for i in 0..6u32 {
+                let data = shared.read_file(&format!("t{}_{}", t, i)).unwrap();
+                assert_eq!(data, pseudo_random(1000 + 700 * i as usize, t * 100 + i));
+            }
+        }
+        assert!(shared.with(|fs| fs.check()).unwrap().unwrap().is_empty());
+        shared.close().unwrap();
+    }
+
+    #[test]
+    fn shrink_to_fit_truncates_image_and_keeps_data() {
+        let (image, mut fs) = format_temp();
+        let cluster_size = fs.geometry().cluster_size;
+        let files: Vec<(String, Vec<u8>)> = (0..20)
+            .map(|i| (format!("f{}", i), pseudo_random(3 * cluster_size, i)))
+            .collect();
+        for (name, data) in &files {
+            fs.write_file(name, data, Some(CompressionMethod::None))
+                .unwrap();
+        }
+        // 删掉大部分文件，留下的文件散落在数据区各处
+        for (name, _) in files
+            .iter()
+            .filter(|(name, _)| name != "f3" && name != "f17")
+        {
+            fs.delete_file(name).unwrap();
+        }
+        let before = std::fs::metadata(image.path()).unwrap().len();
+        fs.shrink_to_fit().unwrap();
+        drop(fs);
+
+        let after = std::fs::metadata(image.path()).unwrap().len();
+        assert!(after < before, "{} -> {}", before, after);
+        let mut fs = FileSystem::mount(image.path()).unwrap();
+        for (name, data) in files
+            .iter()
+            .filter(|(name, _)| name == "f3" || name == "f17")
+        {
+            assert_eq!(&fs.read_file(name).unwrap(), data);
+        }
+        assert!(fs.check().unwrap().is_empty());
+
+        // 截断后仍能写入需要更多簇的文件
+        let big = pseudo_random(40 * cluster_size, 99);
+        fs.write_file("big", &big, Some(CompressionMethod::None))
+            .unwrap();
+        assert_eq!(fs.read_file("big").unwrap(), big);
+        assert!(fs.check().unwrap().is_empty());
+    }
+
+    #[test]
+    fn estimate_clusters_matches_what_a_write_allocates() {
+        let (_image, mut fs) = format_temp();
+        let cluster_size = fs.geometry().cluster_size;
+        let inputs = [
+            vec![b'a'; 10 * cluster_size],
+            pseudo_random(3 * cluster_size + 1, 5),
+        ];
+        for method in ALL_METHODS {
+            // 空输入也至少一个簇
+            assert_eq!(
+                fs.estimate_clusters(&[], method).unwrap(),
+                1,
+                "{:?}",
+                method
+            );
+            for (i, data) in inputs.iter().enumerate() {
+                let estimate = fs.estimate_clusters(data, method).unwrap();
+                let free = fs.free_cluster_count().unwrap();
+                fs.write_file(&format!("f{}", i), data, Some(method))
+                    .unwrap();
+                let used = free - fs.free_cluster_count().unwrap();
+                assert_eq!(estimate, used, "{:?} {}", method, data.len());
+                fs.delete_file(&format!("f{}", i)).unwrap();
+            }
+        }
+        // 估算不改动镜像
+        assert_eq!(fs.list_files().unwrap().len(), 0);
+        let repetitive = fs
+            .estimate_clusters(&inputs[0], CompressionMethod::Deflate)
+            .unwrap();
+        let raw = fs
+            .estimate_clusters(&inputs[0], CompressionMethod::None)
+            .unwrap();
+        assert_eq!(raw, 10);
+        assert!(repetitive < raw);
+    }
+
+    #[test]
+    fn empty_files_round_trip_with_every_method() {
+        let image = TempImage::new();
+        let mut fs = FileSystem::format(image.path()).unwrap();
+        for method in ALL_METHODS {
+            let name = format!("empty{}", method as u8);
+            fs.write_file(&name, b"", Some(method)).unwrap();
+            assert_eq!(fs.read_file(&name).unwrap(), b"");
+
+            // 解码路径：簇链上没有存储任何字节，或者存的是压缩空输入得到的头部
+            if method != CompressionMethod::Smart {
+                let entry = FileEntry::new(&name, 0, 0, 2, method as u8);
+                assert_eq!(FileSystem::decode_file(&entry, Vec::new()).unwrap(), b"");
+                let compressed = compress_with(b"", method).unwrap();
+                let entry = FileEntry::new(&name, 0, compressed.len() as u32, 2, method as u8);
+                assert_eq!(FileSystem::decode_file(&entry, compressed).unwrap(), b"");
+            }
+        }
+        fs.close().unwrap();
+
+        let mut fs = FileSystem::mount(image.path()).unwrap();
+        for method in ALL_METHODS {
+            assert_eq!(
+                fs.read_file(&format!("empty{}", method as u8)).unwrap(),
+                b""
+            );
+        }
+        assert!(fs.check().unwrap().is_empty());
+    }
+
+    #[test]
+    fn write_file_commits_the_entry_after_its_data() {
+        let (disk, mut fs) = MockDisk::format(FormatOptions::default());
+        let geometry = fs.geometry();
+        let root_dir = root_dir_range(&geometry);
+        let data_start = (geometry.data_start_sector() * SECTOR_SIZE) as u64;
+        fs.write_file("old", &pseudo_random(5000, 1), None).unwrap();
+
+        disk.state().writes.clear();
+        fs.write_file("old", &pseudo_random(5000, 2), None).unwrap();
+        let writes = disk.state().writes.clone();
+        let in_root_dir = |&(offset, _): &(u64, usize)| root_dir.contains(&offset);
+        let commit = writes.iter().position(in_root_dir).unwrap();
+        // 目录项只写一次，之前已经写完全部新数据，之后只有释放旧簇链的FAT写入
+        assert_eq!(writes.iter().filter(|w| in_root_dir(w)).count(), 1);
+        assert!(
+            writes[..commit]
+                .iter()
+                .any(|&(offset, _)| offset >= data_start)
+        );
+        assert!(
+            writes[commit + 1..]
+                .iter()
+                .all(|&(offset, _)| offset < data_start)
+        );
+
+        // 在提交目录项之前崩溃：新文件不存在，已写入的簇链只是无主簇
+        let free = fs.free_cluster_count().unwrap();
+        disk.state().fail_writes = Some(root_dir);
+        fs.write_file("new", &pseudo_random(5000, 3), None)
+            .unwrap_err();
+        drop(fs);
+        disk.state().fail_writes = None;
+
+        let mut fs = disk.mount().unwrap();
+        assert!(!fs.exists("new").unwrap());
+        assert_eq!(fs.read_file("old").unwrap(), pseudo_random(5000, 2));
+        assert!(fs.check().unwrap().is_empty());
+        assert!(fs.free_cluster_count().unwrap() < free);
+        fs.repair_lost_clusters().unwrap();
+        assert_eq!(fs.free_cluster_count().unwrap(), free);
+    }
+
+    #[test]
+    fn write_barriers_surround_the_entry_commit() {
+        let (disk, mut fs) = MockDisk::format(FormatOptions::default());
+        let root_dir = root_dir_range(&fs.geometry());
+        let data_start = (fs.geometry().data_start_sector() * SECTOR_SIZE) as u64;
+
+        let write_and_log = |fs: &mut FileSystem, seed| {
+            {
+                let mut state = disk.state();
+                state.writes.clear();
+                state.syncs.clear();
+            }
+            fs.write_file("f", &pseudo_random(5000, seed), None)
+                .unwrap();
+            let state = disk.state();
+            (state.writes.clone(), state.syncs.clone())
+        };
+
+        let (writes, syncs) = write_and_log(&mut fs, 1);
+        let commit = writes
+            .iter()
+            .position(|&(offset, _)| root_dir.contains(&offset))
+            .unwrap();
+        let last_data = writes
+            .iter()
+            .rposition(|&(offset, _)| offset >= data_start)
+            .unwrap();
+        assert!(last_data < commit);
+        // 数据写完之后、目录项之前有一次屏障，目录项之后还有一次
+        assert!(
+            syncs.iter().any(|&n| n > last_data && n <= commit),
+            "{:?}",
+            syncs
+        );
+        assert!(syncs.iter().any(|&n| n > commit), "{:?}", syncs);
+
+        fs.set_write_barriers(false);
+        let (_, syncs) = write_and_log(&mut fs, 2);
+        assert!(syncs.is_empty());
+        assert_eq!(fs.read_file("f").unwrap(), pseudo_random(5000, 2));
+    }
+
+    #[test]
+    fn brotli_round_trips_text_denser_than_deflate() {
+        let (_image, mut fs) = format_temp();
+        let words = [
+            "tinyfs",
+            "簇",
+            "目录项",
+            "compression",
+            "brotli",
+            "the",
+            "FAT",
+            "数据",
+        ];
+        let mut text = String::new();
+        let mut state = 7u32;
+        while text.len() < 64 * 1024 {
+            state = state.wrapping_mul(1103515245).wrapping_add(12345);
+            text.push_str(words[(state >> 16) as usize % words.len()]);
+            text.push(if (state >> 8).is_multiple_of(11) {
+                '\n'
+            } else {
+                ' '
+            });
+        }
+
+        let brotli = fs
+            .write_file("b.txt", text.as_bytes(), Some(CompressionMethod::Brotli))
+            .unwrap();
+        let deflate = fs
+            .write_file("d.txt", text.as_bytes(), Some(CompressionMethod::Deflate))
+            .unwrap();
+        assert_eq!(brotli.compression_method, CompressionMethod::Brotli as u8);
+        assert!(
+            brotli.compressed_size < deflate.compressed_size,
+            "{} >= {}",
+            brotli.compressed_size,
+            deflate.compressed_size
+        );
+        assert_eq!(fs.read_file("b.txt").unwrap(), text.as_bytes());
+        assert_eq!(
+            fs.compression_method("b.txt").unwrap(),
+            CompressionMethod::Brotli
+        );
+
+        // 读取按目录项中的方法字节选择解码器：改成DEFLATE后同样的数据解不出来
+        let mut entry = brotli.clone();
+        entry.compression_method = CompressionMethod::Deflate as u8;
+        let raw = fs.read_raw("b.txt").unwrap();
+        assert!(FileSystem::decode_file(&entry, raw).is_err());
+    }
+
+    #[test]
+    fn unknown_method_byte_is_reported_and_rejected() {
+        let (disk, mut fs) = MockDisk::format(FormatOptions::default());
+        fs.write_file("good", &pseudo_random(3000, 1), None)
+            .unwrap();
+        fs.write_file("bad", &pseudo_random(3000, 2), None).unwrap();
+        let slot = fs
+            .list_all_slots()
+            .unwrap()
+            .into_iter()
+            .find(|(_, entry)| entry.as_ref().is_some_and(|entry| entry.name == "bad"))
+            .unwrap()
+            .0;
+        let offset = root_dir_range(&fs.geometry()).start as usize + slot * DIR_ENTRY_SIZE + 46;
+        drop(fs);
+
+        disk.state().data[offset] = 200;
+        let mut fs = disk.mount().unwrap();
+        assert_eq!(
+            fs.check().unwrap(),
+            [CheckProblem::UnknownMethod {
+                file: "bad".to_string(),
+                method: 200,
+            }]
+        );
+        let err = fs.read_file("bad").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(fs.read_file("good").unwrap(), pseudo_random(3000, 1));
+    }
+
+    #[test]
+    fn write_flags_follow_open_options_semantics() {
+        let (_image, mut fs) = format_temp();
+        let deflate = CompressionMethod::Deflate;
+        let first = vec![b'a'; 3000];
+
+        // CREATE_NEW：只能新建
+        fs.write_file_with_flags("f", &first, deflate, WriteFlags::CREATE_NEW)
+            .unwrap();
+        let err = fs
+            .write_file_with_flags("f", b"other", deflate, WriteFlags::CREATE_NEW)
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::AlreadyExists);
+
+        // 单独的 CREATE 不覆盖已有文件，不存在时才创建
+        let err = fs
+            .write_file_with_flags("f", b"other", deflate, WriteFlags::CREATE)
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::AlreadyExists);
+        assert_eq!(fs.read_file("f").unwrap(), first);
+        fs.write_file_with_flags("g", b"created", deflate, WriteFlags::CREATE)
+            .unwrap();
+
+        // 没有 CREATE 时不存在的文件报 NotFound
+        let err = fs
+            .write_file_with_flags("missing", b"x", deflate, WriteFlags::TRUNCATE)
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+
+        // TRUNCATE 覆盖原内容，压缩方法按本次调用
+        fs.write_file_with_flags(
+            "g",
+            b"replaced",
+            CompressionMethod::Rle,
+            WriteFlags::TRUNCATE,
+        )
+        .unwrap();
+        assert_eq!(fs.read_file("g").unwrap(), b"replaced");
+
+        // APPEND 追加到原内容之后，沿用文件原来的压缩方法而不是本次传入的
+        let tail = pseudo_random(2000, 1);
+        let entry = fs
+            .write_file_with_flags("f", &tail, CompressionMethod::None, WriteFlags::APPEND)
+            .unwrap();
+        assert_eq!(entry.compression_method, deflate as u8);
+        assert_eq!(fs.compression_method("f").unwrap(), deflate);
+        assert_eq!(fs.read_file("f").unwrap(), [first, tail].concat());
+
+        let err = fs
+            .write_file_with_flags(
+                "f",
+                b"x",
+                deflate,
+                WriteFlags::TRUNCATE | WriteFlags::APPEND,
+            )
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn zstd_round_trips_at_any_level_and_beats_deflate() {
+        let (_image, mut fs) = format_temp();
+        // 重复度较高的样本：日志行中夹着递增的数字
+        let sample: Vec<u8> = (0..3000)
+            .flat_map(|i| format!("{} INFO tinyfs: wrote cluster {}\n", i * 7, i % 97).into_bytes())
+            .collect();
+
+        let deflate = fs
+            .write_file("d", &sample, Some(CompressionMethod::Deflate))
+            .unwrap();
+        let default = fs
+            .write_file("z", &sample, Some(CompressionMethod::Zstd))
+            .unwrap();
+        assert_eq!(default.compression_method, CompressionMethod::Zstd as u8);
+        assert!(default.compressed_size < deflate.compressed_size);
+        assert_eq!(fs.read_file("z").unwrap(), sample);
+
+        fs.write_file_zstd("z1", &sample, 1).unwrap();
+        let best = fs.write_file_zstd("z19", &sample, ZSTD_MAX_LEVEL).unwrap();
+        assert!(best.compressed_size < deflate.compressed_size);
+        // 级别不写入目录项，两种级别都按方法5解压
+        assert_eq!(fs.read_file("z1").unwrap(), sample);
+        assert_eq!(fs.read_file("z19").unwrap(), sample);
+
+        for level in [0, ZSTD_MAX_LEVEL + 1] {
+            let err = fs.write_file_zstd("bad", &sample, level).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        }
+        assert!(!fs.exists("bad").unwrap());
+    }
+
+    #[test]
+    fn free_cluster_bitmap_agrees_with_fat() {
+        let (_image, mut fs) = format_temp();
+        let cluster_size = fs.geometry().cluster_size;
+        let mut state = 99u32;
+        for step in 0..200 {
+            state = state.wrapping_mul(1103515245).wrapping_add(12345);
+            let name = format!("f{}", (state >> 16) % 16);
+            if (state >> 8).is_multiple_of(4) {
+                let _ = fs.delete_file(&name);
+            } else {
+                let len = (state >> 4) as usize % (6 * cluster_size);
+                fs.write_file(&name, &pseudo_random(len, step), None)
+                    .unwrap();
+            }
+
+            let fat = fs.read_fat().unwrap();
+            assert_eq!(fs.bitmap.words, ClusterBitmap::from_fat(&fat).words);
+            let free = fat[2..].iter().filter(|&&next| next == FAT_FREE).count();
+            assert_eq!(fs.free_cluster_count().unwrap(), free);
+        }
+    }
+
+    #[test]
+    fn chain_length_reveals_an_extended_chain() {
+        let (disk, mut fs) = MockDisk::format(FormatOptions::default());
+        let cluster_size = fs.geometry().cluster_size;
+        let entry = fs
+            .write_file("f", &pseudo_random(2 * cluster_size + 1, 1), None)
+            .unwrap();
+        let chain = fs.cluster_chain("f").unwrap();
+        assert_eq!(fs.chain_length(entry.first_cluster).unwrap(), 3);
+        assert_eq!(chain.len(), 3);
+        assert!(fs.check().unwrap().is_empty());
+
+        // 把一个空闲簇接到簇链末尾，相当于写入失败后没有释放的多余簇
+        let geometry = fs.geometry();
+        let extra = (2..fs.cluster_limit)
+            .find(|&cluster| !chain.contains(&cluster))
+            .unwrap();
+        drop(fs);
+        disk.poke_fat(&geometry, *chain.last().unwrap(), extra);
+        disk.poke_fat(&geometry, extra, FAT_EOC);
+
+        let mut fs = disk.mount().unwrap();
+        assert_eq!(fs.chain_length(entry.first_cluster).unwrap(), 4);
+        assert_eq!(
+            fs.check().unwrap(),
+            [CheckProblem::ChainLength {
+                file: "f".to_string(),
+                expected: 3,
+                actual: 4,
+            }]
+        );
+        // 多出来的簇不影响读取，仍按 compressed_size 截止
+        assert_eq!(
+            fs.read_file("f").unwrap(),
+            pseudo_random(2 * cluster_size + 1, 1)
+        );
+    }
+
+    #[test]
+    fn cyclic_chain_fails_reads_and_deletes_instead_of_spinning() {
+        let (disk, mut fs) = MockDisk::format(FormatOptions::default());
+        let cluster_size = fs.geometry().cluster_size;
+        fs.write_file("ring", &pseudo_random(3 * cluster_size, 1), None)
+            .unwrap();
+        fs.write_file("self", &pseudo_random(cluster_size, 2), None)
+            .unwrap();
+        let ring = fs.cluster_chain("ring").unwrap();
+        let single = fs.cluster_chain("self").unwrap()[0];
+        let geometry = fs.geometry();
+        drop(fs);
+
+        // 最后一个簇指回第一个簇；另一个文件的簇指向自己
+        disk.poke_fat(&geometry, ring[2], ring[0]);
+        disk.poke_fat(&geometry, single, single);
+
+        let mut fs = disk.mount().unwrap();
+        let free = fs.free_cluster_count().unwrap();
+        for name in ["ring", "self"] {
+            assert_eq!(
+                fs.read_file(name).unwrap_err().kind(),
+                io::ErrorKind::InvalidData
+            );
+            assert_eq!(
+                fs.delete_file(name).unwrap_err().kind(),
+                io::ErrorKind::InvalidData
+            );
+            assert!(fs.cluster_chain(name).is_err());
+        }
+        // 删除失败时没有释放任何簇，目录项也还在
+        assert_eq!(fs.free_cluster_count().unwrap(), free);
+        assert!(fs.exists("ring").unwrap());
+        let problems = fs.check().unwrap();
+        assert!(
+            problems.iter().any(
+                |problem| matches!(problem, CheckProblem::BadChain { file, .. } if file == "ring")
+            ),
+            "{:?}",
+            problems
+        );
+    }
+
+    #[test]
+    fn out_of_range_first_cluster_is_rejected_without_growing_the_image() {
+        let (disk, mut fs) = MockDisk::format(FormatOptions::default());
+        fs.write_file("f", &pseudo_random(3000, 1), None).unwrap();
+        let offset = root_dir_range(&fs.geometry()).start as usize + 40;
+        drop(fs);
+
+        disk.state().data[offset..offset + 4].copy_from_slice(&0xFFFF0000u32.to_le_bytes());
+        let len = disk.state().data.len();
+        let mut fs = disk.mount().unwrap();
+        assert_eq!(
+            fs.read_file("f").unwrap_err().kind(),
+            io::ErrorKind::InvalidData
+        );
+        assert_eq!(
+            fs.delete_file("f").unwrap_err().kind(),
+            io::ErrorKind::InvalidData
+        );
+        assert!(!fs.check().unwrap().is_empty());
+        // 没有按越界簇号去定位，镜像没有被扩展
+        assert_eq!(disk.state().data.len(), len);
+        assert_eq!(disk.reads_in(len as u64..u64::MAX), 0);
+    }
+
+    #[test]
+    fn rle2_round_trips_and_bounds_expansion() {
+        let random = pseudo_random(10_000, 4);
+        let mut mixed = vec![0u8; 1000];
+        mixed.extend_from_slice(&random[..300]);
+        mixed.extend_from_slice(&[7; 2]); // 短于最短游程，按字面字节处理
+        mixed.extend_from_slice(&[9; RLE2_MAX_RUN + 1]); // 超过最长游程，拆成两段
+        let inputs = [vec![b'x'; 5000], random.clone(), mixed];
+        for data in &inputs {
+            let compressed = rle2_compress_data(data);
+            let mut out = Vec::new();
+            rle2_decompress_data(&compressed, &mut out).unwrap();
+            assert_eq!(&out, data);
+        }
+
+        // 不可压缩的数据每128字节只多1字节，旧的RLE则翻倍
+        let compressed = rle2_compress_data(&random);
+        assert!(compressed.len() <= random.len() + random.len().div_ceil(RLE2_MAX_LITERAL));
+        assert!(rle_compress_data(&random).len() > random.len() * 19 / 10);
+        assert!(rle2_compress_data(&inputs[0]).len() < 100);
+
+        // 通过文件系统读写同样按方法3解码
+        let (_image, mut fs) = format_temp();
+        for (i, data) in inputs.iter().enumerate() {
+            let name = format!("f{}", i);
+            let entry = fs
+                .write_file(&name, data, Some(CompressionMethod::Rle2))
+                .unwrap();
+            assert_eq!(entry.compression_method, CompressionMethod::Rle2 as u8);
+            assert_eq!(&fs.read_file(&name).unwrap(), data);
+        }
+    }
+
+    #[test]
+    fn close_persists_every_change_for_the_next_mount() {
+        let image = TempImage::new();
+        let mut fs = FileSystem::format(image.path()).unwrap();
+        let big = pseudo_random(20_000, 1);
+        fs.write_file("big", &big, None).unwrap();
+        fs.write_file("gone", b"temporary data", None).unwrap();
+        fs.write_file("small", b"tiny", None).unwrap();
+        fs.delete_file("gone").unwrap();
+        fs.set_label("PERSIST").unwrap();
+        fs.set_attributes("small", FileAttributes::READ_ONLY)
+            .unwrap();
+        let free = fs.free_cluster_count().unwrap();
+        fs.close().unwrap();
+
+        let mut fs = FileSystem::mount(image.path()).unwrap();
+        assert!(!fs.is_dirty());
+        assert_eq!(fs.read_file("big").unwrap(), big);
+        assert_eq!(fs.read_file("small").unwrap(), b"tiny");
+        assert!(!fs.exists("gone").unwrap());
+        assert_eq!(fs.label().unwrap(), "PERSIST");
+        assert_eq!(
+            fs.stat("small").unwrap().attributes,
+            FileAttributes::READ_ONLY
+        );
+        // 空闲簇位图在挂载时从FAT重建，与关闭前一致
+        assert_eq!(fs.free_cluster_count().unwrap(), free);
+        assert!(fs.check().unwrap().is_empty());
+    }
+
+    #[test]
+    fn attributes_protect_and_hide_files() {
+        let image = TempImage::new();
+        let mut fs = FileSystem::format(image.path()).unwrap();
+        fs.write_file("locked", b"keep me", None).unwrap();
+        fs.write_file("secret", b"hidden", None).unwrap();
+        fs.write_file("plain", b"normal", None).unwrap();
+        fs.set_attributes("locked", FileAttributes::READ_ONLY)
+            .unwrap();
+        fs.set_attributes("secret", FileAttributes::HIDDEN).unwrap();
+        fs.close().unwrap();
+
+        // 属性位随目录项持久化
+        let mut fs = FileSystem::mount(image.path()).unwrap();
+
+        // 只读：覆盖和删除都被拒绝，内容不变，仍可读取
+        let err = fs.write_file("locked", b"changed", None).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+        let err = fs.delete_file("locked").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+        assert_eq!(fs.read_file("locked").unwrap(), b"keep me");
+
+        // 隐藏：默认列表中没有，show_hidden 时才出现，按名字仍能读取
+        assert_eq!(sorted_names(fs.list_files().unwrap()), ["locked", "plain"]);
+        assert_eq!(
+            sorted_names(fs.list_files_filtered(true).unwrap()),
+            ["locked", "plain", "secret"]
+        );
+        assert!(fs.list_files_matching("sec*").unwrap().is_empty());
+        assert_eq!(fs.read_file("secret").unwrap(), b"hidden");
+        // 隐藏文件不是只读的
+        fs.write_file("secret", b"updated", None).unwrap();
+
+        // 清除属性后恢复正常
+        fs.set_attributes("locked", FileAttributes::NONE).unwrap();
+        fs.delete_file("locked").unwrap();
+        let both = FileAttributes::READ_ONLY | FileAttributes::HIDDEN;
+        fs.set_attributes("plain", both).unwrap();
+        assert!(
+            fs.list_files()
+                .unwrap()
+                .iter()
+                .all(|entry| entry.name != "plain")
+        );
+        assert_eq!(
+            fs.delete_file("plain").unwrap_err().kind(),
+            io::ErrorKind::PermissionDenied
+        );
+    }
+
+    #[test]
+    fn get_or_create_formats_only_missing_images() {
+        let image = TempImage::new();
+        let mut fs = FileSystem::get_or_create(image.path()).unwrap();
+        fs.write_file("keep", b"precious data", None).unwrap();
+        fs.close().unwrap();
+        // 已有的镜像直接挂载
+        let mut fs = FileSystem::get_or_create(image.path()).unwrap();
+        assert_eq!(fs.read_file("keep").unwrap(), b"precious data");
+        fs.close().unwrap();
+        let original = std::fs::read(image.path()).unwrap();
+
+        // 版本过新（校验和清零按旧版镜像处理，只留下版本错误）、校验和不符、被截断：
+        // 错误原样返回，镜像没有被格式化
+        let mut newer = original.clone();
+        newer[FORMAT_VERSION_OFFSET] = FORMAT_VERSION + 1;
+        newer[BOOT_CHECKSUM_OFFSET..BOOT_CHECKSUM_OFFSET + 4].fill(0);
+        let mut corrupted = original.clone();
+        corrupted[LABEL_OFFSET] ^= 0x20;
+        let truncated = original[..original.len() - SECTOR_SIZE].to_vec();
+        for (bytes, kind) in [
+            (newer, io::ErrorKind::Unsupported),
+            (corrupted, io::ErrorKind::InvalidData),
+            (truncated, io::ErrorKind::InvalidData),
+        ] {
+            std::fs::write(image.path(), &bytes).unwrap();
+            let err = FileSystem::get_or_create(image.path()).err().unwrap();
+            assert_eq!(err.kind(), kind);
+            assert_eq!(std::fs::read(image.path()).unwrap(), bytes);
+        }
+    }
+
+    #[test]
+    fn delete_matching_removes_only_matching_files() {
+        let (disk, mut fs) = MockDisk::format(FormatOptions::default());
+        for name in ["a.tmp", "b.tmp", "c.tmp", "keep.txt", "tmp", "a.tmp.bak"] {
+            fs.write_file(name, &pseudo_random(3000, name.len() as u32), None)
+                .unwrap();
+        }
+        assert_eq!(fs.delete_matching("*.tmp").unwrap(), 3);
+        assert_eq!(
+            sorted_names(fs.list_files().unwrap()),
+            ["a.tmp.bak", "keep.txt", "tmp"]
+        );
+        assert_eq!(fs.delete_matching("*.tmp").unwrap(), 0);
+        assert!(fs.check().unwrap().is_empty());
+
+        // 匹配到只读文件时一个都不删
+        fs.set_attributes("tmp", FileAttributes::READ_ONLY).unwrap();
+        let err = fs.delete_matching("*").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+        assert_eq!(fs.list_files().unwrap().len(), 3);
+        fs.set_attributes("tmp", FileAttributes::NONE).unwrap();
+
+        // 中途失败（第二个文件的簇链成环）时错误信息列出已经删除的文件
+        let second = fs.list_files_matching("*").unwrap()[1].clone();
+        let geometry = fs.geometry();
+        drop(fs);
+        disk.poke_fat(&geometry, second.first_cluster, second.first_cluster);
+        let mut fs = disk.mount().unwrap();
+        let matched = fs.list_files_matching("*").unwrap();
+        let err = fs.delete_matching("*").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(
+            err.to_string()
+                .contains(&format!("已删除的文件：[{}]", matched[0].name)),
+            "{}",
+            err
+        );
+        assert!(!fs.exists(&matched[0].name).unwrap());
+        assert!(fs.exists(&matched[1].name).unwrap());
+    }
+
+    #[test]
+    fn image_digest_is_stable_until_content_changes() {
+        let (disk, mut fs) = MockDisk::format(FormatOptions::default());
+        fs.write_file("a", &pseudo_random(5000, 1), None).unwrap();
+        fs.write_file("b", b"small", None).unwrap();
+        fs.close().unwrap();
+
+        let mut fs = disk.mount().unwrap();
+        let digest = fs.image_digest().unwrap();
+        assert_eq!(fs.image_digest().unwrap(), digest);
+        fs.close().unwrap();
+        let mut fs = disk.mount().unwrap();
+        assert_eq!(fs.image_digest().unwrap(), digest);
+
+        // 空闲簇中的残留数据不计入
+        let geometry = fs.geometry();
+        let free = (2..fs.cluster_limit)
+            .find(|&cluster| fs.get_next_cluster(cluster).unwrap() == FAT_FREE)
+            .unwrap();
+        let offset = geometry.cluster_offset(free) as usize;
+        disk.state().data[offset..offset + 16].fill(0xAB);
+        assert_eq!(fs.image_digest().unwrap(), digest);
+
+        // 改动文件内容后摘要改变
+        fs.write_file("b", b"SMALL", None).unwrap();
+        assert_ne!(fs.image_digest().unwrap(), digest);
+    }
+
+    #[test]
+    fn compact_directory_packs_live_entries_without_touching_data() {
+        let (_image, mut fs) = format_temp();
+        let slots = fs.geometry().root_entries();
+        for i in 0..slots {
+            fs.write_file(
+                &format!("f{:02}", i),
+                &pseudo_random(100 + i, i as u32),
+                None,
+            )
+            .unwrap();
+        }
+        assert_eq!(
+            fs.write_file("extra", b"x", None).unwrap_err().kind(),
+            io::ErrorKind::QuotaExceeded
+        );
+        // 隔一个删一个，目录里留下一半墓碑
+        for i in (0..slots).step_by(2) {
+            fs.delete_file(&format!("f{:02}", i)).unwrap();
+        }
+        let before: Vec<FileEntry> = fs.list_files().unwrap();
+        assert_eq!(fs.directory_stats().unwrap().deleted, slots / 2);
+
+        fs.compact_directory().unwrap();
+        let stats = fs.directory_stats().unwrap();
+        assert_eq!(
+            (stats.live, stats.deleted, stats.free),
+            (slots / 2, 0, slots / 2)
+        );
+        // 有效目录项按原顺序排在最前面，簇链不变
+        let packed = fs.list_all_slots().unwrap();
+        for (i, entry) in before.iter().enumerate() {
+            let (slot, moved) = &packed[i];
+            let moved = moved.as_ref().unwrap();
+            assert_eq!((*slot, &moved.name), (i, &entry.name));
+            assert_eq!(moved.first_cluster, entry.first_cluster);
+        }
+        assert!(
+            packed[before.len()..]
+                .iter()
+                .all(|(_, entry)| entry.is_none())
+        );
+        for i in (1..slots).step_by(2) {
+            let name = format!("f{:02}", i);
+            assert_eq!(
+                fs.read_file(&name).unwrap(),
+                pseudo_random(100 + i, i as u32)
+            );
+        }
+        assert!(fs.check().unwrap().is_empty());
+
+        // 整理出的空槽位可以写满，再多一个才报目录已满
+        for i in 0..slots / 2 {
+            fs.write_file(&format!("new{}", i), b"x", None).unwrap();
+        }
+        assert_eq!(
+            fs.write_file("extra", b"x", None).unwrap_err().kind(),
+            io::ErrorKind::QuotaExceeded
+        );
+    }
+
+    // 2000个小文件，各占一个根目录槽位和一个512字节的簇
+    fn many_small_files() -> (FormatOptions, Vec<(String, Vec<u8>)>) {
+        let opts = FormatOptions {
+            cluster_size: 512,
+            max_clusters: 4096,
+            root_dir_sectors: 256,
+            ..FormatOptions::default()
+        };
+        let files = (0..2000)
+            .map(|i| {
+                let line = format!("record {} value {}\n", i, i * 31 % 977);
+                (format!("r{:04}", i), line.repeat(1 + i % 8).into_bytes())
+            })
+            .collect();
+        (opts, files)
+    }
+
+    #[test]
+    fn write_session_writes_are_all_visible() {
+        let (opts, mut files) = many_small_files();
+        files.truncate(300); // 调试构建中逐个校验2000个文件太慢
+        let (disk, mut fs) = MockDisk::format(opts);
+        let mut session = fs.writer_session();
+        let mut entries = Vec::new();
+        for (name, data) in &files {
+            entries.push(session.write(name, data, None).unwrap());
+        }
+        // 同名覆盖和非DEFLATE方法也走同一个会话
+        session
+            .write("r0000", b"overwritten in session", None)
+            .unwrap();
+        session
+            .write(
+                "raw",
+                &pseudo_random(3000, 1),
+                Some(CompressionMethod::None),
+            )
+            .unwrap();
+        drop(session);
+
+        let check_all = |fs: &mut FileSystem| {
+            assert_eq!(fs.read_file("r0000").unwrap(), b"overwritten in session");
+            assert_eq!(fs.read_file("raw").unwrap(), pseudo_random(3000, 1));
+            for ((name, data), entry) in files.iter().zip(&entries).skip(1) {
+                assert_eq!(&fs.read_file(name).unwrap(), data);
+                assert_eq!(
+                    fs.stat(name).unwrap().compressed_size,
+                    entry.compressed_size
+                );
+            }
+            assert_eq!(fs.list_files().unwrap().len(), files.len() + 1);
+            assert!(fs.check().unwrap().is_empty());
+        };
+        check_all(&mut fs);
+        fs.close().unwrap();
+        check_all(&mut disk.mount().unwrap());
+
+        // 压缩结果与逐个 write_file 完全相同
+        let (_, mut plain) = MockDisk::format(FormatOptions::default());
+        for ((name, data), entry) in files.iter().zip(&entries).take(20) {
+            let single = plain.write_file(name, data, None).unwrap();
+            assert_eq!(single.compressed_size, entry.compressed_size);
+        }
+    }
+
+    // cargo test --release -- --ignored --nocapture write_session_is_faster
+    #[test]
+    #[ignore]
+    fn write_session_is_faster_than_write_file() {
+        let (opts, files) = many_small_files();
+        let time = |use_session: bool| {
+            let (_, mut fs) = MockDisk::format(opts.clone());
+            fs.set_write_barriers(false);
+            let start = std::time::Instant::now();
+            if use_session {
+                let mut session = fs.writer_session();
+                for (name, data) in &files {
+                    session.write(name, data, None).unwrap();
+                }
+            } else {
+                for (name, data) in &files {
+                    fs.write_file(name, data, None).unwrap();
+                }
+            }
+            start.elapsed()
+        };
+
+        let per_call = time(false);
+        let session = time(true);
+        println!("write_file: {:?}, writer_session: {:?}", per_call, session);
+        assert!(session < per_call);
+    }
+
+    #[test]
+    fn read_file_chunks_delivers_the_same_bytes_as_read_file() {
+        let (_image, mut fs) = format_temp();
+        let cluster_size = fs.geometry().cluster_size;
+        let data = [pseudo_random(3 * cluster_size, 1), vec![b'z'; 5000]].concat();
+        for method in ALL_METHODS {
+            fs.write_file("f", &data, Some(method)).unwrap();
+            for chunk_size in [1000, cluster_size, 7 * cluster_size] {
+                let mut sizes = Vec::new();
+                let mut sum = 0u64;
+                let mut joined = Vec::new();
+                fs.read_file_chunks("f", chunk_size, |chunk| {
+                    sizes.push(chunk.len());
+                    sum += chunk.iter().map(|&byte| byte as u64).sum::<u64>();
+                    joined.extend_from_slice(chunk);
+                    Ok(())
+                })
+                .unwrap();
+                assert_eq!(joined, fs.read_file("f").unwrap(), "{:?}", method);
+                assert_eq!(sum, data.iter().map(|&byte| byte as u64).sum::<u64>());
+                // 除最后一块外每块恰好 chunk_size 字节
+                let (last, full) = sizes.split_last().unwrap();
+                assert!(full.iter().all(|&size| size == chunk_size));
+                assert!(*last > 0 && *last <= chunk_size);
+            }
+        }
+
+        // 回调出错时立即停止
+        let mut calls = 0;
+        let err = fs
+            .read_file_chunks("f", 100, |_| {
+                calls += 1;
+                Err(io::Error::other("stop"))
+            })
+            .unwrap_err();
+        assert_eq!(err.to_string(), "stop");
+        assert_eq!(calls, 1);
+    }
+
+    #[test]
+    fn repack_all_recompresses_and_keeps_content() {
+        let (_image, mut fs) = format_temp();
+        let files = [
+            ("text", b"tinyfs repack ".repeat(400)),
+            ("noise", pseudo_random(4000, 1)),
+            ("zeros", vec![0u8; 9000]),
+            ("locked", b"read only text ".repeat(100)),
+        ];
+        for (name, data) in &files {
+            fs.write_file(name, data, Some(CompressionMethod::Rle))
+                .unwrap();
+        }
+        fs.set_attributes("locked", FileAttributes::READ_ONLY)
+            .unwrap();
+        let before: u64 = fs
+            .list_files()
+            .unwrap()
+            .iter()
+            .map(|entry| entry.compressed_size as u64)
+            .sum();
+
+        let mut last = None;
+        let report = fs
+            .repack_all_with_progress(CompressionMethod::Deflate, &mut |p| last = Some(p))
+            .unwrap();
+        assert_eq!(last, Some(Progress { done: 4, total: 4 }));
+        assert_eq!(report.before_bytes, before);
+        assert_eq!(
+            report.skipped,
+            [("locked".to_string(), io::ErrorKind::PermissionDenied)]
+        );
+        assert_eq!(report.files.len(), 3);
+        let after: u64 = fs
+            .list_files()
+            .unwrap()
+            .iter()
+            .map(|entry| entry.compressed_size as u64)
+            .sum();
+        assert_eq!(report.after_bytes, after);
+        assert!(report.after_bytes < report.before_bytes);
+
+        for (name, data) in &files {
+            assert_eq!(&fs.read_file(name).unwrap(), data);
+            let expected = if *name == "locked" {
+                CompressionMethod::Rle
+            } else {
+                CompressionMethod::Deflate
+            };
+            assert_eq!(fs.compression_method(name).unwrap(), expected);
+        }
+        for file in &report.files {
+            assert_eq!(file.old_method, CompressionMethod::Rle as u8);
+            assert_eq!(fs.stat(&file.name).unwrap().compressed_size, file.new_size);
+        }
+        assert!(fs.check().unwrap().is_empty());
+
+        // 已经是目标方法的文件不再处理
+        let again = fs.repack_all(CompressionMethod::Deflate).unwrap();
+        assert!(again.files.is_empty());
+    }
+
+    #[test]
+    fn image_at_base_offset_leaves_the_header_alone() {
+        let image = TempImage::new();
+        let header = pseudo_random(4096, 1);
+        std::fs::write(image.path(), &header).unwrap();
+        let opts = FormatOptions {
+            base_offset: 4096,
+            ..FormatOptions::default()
+        };
+        let mut fs = FileSystem::format_with(image.path(), opts).unwrap();
+        let data = pseudo_random(10_000, 2);
+        fs.write_file("inner", &data, None).unwrap();
+        fs.write_file("small", b"hi", None).unwrap();
+        fs.close().unwrap();
+
+        // 开头4KB的内容原样保留，镜像从4096字节处开始
+        let bytes = std::fs::read(image.path()).unwrap();
+        assert_eq!(&bytes[..4096], &header[..]);
+        assert_eq!(&bytes[4096 + 3..4096 + 11], b"MINIFAT ");
+
+        let mut fs = FileSystem::mount_at(image.path(), 4096).unwrap();
+        assert_eq!(fs.read_file("inner").unwrap(), data);
+        assert_eq!(fs.read_file("small").unwrap(), b"hi");
+        assert!(fs.check().unwrap().is_empty());
+        fs.close().unwrap();
+        // 从文件开头挂载找不到启动扇区
+        assert!(FileSystem::mount(image.path()).is_err());
+    }
+
+    #[test]
+    fn entry_parsing_survives_random_bytes() {
+        for seed in 0..20_000u32 {
+            let mut bytes = pseudo_random(DIR_ENTRY_SIZE, seed);
+            // 一半的样本用合法的文件名，让解析走到后面的字段
+            if seed % 2 == 0 {
+                let name_len = seed as usize % MAX_FILENAME_LENGTH + 1;
+                for (i, byte) in bytes[..MAX_FILENAME_LENGTH].iter_mut().enumerate() {
+                    *byte = if i < name_len {
+                        b'a' + (i % 26) as u8
+                    } else {
+                        0
+                    };
+                }
+                if seed % 4 == 0 {
+                    bytes[40..44].copy_from_slice(&INLINE_CLUSTER.to_le_bytes());
+                }
+            }
+
+            let Some(entry) = FileEntry::from_bytes(&bytes) else {
+                continue;
+            };
+            assert!(entry.name.len() <= MAX_FILENAME_LENGTH);
+            assert!(entry.inline_data.len() <= MAX_INLINE_SIZE);
+            assert!(entry.comment.len() <= MAX_COMMENT_SIZE);
+            // 解析结果重新编码后再解析，得到相同的目录项
+            let again = FileEntry::from_bytes(&entry.to_bytes()).unwrap();
+            assert_eq!(format!("{:?}", again), format!("{:?}", entry));
+        }
+        assert!(FileEntry::from_bytes(&[0u8; DIR_ENTRY_SIZE - 1]).is_none());
+    }
+
+    #[test]
+    fn decoders_reject_random_input_without_panicking() {
+        for seed in 0..2_000u32 {
+            let garbage = pseudo_random(1 + seed as usize % 300, seed);
+            for method in 1..=5u8 {
+                let mut out = Vec::new();
+                let _ = decompress_into(method, &garbage, &mut out);
+
+                // 按目录项解码时，解出的大小与目录项不符一律是错误
+                let size = seed % 1000 + 1;
+                let entry = FileEntry::new("f", size, garbage.len() as u32, 2, method);
+                if let Ok(data) = FileSystem::decode_file(&entry, garbage.clone()) {
+                    assert_eq!(data.len(), size as usize);
+                }
+            }
+        }
+
+        // 截断的合法压缩数据一律报错
+        let data = b"truncated compressed stream ".repeat(50);
+        for method in [
+            CompressionMethod::Deflate,
+            CompressionMethod::Rle2,
+            CompressionMethod::Brotli,
+            CompressionMethod::Zstd,
+        ] {
+            let compressed = compress_with(&data, method).unwrap();
+            let entry = FileEntry::new("f", data.len() as u32, 0, 2, method as u8);
+            let cut = compressed[..compressed.len() / 2].to_vec();
+            assert!(
+                FileSystem::decode_file(&entry, cut).is_err(),
+                "{:?}",
+                method
+            );
+            assert_eq!(FileSystem::decode_file(&entry, compressed).unwrap(), data);
+        }
+    }
+
+    #[test]
+    fn write_file_if_changed_skips_identical_content() {
+        let (disk, mut fs) = MockDisk::format(FormatOptions::default());
+        let data = pseudo_random(6000, 1);
+        let deflate = CompressionMethod::Deflate;
+        assert!(fs.write_file_if_changed("f", &data, deflate).unwrap());
+        let digest = fs.image_digest().unwrap();
+
+        // 内容相同：不写入任何字节，摘要不变；压缩方法不同也不重写
+        disk.state().writes.clear();
+        assert!(!fs.write_file_if_changed("f", &data, deflate).unwrap());
+        assert!(
+            !fs.write_file_if_changed("f", &data, CompressionMethod::None)
+                .unwrap()
+        );
+        assert!(disk.state().writes.is_empty());
+        assert_eq!(fs.image_digest().unwrap(), digest);
+
+        // 大小相同只差最后一个字节，以及大小不同，都会重写
+        let mut changed = data.clone();
+        *changed.last_mut().unwrap() ^= 1;
+        assert!(fs.write_file_if_changed("f", &changed, deflate).unwrap());
+        assert_eq!(fs.read_file("f").unwrap(), changed);
+        assert!(
+            fs.write_file_if_changed("f", &data[..100], deflate)
+                .unwrap()
+        );
+        assert_eq!(fs.read_file("f").unwrap(), &data[..100]);
+        assert_ne!(fs.image_digest().unwrap(), digest);
+    }
+
+    #[test]
+    fn links_share_a_chain_until_the_last_name_is_deleted() {
+        let (_image, mut fs) = format_temp();
+        let empty = fs.free_cluster_count().unwrap();
+        let data = pseudo_random(5000, 1);
+        fs.write_file("a", &data, None).unwrap();
+        let used = fs.free_cluster_count().unwrap();
+        fs.link("a", "b").unwrap();
+        // 链接不分配新簇，两个名字读到同样的内容
+        assert_eq!(fs.free_cluster_count().unwrap(), used);
+        assert_eq!(fs.read_file("b").unwrap(), data);
+        assert_eq!(fs.stat("a").unwrap().links, 2);
+        assert_eq!(
+            fs.stat("b").unwrap().first_cluster,
+            fs.stat("a").unwrap().first_cluster
+        );
+        assert!(fs.check().unwrap().is_empty());
+        assert_eq!(
+            fs.link("a", "b").unwrap_err().kind(),
+            io::ErrorKind::AlreadyExists
+        );
+
+        fs.delete_file("a").unwrap();
+        assert_eq!(fs.read_file("b").unwrap(), data);
+        assert_eq!(fs.stat("b").unwrap().links, 1);
+        assert_eq!(fs.free_cluster_count().unwrap(), used);
+        assert!(fs.check().unwrap().is_empty());
+
+        fs.delete_file("b").unwrap();
+        assert_eq!(fs.free_cluster_count().unwrap(), empty);
+
+        // 覆盖其中一个名字只影响这个名字
+        fs.write_file("c", &data, None).unwrap();
+        fs.link("c", "d").unwrap();
+        fs.write_file("c", b"new content for c", None).unwrap();
+        assert_eq!(fs.read_file("d").unwrap(), data);
+        assert_eq!(fs.stat("d").unwrap().links, 1);
+        assert!(fs.check().unwrap().is_empty());
+    }
+
+    #[test]
+    fn truncate_file_shrinks_ignores_growth_and_empties() {
+        let (_image, mut fs) = format_temp();
+        let cluster_size = fs.geometry().cluster_size;
+        let data = pseudo_random(5 * cluster_size, 1);
+        fs.write_file("f", &data, Some(CompressionMethod::Zstd))
+            .unwrap();
+        let free = fs.free_cluster_count().unwrap();
+
+        fs.truncate_file("f", cluster_size + 10).unwrap();
+        assert_eq!(fs.read_file("f").unwrap(), &data[..cluster_size + 10]);
+        assert_eq!(fs.stat("f").unwrap().size as usize, cluster_size + 10);
+        assert_eq!(fs.compression_method("f").unwrap(), CompressionMethod::Zstd);
+        assert!(fs.free_cluster_count().unwrap() > free);
+
+        // 不小于当前大小时什么也不做，不补零
+        let entry = fs.stat("f").unwrap();
+        for new_len in [cluster_size + 10, 100 * cluster_size] {
+            fs.truncate_file("f", new_len).unwrap();
+            let after = fs.stat("f").unwrap();
+            assert_eq!(after.size, entry.size);
+            assert_eq!(after.first_cluster, entry.first_cluster);
+        }
+
+        fs.truncate_file("f", 0).unwrap();
+        assert_eq!(fs.read_file("f").unwrap(), b"");
+        assert_eq!(fs.stat("f").unwrap().size, 0);
+        assert!(fs.check().unwrap().is_empty());
+        assert_eq!(
+            fs.truncate_file("missing", 0).unwrap_err().kind(),
+            io::ErrorKind::NotFound
+        );
+    }
+
+    #[test]
+    fn last_cluster_slack_is_zeroed() {
+        let (disk, mut fs) = MockDisk::format(FormatOptions::default());
+        let cluster_size = fs.geometry().cluster_size;
+        // 先让数据区开头的簇写满0xAB再释放
+        fs.write_file(
+            "junk",
+            &vec![0xAB; 2 * cluster_size],
+            Some(CompressionMethod::None),
+        )
+        .unwrap();
+        let junk_cluster = fs.cluster_chain("junk").unwrap()[0];
+        fs.delete_file("junk").unwrap();
+
+        // 压缩后恰好5字节：100个'a'的游程加上两个字面字节
+        let data = [vec![b'a'; 100], b"bc".to_vec()].concat();
+        let entry = fs
+            .write_file("five", &data, Some(CompressionMethod::Rle2))
+            .unwrap();
+        assert_eq!(entry.compressed_size, 5);
+        assert_eq!(entry.first_cluster, junk_cluster);
+        assert!(fs.debug_assert_clean_slack("five").unwrap());
+        let offset = fs.geometry().cluster_offset(junk_cluster) as usize;
+        let on_disk = disk.state().data[offset + 5..offset + cluster_size].to_vec();
+        assert_eq!(on_disk.len(), cluster_size - 5);
+        assert!(on_disk.iter().all(|&byte| byte == 0));
+
+        // 原地覆盖变短时同样清零
+        fs.set_overwrite_in_place(true);
+        fs.write_file(
+            "junk",
+            &vec![0xAB; 2 * cluster_size],
+            Some(CompressionMethod::None),
+        )
+        .unwrap();
+        fs.write_file("junk", &[0xCD; 100], Some(CompressionMethod::None))
+            .unwrap();
+        assert!(fs.debug_assert_clean_slack("junk").unwrap());
+
+        // 残留数据确实会被发现
+        let last = fs
+            .geometry()
+            .cluster_offset(fs.cluster_chain("junk").unwrap()[0]) as usize;
+        disk.state().data[last + cluster_size - 1] = 0xEE;
+        assert!(!fs.debug_assert_clean_slack("junk").unwrap());
+    }
+
+    #[test]
+    fn list_files_sorted_orders_by_each_key() {
+        let (_image, mut fs) = format_temp();
+        let none = Some(CompressionMethod::None);
+        // 目录顺序 c, a, b, d；修改时间 a < b < c < d
+        fs.set_time_source(|| 300);
+        fs.write_file("c", &pseudo_random(4000, 1), none).unwrap();
+        fs.set_time_source(|| 100);
+        fs.write_file("a", &[b'x'; 9000], Some(CompressionMethod::Deflate))
+            .unwrap();
+        fs.set_time_source(|| 200);
+        fs.write_file("b", &pseudo_random(3000, 2), none).unwrap();
+        fs.set_time_source(|| 400);
+        fs.write_file("d", b"", none).unwrap();
+        fs.write_file("hidden", b"never listed", none).unwrap();
+        fs.set_attributes("hidden", FileAttributes::HIDDEN).unwrap();
+
+        let mut sorted = |by, descending| -> Vec<String> {
+            fs.list_files_sorted(by, descending)
+                .unwrap()
+                .into_iter()
+                .map(|entry| entry.name)
+                .collect()
+        };
+        assert_eq!(sorted(SortKey::Name, false), ["a", "b", "c", "d"]);
+        assert_eq!(sorted(SortKey::Name, true), ["d", "c", "b", "a"]);
+        assert_eq!(sorted(SortKey::Size, false), ["d", "b", "c", "a"]);
+        assert_eq!(sorted(SortKey::CompressedSize, false), ["d", "a", "b", "c"]);
+        assert_eq!(sorted(SortKey::CompressedSize, true), ["c", "b", "a", "d"]);
+        // b和c都不压缩，比例相同时保持目录顺序（升序和降序都是c在前）
+        assert_eq!(sorted(SortKey::Ratio, false), ["d", "a", "c", "b"]);
+        assert_eq!(sorted(SortKey::Ratio, true), ["c", "b", "a", "d"]);
+        assert_eq!(sorted(SortKey::Modified, false), ["a", "b", "c", "d"]);
+        assert_eq!(sorted(SortKey::Modified, true), ["d", "c", "b", "a"]);
+    }
+
+    #[test]
+    fn payloads_around_cluster_boundaries_round_trip() {
+        for cluster_size in [512, 2048] {
+            let opts = FormatOptions {
+                cluster_size,
+                ..FormatOptions::default()
+            };
+            let (disk, mut fs) = MockDisk::format(opts);
+            let mut sizes = Vec::new();
+            for k in [1, 2, 3, 8] {
+                sizes.extend([k * cluster_size - 1, k * cluster_size, k * cluster_size + 1]);
+            }
+
+            let mut written = Vec::new();
+            for method in ALL_METHODS {
+                for &size in &sizes {
+                    // 一半不可压缩、一半可压缩，让压缩后的大小也落在不同位置
+                    let mut data = pseudo_random(size, size as u32);
+                    data[size / 2..].fill(b'z');
+                    let name = format!("{}-{}", method as u8, size);
+                    let entry = fs.write_file(&name, &data, Some(method)).unwrap();
+
+                    let chain = fs.chain_length(entry.first_cluster).unwrap();
+                    let expected = (entry.compressed_size as usize)
+                        .div_ceil(cluster_size)
+                        .max(1);
+                    assert_eq!(chain, expected, "{}", name);
+                    if method == CompressionMethod::None {
+                        assert_eq!(chain, size.div_ceil(cluster_size));
+                    }
+                    assert_eq!(fs.read_file(&name).unwrap(), data, "{}", name);
+                    written.push((name, data));
+                }
+                fs.close().unwrap();
+                fs = disk.mount().unwrap();
+                for (name, data) in written.drain(..) {
+                    assert_eq!(fs.read_file(&name).unwrap(), data, "{}", name);
+                    fs.delete_file(&name).unwrap();
+                }
+            }
+            assert!(fs.check().unwrap().is_empty());
+        }
+    }
+
+    #[test]
+    fn metadata_export_import_round_trips() {
+        let (_image, mut fs) = format_temp();
+        let data = pseudo_random(7000, 1);
+        fs.write_file("keep", &data, None).unwrap();
+        fs.write_file("lost", b"removed after the backup", None)
+            .unwrap();
+        let blob = fs.export_metadata().unwrap();
+        assert_eq!(blob.len(), fs.geometry().data_start_sector() * SECTOR_SIZE);
+
+        // 只删除目录项和FAT链接，数据区不变，恢复元数据后文件回来了
+        fs.delete_file("lost").unwrap();
+        fs.set_label("CHANGED").unwrap();
+        fs.import_metadata(&blob).unwrap();
+        assert_eq!(sorted_names(fs.list_files().unwrap()), ["keep", "lost"]);
+        assert_eq!(fs.read_file("lost").unwrap(), b"removed after the backup");
+        assert_eq!(fs.read_file("keep").unwrap(), data);
+        assert_eq!(fs.label().unwrap(), "");
+        assert!(fs.check().unwrap().is_empty());
+        assert_eq!(
+            fs.export_metadata().unwrap()[SECTOR_SIZE..],
+            blob[SECTOR_SIZE..]
+        );
+
+        // 长度或布局不同的元数据被拒绝，镜像不变
+        let err = fs.import_metadata(&blob[..blob.len() - 1]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        let other = TempImage::new();
+        let opts = FormatOptions {
+            cluster_size: 4096,
+            ..FormatOptions::default()
+        };
+        let mut other_fs = FileSystem::format_with(other.path(), opts).unwrap();
+        let other_blob = other_fs.export_metadata().unwrap();
+        let err = fs.import_metadata(&other_blob).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(fs.read_file("keep").unwrap(), data);
+    }
+
+    #[test]
+    fn cluster_owner_maps_clusters_back_to_files() {
+        let (disk, mut fs) = MockDisk::format(FormatOptions::default());
+        let cluster_size = fs.geometry().cluster_size;
+        fs.write_file("a", &pseudo_random(3 * cluster_size, 1), None)
+            .unwrap();
+        fs.write_file("b", &pseudo_random(2 * cluster_size, 2), None)
+            .unwrap();
+        fs.link("a", "a2").unwrap();
+        for name in ["a", "b"] {
+            for cluster in fs.cluster_chain(name).unwrap() {
+                assert_eq!(fs.cluster_owner(cluster).unwrap().as_deref(), Some(name));
+            }
+        }
+
+        // 空闲簇和无主簇都没有所有者
+        let free = fs.cluster_limit - 1;
+        assert_eq!(fs.cluster_owner(free).unwrap(), None);
+        let geometry = fs.geometry();
+        drop(fs);
+        disk.poke_fat(&geometry, free, FAT_EOC);
+        let mut fs = disk.mount().unwrap();
+        assert_eq!(fs.cluster_owner(free).unwrap(), None);
+
+        // 簇号越界时报错
+        for cluster in [0, 1, fs.cluster_limit] {
+            assert_eq!(
+                fs.cluster_owner(cluster).unwrap_err().kind(),
+                io::ErrorKind::InvalidData
+            );
+        }
+    }
+
+    #[test]
+    fn larger_root_directory_holds_over_a_hundred_files() {
+        let image = TempImage::new();
+        let opts = FormatOptions {
+            root_dir_sectors: 16,
+            ..FormatOptions::default()
+        };
+        let mut fs = FileSystem::format_with(image.path(), opts).unwrap();
+        assert_eq!(fs.geometry().root_entries(), 128);
+        let content = |i: usize| format!("file number {} ", i).repeat(i % 5 + 1).into_bytes();
+        for i in 0..120 {
+            fs.write_file(&format!("file{:03}", i), &content(i), None)
+                .unwrap();
+        }
+        fs.close().unwrap();
+
+        // 重新挂载后从启动扇区读出根目录大小，后面几个扇区中的文件都能找到
+        let mut fs = FileSystem::mount(image.path()).unwrap();
+        assert_eq!(fs.geometry().root_dir_sectors, 16);
+        assert_eq!(fs.list_files().unwrap().len(), 120);
+        for i in 0..120 {
+            assert_eq!(fs.read_file(&format!("file{:03}", i)).unwrap(), content(i));
+        }
+
+        // 删除前面的文件后，新文件复用这些槽位；后面的文件不受影响
+        for i in 0..60 {
+            fs.delete_file(&format!("file{:03}", i)).unwrap();
+        }
+        for i in 0..68 {
+            fs.write_file(&format!("new{:03}", i), b"refill", None)
+                .unwrap();
+        }
+        assert_eq!(fs.directory_stats().unwrap().live, 128);
+        assert_eq!(
+            fs.write_file("overflow", b"x", None).unwrap_err().kind(),
+            io::ErrorKind::QuotaExceeded
+        );
+        assert!(!fs.exists("file000").unwrap());
+        assert_eq!(fs.read_file("file119").unwrap(), content(119));
+        assert_eq!(fs.list_files_matching("new*").unwrap().len(), 68);
+        assert!(fs.check().unwrap().is_empty());
+    }
+
+    #[test]
+    fn write_file_verified_rejects_a_flipped_bit() {
+        let (disk, mut fs) = MockDisk::format(FormatOptions::default());
+        let old = pseudo_random(5000, 1);
+        fs.write_file_verified("f", &old, CompressionMethod::Deflate)
+            .unwrap();
+        let free = fs.free_cluster_count().unwrap();
+        let data_start = (fs.geometry().data_start_sector() * SECTOR_SIZE) as u64;
+
+        // 数据区的写入落盘时翻转一位，模拟坏介质
+        disk.state().flip_bits = Some(data_start..u64::MAX);
+        for method in [CompressionMethod::None, CompressionMethod::Deflate] {
+            let err = fs
+                .write_file_verified("f", &pseudo_random(5000, 2), method)
+                .unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData, "{:?}", method);
+            let err = fs
+                .write_file_verified("new", &pseudo_random(5000, 3), method)
+                .unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData, "{:?}", method);
+        }
+
+        // 目录项没有提交：原文件不变，新文件不存在，新簇链已经释放
+        assert_eq!(fs.read_file("f").unwrap(), old);
+        assert!(!fs.exists("new").unwrap());
+        assert_eq!(fs.free_cluster_count().unwrap(), free);
+        drop(fs);
+        disk.state().flip_bits = None;
+        let mut fs = disk.mount().unwrap();
+        assert_eq!(fs.read_file("f").unwrap(), old);
+        assert!(!fs.exists("new").unwrap());
+        assert!(fs.check().unwrap().is_empty());
+        assert_eq!(fs.repair_lost_clusters().unwrap(), 0);
+    }
+
+    #[test]
+    fn read_file_at_matches_reads_by_name() {
+        let (_image, mut fs) = format_temp();
+        fs.write_file("tiny", b"hi", Some(CompressionMethod::None))
+            .unwrap();
+        fs.write_file(
+            "big",
+            &pseudo_random(9000, 1),
+            Some(CompressionMethod::Deflate),
+        )
+        .unwrap();
+        fs.write_file("gone", b"deleted", Some(CompressionMethod::None))
+            .unwrap();
+        fs.delete_file("gone").unwrap();
+
+        let mut live = 0;
+        for (slot, entry) in fs.list_all_slots().unwrap() {
+            match entry {
+                Some(entry) if !entry.is_deleted => {
+                    live += 1;
+                    assert_eq!(
+                        fs.read_file_at(slot).unwrap(),
+                        fs.read_file(&entry.name).unwrap()
+                    );
+                }
+                _ => {
+                    let err = fs.read_file_at(slot).unwrap_err();
+                    assert_eq!(err.kind(), io::ErrorKind::NotFound, "槽位 {}", slot);
+                }
+            }
+        }
+        assert_eq!(live, 2);
+
+        let total = fs.geometry().root_entries();
+        let err = fs.read_file_at(total).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn clone_image_copies_every_file() {
+        let (_image, mut fs) = format_temp();
+        let files: Vec<(String, Vec<u8>)> = (0..6)
+            .map(|i| (format!("file{}", i), pseudo_random(700 * i + 5, i as u32)))
+            .collect();
+        for ((name, data), method) in files.iter().zip(ALL_METHODS) {
+            fs.write_file(name, data, Some(method)).unwrap();
+        }
+
+        let clone_path = TempImage::new();
+        let mut clone = fs.clone_image(clone_path.path()).unwrap();
+        assert_eq!(
+            sorted_names(clone.list_files().unwrap()),
+            sorted_names(fs.list_files().unwrap())
+        );
+        for (name, data) in &files {
+            assert_eq!(&clone.read_file(name).unwrap(), data);
+        }
+        assert!(clone.check().unwrap().is_empty());
+
+        // 副本是独立的镜像，修改互不影响
+        clone.delete_file("file0").unwrap();
+        assert_eq!(fs.read_file("file0").unwrap(), files[0].1);
+
+        // 目标已存在时不覆盖
+        let err = fs.clone_image(clone_path.path()).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::AlreadyExists);
+    }
+
+    #[test]
+    fn primary_fat_is_restored_from_the_backup() {
+        let opts = FormatOptions {
+            backup_fat: true,
+            ..FormatOptions::default()
+        };
+        let (disk, mut fs) = MockDisk::format(opts);
+        let files: Vec<(String, Vec<u8>)> = (0..4)
+            .map(|i| (format!("f{}", i), pseudo_random(3000 * (i + 1), i as u32)))
+            .collect();
+        for (name, data) in &files {
+            fs.write_file(name, data, Some(CompressionMethod::None))
+                .unwrap();
+        }
+        let geometry = fs.geometry();
+        assert!(fs.check().unwrap().is_empty());
+        assert!(!fs.repair_fat_from_backup().unwrap());
+        drop(fs);
+
+        // 把主FAT的第一个扇区写成垃圾，所有文件的簇链都断了
+        let primary = geometry.fat_start_sector(0) * SECTOR_SIZE;
+        disk.state().data[primary..primary + SECTOR_SIZE].fill(0xA5);
+        let mut fs = disk.mount().unwrap();
+        assert!(fs.check().unwrap().contains(&CheckProblem::FatChecksum));
+        assert!(fs.read_file("f0").is_err());
+
+        assert!(fs.repair_fat_from_backup().unwrap());
+        assert!(fs.check().unwrap().is_empty());
+        for (name, data) in &files {
+            assert_eq!(&fs.read_file(name).unwrap(), data);
+        }
+        drop(fs);
+        let mut fs = disk.mount().unwrap();
+        assert_eq!(fs.read_file("f3").unwrap(), files[3].1);
+
+        // 两份FAT都损坏时无法恢复，也不做任何修改
+        drop(fs);
+        let backup = geometry.fat_start_sector(1) * SECTOR_SIZE;
+        for start in [primary, backup] {
+            disk.state().data[start..start + SECTOR_SIZE].fill(0x5A);
+        }
+        let before = disk.state().data.clone();
+        let mut fs = disk.mount().unwrap();
+        let err = fs.repair_fat_from_backup().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        drop(fs);
+        assert!(
+            disk.state().data[primary..primary + SECTOR_SIZE]
+                == before[primary..primary + SECTOR_SIZE]
+        );
+
+        // 没有备份FAT的镜像
+        let (_disk, mut fs) = MockDisk::format(FormatOptions::default());
+        let err = fs.repair_fat_from_backup().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::Unsupported);
+    }
+
+    #[test]
+    fn dropping_without_close_leaves_the_image_dirty() {
+        let image = TempImage::new();
+        let mut fs = FileSystem::format(image.path()).unwrap();
+        fs.write_file("a", b"data", None).unwrap();
+        drop(fs);
+
+        let mut fs = FileSystem::mount(image.path()).unwrap();
+        assert!(fs.is_dirty());
+        assert_eq!(fs.read_file("a").unwrap(), b"data");
+        fs.close().unwrap();
+
+        // 只有 close 会清除脏标记
+        let fs = FileSystem::mount(image.path()).unwrap();
+        assert!(!fs.is_dirty());
+        fs.close().unwrap();
+    }
+
+    #[test]
+    fn list_modified_since_selects_newer_files() {
+        let (_image, mut fs) = format_temp();
+        fs.set_time_source(|| 0);
+        fs.write_file("legacy", b"unknown mtime", None).unwrap();
+        fs.set_time_source(|| 1_000);
+        fs.write_file("old", b"old", None).unwrap();
+        fs.write_file("rewritten", b"v1", None).unwrap();
+        fs.set_time_source(|| 2_000);
+        fs.write_file("new", b"new", None).unwrap();
+        fs.set_attributes("new", FileAttributes::HIDDEN).unwrap();
+        fs.set_time_source(|| 3_000);
+        fs.write_file("rewritten", b"v2", None).unwrap();
+        fs.write_file("removed", b"x", None).unwrap();
+        fs.delete_file("removed").unwrap();
+
+        let since = |fs: &mut FileSystem, secs| sorted_names(fs.list_modified_since(secs).unwrap());
+        // 修改时间未知的文件总是包括在内；时间等于参数的文件不算更新
+        assert_eq!(since(&mut fs, 0), ["legacy", "new", "old", "rewritten"]);
+        assert_eq!(since(&mut fs, 999), ["legacy", "new", "old", "rewritten"]);
+        assert_eq!(since(&mut fs, 1_000), ["legacy", "new", "rewritten"]);
+        assert_eq!(since(&mut fs, 2_000), ["legacy", "rewritten"]);
+        assert_eq!(since(&mut fs, 3_000), ["legacy"]);
+    }
+
+    #[test]
+    fn smart_stores_random_data_raw_and_deflates_text() {
+        let (_image, mut fs) = format_temp();
+        let random = pseudo_random(20_000, 5);
+        let text = "the quick brown fox jumps over the lazy dog\n"
+            .repeat(400)
+            .into_bytes();
+
+        let entry = fs
+            .write_file("random", &random, Some(CompressionMethod::Smart))
+            .unwrap();
+        assert_eq!(entry.compression_method, u8::from(CompressionMethod::None));
+        assert_eq!(entry.compressed_size as usize, random.len());
+        let entry = fs
+            .write_file("text", &text, Some(CompressionMethod::Smart))
+            .unwrap();
+        assert_eq!(
+            entry.compression_method,
+            u8::from(CompressionMethod::Deflate)
+        );
+        assert!((entry.compressed_size as usize) < text.len() / 10);
+
+        // 目录项记录实际选择的方法，不会出现Smart
+        assert_eq!(
+            fs.compression_method("random").unwrap(),
+            CompressionMethod::None
+        );
+        assert_eq!(
+            fs.compression_method("text").unwrap(),
+            CompressionMethod::Deflate
+        );
+        assert_eq!(fs.read_file("random").unwrap(), random);
+        assert_eq!(fs.read_file("text").unwrap(), text);
+
+        // 只采样开头：开头是文本、后面是随机数据时仍然用DEFLATE
+        let mixed = [&text[..SMART_SAMPLE_SIZE], &random[..]].concat();
+        let entry = fs
+            .write_file("mixed", &mixed, Some(CompressionMethod::Smart))
+            .unwrap();
+        assert_eq!(
+            entry.compression_method,
+            u8::from(CompressionMethod::Deflate)
+        );
+        assert_eq!(fs.read_file("mixed").unwrap(), mixed);
+    }
+
+    #[test]
+    fn largest_writable_and_can_store_agree_with_writes_near_full() {
+        let opts = FormatOptions {
+            cluster_size: 512,
+            max_clusters: 128,
+            root_dir_sectors: 1,
+            ..FormatOptions::default()
+        };
+        let (_disk, mut fs) = MockDisk::format(opts);
+        let none = CompressionMethod::None;
+
+        // 填到只剩两个空闲簇
+        let free = fs.free_cluster_count().unwrap();
+        fs.write_file("fill", &pseudo_random((free - 2) * 512, 1), Some(none))
+            .unwrap();
+        assert_eq!(fs.free_cluster_count().unwrap(), 2);
+        assert_eq!(fs.largest_writable().unwrap(), 1024);
+
+        // 恰好放得下和多一个字节
+        assert!(fs.can_store(&pseudo_random(1024, 2), none).unwrap());
+        assert!(!fs.can_store(&pseudo_random(1025, 2), none).unwrap());
+        let err = fs
+            .write_file("over", &pseudo_random(1025, 2), Some(none))
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::StorageFull);
+
+        // 压缩后放得下的大文件
+        let text = vec![b'a'; 50_000];
+        assert!(!fs.can_store(&text, none).unwrap());
+        assert!(fs.can_store(&text, CompressionMethod::Deflate).unwrap());
+        fs.write_file("text", &text, Some(CompressionMethod::Deflate))
+            .unwrap();
+
+        fs.write_file("exact", &pseudo_random(512, 3), Some(none))
+            .unwrap();
+        assert_eq!(fs.free_cluster_count().unwrap(), 0);
+        assert_eq!(fs.largest_writable().unwrap(), 0);
+        assert!(!fs.can_store(&pseudo_random(13, 4), none).unwrap());
+        // 内联的小文件不占簇
+        assert!(fs.can_store(b"tiny", none).unwrap());
+        fs.write_file("tiny", b"tiny", Some(none)).unwrap();
+
+        // 根目录满了时什么都写不下
+        for i in 0..4 {
+            fs.write_file(&format!("t{}", i), b"x", Some(none)).unwrap();
+        }
+        assert_eq!(fs.directory_stats().unwrap().slots_available(), 0);
+        assert!(!fs.can_store(b"x", none).unwrap());
+        assert_eq!(fs.largest_writable().unwrap(), 0);
+    }
+
+    #[test]
+    fn tiny_files_keep_their_method_around_the_inline_limit() {
+        let (_image, mut fs) = format_temp();
+        let concrete = ALL_METHODS
+            .into_iter()
+            .filter(|&method| method != CompressionMethod::Smart);
+
+        for method in concrete.clone() {
+            for len in [11, 12, 13] {
+                let name = format!("{}-{}", u8::from(method), len);
+                let data = pseudo_random(len, len as u32);
+                let entry = fs.write_file(&name, &data, Some(method)).unwrap();
+                // 12字节以内内联存放，超过一个字节就要分配簇；两种情况都记录调用方指定的方法
+                assert_eq!(entry.is_inline(), len <= MAX_INLINE_SIZE, "{}", name);
+                assert_eq!(entry.compression_method, u8::from(method), "{}", name);
+                assert_eq!(fs.compression_method(&name).unwrap(), method, "{}", name);
+                assert_eq!(fs.read_file(&name).unwrap(), data, "{}", name);
+                let footprint = fs.file_footprint(&name).unwrap();
+                let clusters = usize::from(len > MAX_INLINE_SIZE);
+                assert_eq!(footprint.clusters, clusters, "{}", name);
+                assert_eq!(
+                    footprint.physical_bytes,
+                    (clusters * fs.geometry.cluster_size) as u64,
+                    "{}",
+                    name
+                );
+                assert_eq!(
+                    fs.read_file_prefix(&name, 5).unwrap(),
+                    data[..5],
+                    "{}",
+                    name
+                );
+            }
+        }
+
+        // 其他写入路径同样保留方法
+        let tiny = b"hello, tiny!";
+        let entry = fs.write_file_zstd("zstd", tiny, 19).unwrap();
+        assert!(entry.is_inline());
+        assert_eq!(
+            fs.compression_method("zstd").unwrap(),
+            CompressionMethod::Zstd
+        );
+        let entry = fs
+            .write_file_vectored(
+                "vectored",
+                &[b"hello, ", b"tiny!"],
+                Some(CompressionMethod::Brotli),
+            )
+            .unwrap();
+        assert!(entry.is_inline());
+        assert_eq!(
+            fs.compression_method("vectored").unwrap(),
+            CompressionMethod::Brotli
+        );
+        let entry = fs
+            .writer_session()
+            .write("session", tiny, Some(CompressionMethod::Deflate))
+            .unwrap();
+        assert!(entry.is_inline());
+        assert_eq!(
+            fs.compression_method("session").unwrap(),
+            CompressionMethod::Deflate
+        );
+        fs.write_file_verified("verified", tiny, CompressionMethod::Rle)
+            .unwrap();
+        assert_eq!(
+            fs.compression_method("verified").unwrap(),
+            CompressionMethod::Rle
+        );
+        for name in ["zstd", "vectored", "session", "verified"] {
+            assert_eq!(fs.read_file(name).unwrap(), tiny, "{}", name);
+        }
+
+        // 搬到另一个镜像后方法和内容不变
+        let (_other_image, mut other) = format_temp();
+        FileSystem::transfer(&mut fs, &mut other, "verified").unwrap();
+        assert_eq!(
+            other.compression_method("verified").unwrap(),
+            CompressionMethod::Rle
+        );
+        assert_eq!(other.read_file("verified").unwrap(), tiny);
+
+        // 重新压缩同样处理内联文件：方法改变，仍然内联
+        let report = fs.repack_all(CompressionMethod::Zstd).unwrap();
+        assert!(report.files.iter().any(|file| file.name == "2-11"));
+        let entry = fs.stat("2-11").unwrap();
+        assert!(entry.is_inline());
+        assert_eq!(
+            fs.compression_method("2-11").unwrap(),
+            CompressionMethod::Zstd
+        );
+        assert_eq!(fs.read_file("2-11").unwrap(), pseudo_random(11, 11));
+
+        assert!(fs.check().unwrap().is_empty());
+        let path = fs.path().to_string();
+        fs.close().unwrap();
+        let mut fs = FileSystem::mount(&path).unwrap();
+        for method in concrete {
+            for len in [11, 12, 13] {
+                let name = format!("{}-{}", u8::from(method), len);
+                assert_eq!(fs.read_file(&name).unwrap(), pseudo_random(len, len as u32));
+            }
+        }
+    }
+
+    #[test]
+    fn wipe_free_space_zeroes_deleted_data_only() {
+        let (_image, mut fs) = format_temp();
+        let cluster_size = fs.geometry().cluster_size;
+        fs.write_file(
+            "keep",
+            &pseudo_random(5000, 1),
+            Some(CompressionMethod::None),
+        )
+        .unwrap();
+        fs.write_file(
+            "gone",
+            &pseudo_random(5000, 2),
+            Some(CompressionMethod::None),
+        )
+        .unwrap();
+        let kept_chain = fs.cluster_chain("keep").unwrap();
+        let gone_chain = fs.cluster_chain("gone").unwrap();
+        fs.delete_file("gone").unwrap();
+        // 删除之后旧数据仍留在空闲簇里
+        assert_ne!(
+            fs.dump_cluster(gone_chain[0]).unwrap(),
+            vec![0u8; cluster_size]
+        );
+
+        let free = fs.free_cluster_count().unwrap();
+        assert_eq!(fs.wipe_free_space().unwrap(), free);
+        for &cluster in &gone_chain {
+            assert_eq!(fs.dump_cluster(cluster).unwrap(), vec![0u8; cluster_size]);
+        }
+        assert!(
+            kept_chain
+                .iter()
+                .all(|&cluster| fs.dump_cluster(cluster).unwrap() != vec![0u8; cluster_size])
+        );
+        assert_eq!(fs.read_file("keep").unwrap(), pseudo_random(5000, 1));
+        assert_eq!(fs.free_cluster_count().unwrap(), free);
+        assert!(fs.check().unwrap().is_empty());
+    }
+
+    #[test]
+    fn secure_delete_zeroes_the_chain_before_freeing() {
+        let (_image, mut fs) = format_temp();
+        let cluster_size = fs.geometry().cluster_size;
+        let data = pseudo_random(5000, 3);
+        fs.write_file("secret", &data, Some(CompressionMethod::None))
+            .unwrap();
+        fs.write_file("other", &pseudo_random(3000, 4), None)
+            .unwrap();
+        let chain = fs.cluster_chain("secret").unwrap();
+        let free = fs.free_cluster_count().unwrap();
+
+        fs.secure_delete("secret").unwrap();
+        assert!(!fs.exists("secret").unwrap());
+        for &cluster in &chain {
+            assert_eq!(fs.dump_cluster(cluster).unwrap(), vec![0u8; cluster_size]);
+        }
+        assert_eq!(fs.free_cluster_count().unwrap(), free + chain.len());
+        assert!(fs.undelete("secret").is_err());
+        assert_eq!(fs.read_file("other").unwrap(), pseudo_random(3000, 4));
+
+        // 内联文件的数据在目录项里，同样清掉
+        fs.write_file("tiny", b"password", None).unwrap();
+        fs.secure_delete("tiny").unwrap();
+        let root = fs.read_root_dir().unwrap();
+        assert!(!root.windows(8).any(|window| window == b"password"));
+
+        // 还有其他链接时拒绝，内容保持不变
+        fs.write_file("shared", &data, None).unwrap();
+        fs.link("shared", "alias").unwrap();
+        let err = fs.secure_delete("shared").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::ResourceBusy);
+        assert_eq!(fs.read_file("alias").unwrap(), data);
+        assert!(fs.check().unwrap().is_empty());
+    }
+
+    #[test]
+    fn names_sharing_a_32_byte_prefix_never_clobber_each_other() {
+        let (_image, mut fs) = format_temp();
+        let prefix = "p".repeat(MAX_FILENAME_LENGTH);
+        let first = format!("{}-first--", prefix);
+        let second = format!("{}-second-", prefix);
+        assert_eq!(first.len(), 40);
+        assert_eq!(second.len(), 40);
+
+        // 恰好32字节的文件名就是两个长名字截断后的样子
+        fs.write_file(&prefix, b"original", None).unwrap();
+        for name in [&first, &second] {
+            let err = fs.write_file(name, b"clobber", None).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidInput, "{}", name);
+            assert!(!fs.exists(name).unwrap());
+            let err = fs.link(&prefix, name).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidInput, "{}", name);
+        }
+        assert_eq!(fs.read_file(&prefix).unwrap(), b"original");
+        assert_eq!(sorted_names(fs.list_files().unwrap()), [prefix.as_str()]);
+
+        // 没有同前缀的文件时也一样拒绝，不会先写进第一个、再被第二个覆盖
+        fs.delete_file(&prefix).unwrap();
+        assert!(fs.write_file(&first, b"one", None).is_err());
+        assert!(fs.write_file(&second, b"two", None).is_err());
+        assert!(fs.list_files().unwrap().is_empty());
+    }
+
+    #[test]
+    fn reinit_directory_recovers_chains_after_the_directory_is_wiped() {
+        let (disk, mut fs) = MockDisk::format(FormatOptions::default());
+        let texts: Vec<Vec<u8>> = (0..3)
+            .map(|i| {
+                format!("line {} of a recoverable file\n", i)
+                    .repeat(200 * (i + 1))
+                    .into_bytes()
+            })
+            .collect();
+        for (i, text) in texts.iter().enumerate() {
+            fs.write_file(
+                &format!("text{}", i),
+                text,
+                Some(CompressionMethod::Deflate),
+            )
+            .unwrap();
+        }
+        let raw = pseudo_random(3000, 9);
+        fs.write_file("raw", &raw, Some(CompressionMethod::None))
+            .unwrap();
+        let geometry = fs.geometry();
+        drop(fs);
+
+        // 根目录区整个写成垃圾，FAT和数据区不动
+        let root = root_dir_range(&geometry);
+        disk.state().data[root.start as usize..root.end as usize].fill(0xFF);
+        let mut fs = disk.mount().unwrap();
+        assert!(fs.list_files().unwrap().is_empty());
+
+        assert_eq!(fs.reinit_directory().unwrap(), 4);
+        let names = sorted_names(fs.list_files().unwrap());
+        assert_eq!(
+            names,
+            ["found_0001", "found_0002", "found_0003", "found_0004"]
+        );
+        // DEFLATE数据按原始大小找回；未压缩的数据带着最后一个簇的空余部分
+        let mut recovered_texts = 0;
+        let mut recovered_raw = 0;
+        for name in &names {
+            let data = fs.read_file(name).unwrap();
+            if texts.contains(&data) {
+                assert_eq!(
+                    fs.compression_method(name).unwrap(),
+                    CompressionMethod::Deflate
+                );
+                recovered_texts += 1;
+            } else if data.starts_with(&raw) {
+                assert_eq!(data.len(), 2 * geometry.cluster_size);
+                recovered_raw += 1;
+            }
+        }
+        assert_eq!((recovered_texts, recovered_raw), (3, 1));
+        assert!(fs.check().unwrap().is_empty());
+
+        // 已经登记的文件不会重复找回
+        assert_eq!(fs.reinit_directory().unwrap(), 0);
+    }
+
+    #[test]
+    fn big_endian_images_round_trip_and_store_big_endian_fields() {
+        let opts = FormatOptions {
+            endianness: Endianness::Big,
+            backup_fat: true,
+            ..FormatOptions::default()
+        };
+        let (disk, mut fs) = MockDisk::format(opts);
+        let data = pseudo_random(5000, 7);
+        let entry = fs
+            .write_file("big", &data, Some(CompressionMethod::None))
+            .unwrap();
+        fs.write_file(
+            "text",
+            &b"big endian ".repeat(300),
+            Some(CompressionMethod::Zstd),
+        )
+        .unwrap();
+        fs.write_file("tiny", b"inline", None).unwrap();
+        let geometry = fs.geometry();
+        fs.close().unwrap();
+
+        // 启动扇区、FAT和目录项中的整数都按大端存放
+        let image = disk.state().data.clone();
+        let total_sectors = (image.len() / SECTOR_SIZE) as u32;
+        assert_eq!(image[17..21], total_sectors.to_be_bytes());
+        let fat = geometry.fat_start_sector(0) * SECTOR_SIZE;
+        let next = fat + entry.first_cluster as usize * 4;
+        assert_eq!(
+            image[next..next + 4],
+            (entry.first_cluster + 1).to_be_bytes()
+        );
+        let root = root_dir_range(&geometry).start as usize;
+        assert_eq!(&image[root..root + 3], b"big");
+        assert_eq!(image[root + 32..root + 36], 5000u32.to_be_bytes());
+
+        // 挂载时从启动扇区识别字节序
+        let mut fs = disk.mount().unwrap();
+        assert_eq!(fs.geometry().endianness, Endianness::Big);
+        assert_eq!(fs.read_file("big").unwrap(), data);
+        assert_eq!(fs.read_file("text").unwrap(), b"big endian ".repeat(300));
+        assert_eq!(fs.read_file("tiny").unwrap(), b"inline");
+        assert!(fs.check().unwrap().is_empty());
+        fs.delete_file("big").unwrap();
+        fs.write_file("after", &data, None).unwrap();
+        assert_eq!(fs.read_file("after").unwrap(), data);
+    }
+
+    #[test]
+    fn directory_stats_track_create_delete_churn() {
+        let (_image, mut fs) = format_temp();
+        let stats = fs.directory_stats().unwrap();
+        assert_eq!(
+            (stats.total_slots, stats.live, stats.deleted, stats.free),
+            (32, 0, 0, 32)
+        );
+
+        for i in 0..20 {
+            fs.write_file(&format!("f{}", i), &pseudo_random(100, i), None)
+                .unwrap();
+        }
+        for i in 0..15 {
+            fs.delete_file(&format!("f{}", i)).unwrap();
+        }
+        let stats = fs.directory_stats().unwrap();
+        assert_eq!((stats.live, stats.deleted, stats.free), (5, 15, 12));
+        assert_eq!(stats.slots_available(), 27);
+        assert_eq!(stats.free_clusters, fs.free_cluster_count().unwrap());
+
+        // 新文件先复用最前面的槽位，其中包括已删除的目录项
+        for i in 0..10 {
+            fs.write_file(&format!("g{}", i), b"new", None).unwrap();
+        }
+        let stats = fs.directory_stats().unwrap();
+        assert_eq!((stats.live, stats.deleted, stats.free), (15, 5, 12));
+
+        // 整理之后已删除的目录项变成空槽位
+        fs.compact_directory().unwrap();
+        let stats = fs.directory_stats().unwrap();
+        assert_eq!((stats.live, stats.deleted, stats.free), (15, 0, 17));
+        assert_eq!(stats.live + stats.slots_available(), stats.total_slots);
+    }
+
+    #[test]
+    fn write_file_vectored_matches_a_concatenated_write() {
+        let (_image, mut fs) = format_temp();
+        let header = b"HDR\x01".to_vec();
+        let body = b"structured record ".repeat(500);
+        let tail = pseudo_random(3000, 8);
+        let slices: [&[u8]; 5] = [&header, &[], &body, &tail, b"END"];
+        let joined = slices.concat();
+
+        for method in ALL_METHODS {
+            let vectored = format!("v{}", u8::from(method));
+            let single = format!("s{}", u8::from(method));
+            let v_entry = fs
+                .write_file_vectored(&vectored, &slices, Some(method))
+                .unwrap();
+            let s_entry = fs.write_file(&single, &joined, Some(method)).unwrap();
+            assert_eq!(v_entry.size, s_entry.size, "{:?}", method);
+            assert_eq!(
+                v_entry.compression_method, s_entry.compression_method,
+                "{:?}",
+                method
+            );
+            assert_eq!(fs.read_file(&vectored).unwrap(), joined, "{:?}", method);
+            // 整块压缩的方法内部先拼接，存储的字节与一次写入完全相同
+            if matches!(
+                method,
+                CompressionMethod::None | CompressionMethod::Rle | CompressionMethod::Rle2
+            ) {
+                assert_eq!(
+                    fs.read_raw(&vectored).unwrap(),
+                    fs.read_raw(&single).unwrap()
+                );
+            }
+        }
+
+        // 没有切片时写入空文件
+        let entry = fs
+            .write_file_vectored("empty", &[], Some(CompressionMethod::Deflate))
+            .unwrap();
+        assert_eq!(entry.size, 0);
+        assert!(fs.read_file("empty").unwrap().is_empty());
+    }
+
+    #[test]
+    fn read_file_prefix_peeks_without_reading_the_whole_file() {
+        let (disk, mut fs) = MockDisk::format(FormatOptions::default());
+        let mut data = b"\x89PNG".to_vec();
+        data.extend_from_slice(&pseudo_random(40_000, 6));
+        for method in ALL_METHODS {
+            let name = format!("f{}", u8::from(method));
+            fs.write_file(&name, &data, Some(method)).unwrap();
+            assert_eq!(
+                fs.read_file_prefix(&name, 4).unwrap(),
+                b"\x89PNG",
+                "{:?}",
+                method
+            );
+            assert_eq!(
+                fs.read_file_prefix(&name, 5000).unwrap(),
+                data[..5000],
+                "{:?}",
+                method
+            );
+            assert_eq!(
+                fs.read_file_prefix(&name, usize::MAX).unwrap(),
+                data,
+                "{:?}",
+                method
+            );
+            assert!(fs.read_file_prefix(&name, 0).unwrap().is_empty());
+        }
+
+        // 未压缩的文件只读第一个簇
+        let geometry = fs.geometry();
+        let chain = fs.cluster_chain("f0").unwrap();
+        assert!(chain.len() > 10);
+        disk.state().reads.clear();
+        assert_eq!(fs.read_file_prefix("f0", 4).unwrap(), b"\x89PNG");
+        let rest = geometry.cluster_offset(chain[1])
+            ..geometry.cluster_offset(chain[chain.len() - 1]) + geometry.cluster_size as u64;
+        assert_eq!(disk.reads_in(rest), 0);
+        assert_eq!(
+            disk.reads_in(geometry.cluster_offset(chain[0])..geometry.cluster_offset(chain[1])),
+            1
+        );
+
+        let err = fs.read_file_prefix("missing", 4).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn overwrite_in_place_keeps_the_first_cluster() {
+        let (_image, mut fs) = format_temp();
+        let cluster_size = fs.geometry().cluster_size;
+        let none = Some(CompressionMethod::None);
+        let first = fs
+            .write_file("config", &pseudo_random(3 * cluster_size, 0), none)
+            .unwrap()
+            .first_cluster;
+        let free = fs.free_cluster_count().unwrap();
+
+        // 默认的影子写入每次都换一条新簇链
+        let shadow = fs
+            .write_file("config", &pseudo_random(3 * cluster_size, 1), none)
+            .unwrap();
+        assert_ne!(shadow.first_cluster, first);
+        let first = shadow.first_cluster;
+
+        fs.set_overwrite_in_place(true);
+        for (round, clusters) in [3, 3, 2, 1, 4, 3].into_iter().enumerate() {
+            let data = pseudo_random(clusters * cluster_size - 7, round as u32 + 10);
+            let entry = fs.write_file("config", &data, none).unwrap();
+            assert_eq!(entry.first_cluster, first, "第{}次覆盖", round);
+            assert_eq!(fs.cluster_chain("config").unwrap().len(), clusters);
+            // 变短时释放尾部，变长时只接上缺的簇
+            assert_eq!(fs.free_cluster_count().unwrap(), free + 3 - clusters);
+            assert_eq!(fs.read_file("config").unwrap(), data);
+        }
+        assert!(fs.check().unwrap().is_empty());
+
+        // 有其他链接时仍然影子写入，链接保留旧内容
+        fs.link("config", "alias").unwrap();
+        let old = fs.read_file("config").unwrap();
+        let entry = fs.write_file("config", b"shared no more", none).unwrap();
+        assert_ne!(entry.first_cluster, first);
+        assert_eq!(fs.read_file("alias").unwrap(), old);
+        assert!(fs.check().unwrap().is_empty());
+    }
+
+    #[test]
+    fn remaining_files_predicts_which_limit_is_hit_first() {
+        // 默认布局：32个槽位远少于空闲簇，先满的是根目录
+        let (_image, mut fs) = format_temp();
+        assert_eq!(fs.max_files(), 32);
+        let predicted = fs.directory_stats().unwrap().remaining_files();
+        assert_eq!(predicted, 32);
+        let written = fill_with_one_cluster_files(&mut fs);
+        assert_eq!(written.0, predicted);
+        assert_eq!(written.1, io::ErrorKind::QuotaExceeded);
+
+        // 簇比槽位少：先满的是数据区
+        let opts = FormatOptions {
+            cluster_size: 512,
+            max_clusters: 128,
+            root_dir_sectors: 32,
+            ..FormatOptions::default()
+        };
+        let (_disk, mut fs) = MockDisk::format(opts);
+        assert_eq!(fs.max_files(), 256);
+        let stats = fs.directory_stats().unwrap();
+        assert_eq!(stats.remaining_files(), stats.free_clusters);
+        let written = fill_with_one_cluster_files(&mut fs);
+        assert_eq!(written.0, stats.remaining_files());
+        assert_eq!(written.1, io::ErrorKind::StorageFull);
+        assert_eq!(fs.directory_stats().unwrap().remaining_files(), 0);
+    }
+
+    // 写入占一个簇的文件直到失败，返回写入的文件数和失败原因
+    fn fill_with_one_cluster_files(fs: &mut FileSystem) -> (usize, io::ErrorKind) {
+        for i in 0.. {
+            let data = format!("one cluster file {}", i);
+            if let Err(e) = fs.write_file(
+                &format!("f{}", i),
+                data.as_bytes(),
+                Some(CompressionMethod::None),
+            ) {
+                return (i, e.kind());
+            }
+        }
+        unreachable!()
+    }
+
+    #[test]
+    fn comments_round_trip_and_are_refused_on_inline_files() {
+        let (_image, mut fs) = format_temp();
+        fs.write_file("photo", &pseudo_random(3000, 1), None)
+            .unwrap();
+        assert_eq!(fs.comment("photo").unwrap(), None);
+
+        // 最多8字节，按字节计算，多字节字符同样适用
+        fs.set_comment("photo", "假期照").unwrap_err();
+        fs.set_comment("photo", "假期").unwrap();
+        assert_eq!(fs.comment("photo").unwrap().as_deref(), Some("假期"));
+        fs.set_comment("photo", "8 bytes!").unwrap();
+        assert_eq!(fs.comment("photo").unwrap().as_deref(), Some("8 bytes!"));
+        for bad in ["9 bytes!!", "tab\there"] {
+            let err = fs.set_comment("photo", bad).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidInput, "{}", bad);
+        }
+        assert_eq!(fs.comment("photo").unwrap().as_deref(), Some("8 bytes!"));
+
+        // 覆盖时保留；数据变小也不会内联，备注仍有地方存放
+        fs.write_file("photo", b"tiny", None).unwrap();
+        assert!(!fs.stat("photo").unwrap().is_inline());
+        assert_eq!(fs.comment("photo").unwrap().as_deref(), Some("8 bytes!"));
+        assert_eq!(fs.read_file("photo").unwrap(), b"tiny");
+
+        // 内联文件没有空间存放备注：拒绝，不分配簇，文件保持原样
+        fs.write_file("note", b"hello", Some(CompressionMethod::Deflate))
+            .unwrap();
+        let free = fs.free_cluster_count().unwrap();
+        let err = fs.set_comment("note", "memo").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert!(fs.stat("note").unwrap().is_inline());
+        assert_eq!(fs.free_cluster_count().unwrap(), free);
+        assert_eq!(fs.read_file("note").unwrap(), b"hello");
+        fs.set_comment("note", "").unwrap();
+        assert_eq!(fs.comment("note").unwrap(), None);
+
+        // 搬到另一个镜像后备注和内容都保留
+        let (_other_image, mut other) = format_temp();
+        FileSystem::transfer(&mut fs, &mut other, "photo").unwrap();
+        assert_eq!(other.comment("photo").unwrap().as_deref(), Some("8 bytes!"));
+        assert_eq!(other.read_file("photo").unwrap(), b"tiny");
+        assert!(other.check().unwrap().is_empty());
+
+        let path = fs.path().to_string();
+        fs.write_file("doc", &pseudo_random(100, 2), None).unwrap();
+        fs.set_comment("doc", "v2").unwrap();
+        fs.close().unwrap();
+        let mut fs = FileSystem::mount(&path).unwrap();
+        assert_eq!(fs.comment("doc").unwrap().as_deref(), Some("v2"));
+        assert!(fs.check().unwrap().is_empty());
+    }
+
+    #[test]
+    fn export_tar_carries_content_and_mtime() {
+        let (_image, mut fs) = format_temp();
+        fs.set_time_source(|| 1_700_000_000);
+        fs.write_file("a.txt", &b"tar me ".repeat(500), None)
+            .unwrap();
+        fs.set_time_source(|| 1_700_000_500);
+        fs.write_file(
+            "b.bin",
+            &pseudo_random(3000, 1),
+            Some(CompressionMethod::None),
+        )
+        .unwrap();
+
+        let mut archive = Vec::new();
+        let mut steps = Vec::new();
+        fs.export_tar_with_progress(&mut archive, &mut |p| steps.push(p.done))
+            .unwrap();
+        assert_eq!(steps, [0, 1, 2]);
+
+        let mut archive = tar::Archive::new(archive.as_slice());
+        let mut seen = Vec::new();
+        for entry in archive.entries().unwrap() {
+            let mut entry = entry.unwrap();
+            let name = entry.path().unwrap().to_str().unwrap().to_string();
+            let mut data = Vec::new();
+            entry.read_to_end(&mut data).unwrap();
+            assert_eq!(data, fs.read_file(&name).unwrap(), "{}", name);
+            let modified = fs.stat(&name).unwrap().modified as u64;
+            assert_eq!(entry.header().mtime().unwrap(), modified, "{}", name);
+            seen.push((name, modified));
+        }
+        seen.sort();
+        assert_eq!(
+            seen,
+            [
+                ("a.txt".to_string(), 1_700_000_000),
+                ("b.bin".to_string(), 1_700_000_500)
+            ]
+        );
+    }
+
+    #[test]
+    fn fat_checksum_is_recomputed_once_per_operation() {
+        let opts = FormatOptions {
+            backup_fat: true,
+            ..FormatOptions::default()
+        };
+        let (disk, mut fs) = MockDisk::format(opts);
+        let geometry = fs.geometry();
+        let cluster_size = geometry.cluster_size;
+        // 整个FAT的读取（重算校验和）；单个FAT项的读取不算
+        let fat_len = geometry.max_clusters * 4;
+        let full_fat_reads = |disk: &MockDisk| {
+            disk.state()
+                .reads
+                .iter()
+                .filter(|&&(_, len)| len == fat_len)
+                .count()
+        };
+        let boot_writes = |disk: &MockDisk| {
+            disk.state()
+                .writes
+                .iter()
+                .filter(|&&(offset, _)| offset < SECTOR_SIZE as u64)
+                .count()
+        };
+
+        // 40个簇的文件：每改一项FAT都重算时要读40多遍FAT、写40多次启动扇区
+        let data = pseudo_random(40 * cluster_size, 1);
+        let none = Some(CompressionMethod::None);
+        for step in ["write", "overwrite", "delete", "reserve"] {
+            disk.state().reads.clear();
+            disk.state().writes.clear();
+            match step {
+                "write" => drop(fs.write_file("big", &data, none).unwrap()),
+                "overwrite" => drop(fs.write_file("big", &data[1..], none).unwrap()),
+                "delete" => fs.delete_file("big").unwrap(),
+                _ => drop(
+                    fs.reserve_file("log", 40 * cluster_size, CompressionMethod::None)
+                        .unwrap(),
+                ),
+            }
+            let fat_reads = full_fat_reads(&disk);
+            assert!(fat_reads <= 4, "{}: {}次读FAT", step, fat_reads);
+            let boot = boot_writes(&disk);
+            assert!(boot <= 3, "{}: {}次写启动扇区", step, boot);
+            // 每个操作结束时校验和都已经与FAT一致
+            assert!(fs.check().unwrap().is_empty(), "{}", step);
+        }
+
+        // 丢弃句柄时也会补上校验和
+        fs.write_file("last", &data, None).unwrap();
+        drop(fs);
+        let mut fs = disk.mount().unwrap();
+        assert!(fs.check().unwrap().is_empty());
+        assert!(!fs.repair_fat_from_backup().unwrap());
+    }
+
+    #[test]
+    fn touch_creates_empty_files_and_bumps_existing_mtimes() {
+        let (_image, mut fs) = format_temp();
+        fs.set_time_source(|| 1_000);
+        fs.touch("new").unwrap();
+        let entry = fs.stat("new").unwrap();
+        assert_eq!((entry.size, entry.modified), (0, 1_000));
+        assert!(entry.is_inline());
+        assert_eq!(fs.file_footprint("new").unwrap().clusters, 0);
+
+        let data = pseudo_random(5000, 1);
+        fs.write_file("kept", &data, None).unwrap();
+        let before = fs.stat("kept").unwrap();
+
+        fs.set_time_source(|| 2_000);
+        fs.touch("kept").unwrap();
+        fs.touch("new").unwrap();
+        let after = fs.stat("kept").unwrap();
+        assert_eq!(after.modified, 2_000);
+        // 只改修改时间：内容、簇链都不变
+        assert_eq!(after.first_cluster, before.first_cluster);
+        assert_eq!(after.compressed_size, before.compressed_size);
+        assert_eq!(fs.read_file("kept").unwrap(), data);
+        assert_eq!(fs.stat("new").unwrap().modified, 2_000);
+        assert_eq!(
+            sorted_names(fs.list_modified_since(1_500).unwrap()),
+            ["kept", "new"]
+        );
+    }
+
+    #[test]
+    fn repack_smart_skips_files_already_in_the_chosen_method() {
+        let (_image, mut fs) = format_temp();
+        let random = pseudo_random(8000, 1);
+        let text = b"smart repack text ".repeat(400);
+        fs.write_file("raw", &random, Some(CompressionMethod::None))
+            .unwrap();
+        fs.write_file("deflated", &text, Some(CompressionMethod::Deflate))
+            .unwrap();
+        fs.write_file("text_rle", &text, Some(CompressionMethod::Rle))
+            .unwrap();
+        fs.write_file("random_zstd", &random, Some(CompressionMethod::Zstd))
+            .unwrap();
+        let raw_cluster = fs.stat("raw").unwrap().first_cluster;
+        let deflated_cluster = fs.stat("deflated").unwrap().first_cluster;
+
+        let report = fs.repack_all(CompressionMethod::Smart).unwrap();
+        let mut repacked: Vec<&str> = report.files.iter().map(|file| file.name.as_str()).collect();
+        repacked.sort();
+        assert_eq!(repacked, ["random_zstd", "text_rle"]);
+        assert!(report.skipped.is_empty());
+
+        // 已经是Smart会选的方法的文件原样保留，簇链都没换
+        assert_eq!(fs.stat("raw").unwrap().first_cluster, raw_cluster);
+        assert_eq!(fs.stat("deflated").unwrap().first_cluster, deflated_cluster);
+        assert_eq!(
+            fs.compression_method("text_rle").unwrap(),
+            CompressionMethod::Deflate
+        );
+        assert_eq!(
+            fs.compression_method("random_zstd").unwrap(),
+            CompressionMethod::None
+        );
+        assert_eq!(fs.read_file("text_rle").unwrap(), text);
+        assert_eq!(fs.read_file("random_zstd").unwrap(), random);
+
+        // 再来一次什么都不用做
+        assert!(
+            fs.repack_all(CompressionMethod::Smart)
+                .unwrap()
+                .files
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn append_fills_the_reserved_chain_in_place() {
+        let (_image, mut fs) = format_temp();
+        let cluster_size = fs.geometry.cluster_size;
+
+        let reserved = fs
+            .reserve_file("log", 3 * cluster_size, CompressionMethod::None)
+            .unwrap();
+        assert_eq!(reserved.reserved_clusters, 3);
+        assert!(fs.check().unwrap().is_empty());
+        let free = fs.free_cluster_count().unwrap();
+
+        // 第二次追加跨过第一个簇的末尾
+        let head = vec![b'a'; cluster_size - 10];
+        let tail = pseudo_random(20, 7);
+        fs.append_file("log", &head).unwrap();
+        assert!(fs.check().unwrap().is_empty());
+        let entry = fs.append_file("log", &tail).unwrap();
+        assert!(fs.check().unwrap().is_empty());
+        assert_eq!(entry.first_cluster, reserved.first_cluster);
+        assert_eq!(entry.reserved_clusters, 3);
+        assert_eq!(fs.free_cluster_count().unwrap(), free);
+
+        let mut expected = head.clone();
+        expected.extend_from_slice(&tail);
+        assert_eq!(fs.read_file("log").unwrap(), expected);
+        let chain = fs.walk_chain(entry.first_cluster).unwrap();
+        assert_eq!(chain.len(), 3);
+        assert_eq!(
+            fs.read_cluster(chain[0]).unwrap()[cluster_size - 10..],
+            tail[..10]
+        );
+        assert_eq!(fs.read_cluster(chain[1]).unwrap()[..10], tail[10..]);
+        assert!(
+            fs.read_cluster(chain[1]).unwrap()[10..]
+                .iter()
+                .all(|&b| b == 0)
+        );
+
+        // 超出预留空间后整体重新写入，预留取消
+        let overflow = pseudo_random(2 * cluster_size, 8);
+        let entry = fs.append_file("log", &overflow).unwrap();
+        assert!(fs.check().unwrap().is_empty());
+        assert_eq!(entry.reserved_clusters, 0);
+        assert_eq!(fs.stat("log").unwrap().reserved_clusters, 0);
+        expected.extend_from_slice(&overflow);
+        assert_eq!(fs.read_file("log").unwrap(), expected);
+    }
+
+    #[test]
+    fn compressed_append_zeroes_the_tail_of_a_shorter_encoding() {
+        let (_image, mut fs) = format_temp();
+        let reserved = fs
+            .reserve_file("packed", 0, CompressionMethod::Rle2)
+            .unwrap();
+        let free = fs.free_cluster_count().unwrap();
+
+        // "aa" 只能按字面量编码成3字节，再追加一个 'a' 凑成长度3的游程后只要2字节
+        let entry = fs.append_file("packed", b"aa").unwrap();
+        assert_eq!(entry.compressed_size, 3);
+        assert!(fs.check().unwrap().is_empty());
+        let entry = fs.append_file("packed", b"a").unwrap();
+        assert!(fs.check().unwrap().is_empty());
+        assert_eq!(entry.compressed_size, 2);
+        assert_eq!(entry.first_cluster, reserved.first_cluster);
+        assert_eq!(fs.free_cluster_count().unwrap(), free);
+        assert_eq!(fs.read_file("packed").unwrap(), b"aaa");
+
+        let cluster = fs.read_cluster(entry.first_cluster).unwrap();
+        assert_eq!(cluster[..2], [0x80, b'a']);
+        assert!(cluster[2..].iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    fn reinit_directory_does_not_reuse_names_of_earlier_finds() {
+        let (disk, mut fs) = MockDisk::format(FormatOptions::default());
+        let first = pseudo_random(3000, 1);
+        fs.write_file("first", &first, Some(CompressionMethod::None))
+            .unwrap();
+        let geometry = fs.geometry();
+        drop(fs);
+
+        let root = root_dir_range(&geometry);
+        disk.state().data[root.start as usize..root.end as usize].fill(0xFF);
+        let mut fs = disk.mount().unwrap();
+        assert_eq!(fs.reinit_directory().unwrap(), 1);
+        assert_eq!(sorted_names(fs.list_files().unwrap()), ["found_0001"]);
+
+        // 第二个文件写在 found_0001 后面的槽位，只把这个槽位写成垃圾
+        let second = b"second recoverable file\n".repeat(300);
+        fs.write_file("second", &second, Some(CompressionMethod::Deflate))
+            .unwrap();
+        drop(fs);
+        let slot = root.start as usize + DIR_ENTRY_SIZE;
+        disk.state().data[slot..slot + DIR_ENTRY_SIZE].fill(0xFF);
+        let mut fs = disk.mount().unwrap();
+        assert_eq!(sorted_names(fs.list_files().unwrap()), ["found_0001"]);
+
+        assert_eq!(fs.reinit_directory().unwrap(), 1);
+        assert_eq!(
+            sorted_names(fs.list_files().unwrap()),
+            ["found_0001", "found_0002"]
+        );
+        assert!(fs.read_file("found_0001").unwrap().starts_with(&first));
+        assert_eq!(fs.read_file("found_0002").unwrap(), second);
+        assert!(fs.check().unwrap().is_empty());
     }
 }
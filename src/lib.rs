@@ -0,0 +1,2 @@
+// lib.rs
+pub mod filesystem;
@@ -1,11 +1,353 @@
 // main.rs
-use std::io::{self, Write};
+use std::env;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::Path;
 use std::process;
 
-mod filesystem;
-use filesystem::FileSystem;
+use tinyfs::filesystem::{CompressionMethod, FileSystem, Progress, method_name};
+
+const USAGE: &str = "用法:
+  tinyfs                                   进入交互式菜单
+  tinyfs --image <镜像> write <文件名> [--from <主机文件>] [--method none|rle|rle2|deflate|brotli|zstd|smart]
+  tinyfs --image <镜像> read <文件名>       将文件内容写到标准输出
+  tinyfs --image <镜像> ls [通配符]        每行: 文件名 原始大小 压缩后大小 方式 备注
+  tinyfs --image <镜像> rm <文件名|通配符>   含 * 或 ? 时删除所有匹配的文件
+  tinyfs --image <镜像> export <归档.tar>   将所有文件导出为tar归档
+  tinyfs --image <镜像> import <主机目录> [--method none|rle|rle2|deflate|brotli|zstd|smart]
+  tinyfs --image <镜像> defrag              整理碎片
+  tinyfs --image <镜像> repack <none|rle|rle2|deflate|brotli|zstd|smart>  用指定方法重新压缩所有文件
+  tinyfs --image <镜像> inspect [boot | sector <扇区号> | cluster <簇号>]";
+
+fn usage_error(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, format!("{}\n{}", msg, USAGE))
+}
+
+fn parse_method(name: &str) -> io::Result<CompressionMethod> {
+    match name {
+        "none" | "0" => Ok(CompressionMethod::None),
+        "rle" | "1" => Ok(CompressionMethod::Rle),
+        "deflate" | "2" => Ok(CompressionMethod::Deflate),
+        "rle2" | "3" => Ok(CompressionMethod::Rle2),
+        "brotli" | "4" => Ok(CompressionMethod::Brotli),
+        "zstd" | "5" => Ok(CompressionMethod::Zstd),
+        "smart" | "250" => Ok(CompressionMethod::Smart),
+        _ => Err(usage_error(&format!("未知的压缩方式: {}", name))),
+    }
+}
+
+// 在标准错误输出上原地刷新百分比，完成时换行；标准输出留给数据
+fn show_progress(label: &str) -> impl FnMut(Progress) + '_ {
+    move |progress| {
+        eprint!("\r{} {}%", label, progress.percent());
+        if progress.done == progress.total {
+            eprintln!();
+        }
+    }
+}
+
+// 非交互模式：tinyfs --image <镜像> <子命令> [参数]
+fn run_command(args: &[String]) -> io::Result<()> {
+    let (image, rest) = match args {
+        [flag, image, rest @ ..] if flag == "--image" => (image.as_str(), rest),
+        _ => return Err(usage_error("缺少 --image <镜像> 参数")),
+    };
+    let (command, rest) = match rest.split_first() {
+        Some((command, rest)) => (command.as_str(), rest),
+        None => return Err(usage_error("缺少子命令")),
+    };
+
+    match command {
+        "write" => {
+            let mut filename = None;
+            let mut from = None;
+            let mut method = CompressionMethod::default();
+
+            let mut iter = rest.iter();
+            while let Some(arg) = iter.next() {
+                match arg.as_str() {
+                    "--from" => {
+                        from = Some(iter.next().ok_or_else(|| usage_error("--from 缺少参数"))?)
+                    }
+                    "--method" => {
+                        method = parse_method(
+                            iter.next()
+                                .ok_or_else(|| usage_error("--method 缺少参数"))?,
+                        )?
+                    }
+                    _ if filename.is_none() => filename = Some(arg),
+                    _ => return Err(usage_error(&format!("多余的参数: {}", arg))),
+                }
+            }
+            let filename = filename.ok_or_else(|| usage_error("缺少文件名"))?;
+
+            let source: Box<dyn Read> = match from {
+                Some(path) => Box::new(fs::File::open(path)?),
+                None => Box::new(io::stdin()),
+            };
+
+            // 镜像不存在时创建；已存在但无法挂载时报错，避免误格式化
+            let mut filesystem = if Path::new(image).exists() {
+                FileSystem::mount(image)?
+            } else {
+                FileSystem::format(image)?
+            };
+            let entry = filesystem.write_file_from_reader(filename, source, method)?;
+            filesystem.close()?;
+            println!(
+                "已写入 {}: {} 字节 -> {} 字节 ({})",
+                entry.name,
+                entry.size,
+                entry.compressed_size,
+                method_name(entry.compression_method)
+            );
+            Ok(())
+        }
+        "read" => {
+            let [filename] = rest else {
+                return Err(usage_error("read 需要且只需要一个文件名"));
+            };
+            let mut stdout = io::stdout().lock();
+            FileSystem::mount_shared(image)?.copy_file_to(filename, &mut stdout)?;
+            stdout.flush()
+        }
+        "ls" => {
+            let mut filesystem = FileSystem::mount_shared(image)?;
+            let files = match rest {
+                [] => filesystem.list_files()?,
+                [pattern] => filesystem.list_files_matching(pattern)?,
+                _ => return Err(usage_error("ls 最多接受一个通配符")),
+            };
+            for file in files {
+                println!(
+                    "{}\t{}\t{}\t{}\t{}",
+                    file.name,
+                    file.size,
+                    file.compressed_size,
+                    method_name(file.compression_method),
+                    file.comment
+                );
+            }
+            Ok(())
+        }
+        "rm" => {
+            let [filename] = rest else {
+                return Err(usage_error("rm 需要且只需要一个文件名或通配符"));
+            };
+            let mut filesystem = FileSystem::mount(image)?;
+            if filename.contains(['*', '?']) {
+                let count = filesystem.delete_matching(filename)?;
+                filesystem.close()?;
+                println!("已删除 {} 个文件", count);
+                Ok(())
+            } else {
+                filesystem.delete_file(filename)?;
+                filesystem.close()
+            }
+        }
+        "export" => {
+            let [archive] = rest else {
+                return Err(usage_error("export 需要且只需要一个归档路径"));
+            };
+            let mut filesystem = FileSystem::mount_shared(image)?;
+            filesystem.export_tar_with_progress(
+                io::BufWriter::new(fs::File::create(archive)?),
+                &mut show_progress("导出中"),
+            )
+        }
+        "import" => {
+            let (host_dir, method) = match rest {
+                [host_dir] => (host_dir, CompressionMethod::default()),
+                [host_dir, flag, method] if flag == "--method" => (host_dir, parse_method(method)?),
+                _ => return Err(usage_error("import 需要一个主机目录路径")),
+            };
+            let mut filesystem = if Path::new(image).exists() {
+                FileSystem::mount(image)?
+            } else {
+                FileSystem::format(image)?
+            };
+            let count = filesystem.import_dir(host_dir, method)?;
+            filesystem.close()?;
+            println!("已导入 {} 个文件", count);
+            Ok(())
+        }
+        "defrag" => {
+            if !rest.is_empty() {
+                return Err(usage_error("defrag 不接受参数"));
+            }
+            let mut filesystem = FileSystem::mount(image)?;
+            filesystem.defragment_with_progress(&mut show_progress("整理中"))?;
+            filesystem.close()
+        }
+        "repack" => {
+            let [method] = rest else {
+                return Err(usage_error("repack 需要且只需要一个压缩方式"));
+            };
+            let mut filesystem = FileSystem::mount(image)?;
+            let report = filesystem.repack_all_with_progress(
+                parse_method(method)?,
+                &mut show_progress("重新压缩中"),
+            )?;
+            filesystem.close()?;
+            println!(
+                "已重新压缩 {} 个文件: {} 字节 -> {} 字节，跳过 {} 个",
+                report.files.len(),
+                report.before_bytes,
+                report.after_bytes,
+                report.skipped.len()
+            );
+            Ok(())
+        }
+        "inspect" => match rest {
+            [] => inspect_boot_sector(image),
+            [what] if what == "boot" => inspect_boot_sector(image),
+            [what, number] if what == "sector" => {
+                let sector = number
+                    .parse::<usize>()
+                    .map_err(|_| usage_error(&format!("无效的扇区号: {}", number)))?;
+                let data = FileSystem::mount_shared(image)?.dump_sector(sector)?;
+                print_hex(&data, (sector * data.len()) as u64);
+                Ok(())
+            }
+            [what, number] if what == "cluster" => {
+                let cluster = number
+                    .parse::<u32>()
+                    .map_err(|_| usage_error(&format!("无效的簇号: {}", number)))?;
+                let data = FileSystem::mount_shared(image)?.dump_cluster(cluster)?;
+                print_hex(&data, 0);
+                Ok(())
+            }
+            _ => Err(usage_error("inspect 参数错误")),
+        },
+        _ => Err(usage_error(&format!("未知的子命令: {}", command))),
+    }
+}
+
+// 直接读取镜像文件的启动扇区并逐项打印，不经过挂载检查，无法挂载的镜像也能查看
+fn inspect_boot_sector(image: &str) -> io::Result<()> {
+    let mut boot_sector = [0u8; 512];
+    fs::File::open(image)?.read_exact(&mut boot_sector)?;
+
+    // 第44字节为1时多字节字段按大端存储
+    let big_endian = boot_sector[44] == 1;
+    let u16_at = |offset: usize| {
+        let bytes = [boot_sector[offset], boot_sector[offset + 1]];
+        if big_endian {
+            u16::from_be_bytes(bytes)
+        } else {
+            u16::from_le_bytes(bytes)
+        }
+    };
+    let u32_at = |offset: usize| {
+        let bytes = boot_sector[offset..offset + 4].try_into().unwrap();
+        if big_endian {
+            u32::from_be_bytes(bytes)
+        } else {
+            u32::from_le_bytes(bytes)
+        }
+    };
+
+    println!(
+        "文件系统标识: {:?}",
+        String::from_utf8_lossy(&boot_sector[3..11])
+    );
+    println!(
+        "每簇扇区数: {} (簇大小 {} 字节)",
+        boot_sector[11],
+        boot_sector[11] as usize * 512
+    );
+    println!("保留扇区数: {}", u16_at(12));
+    println!("FAT数量: {}", boot_sector[14]);
+    println!("根目录项数: {}", u16_at(15));
+    println!("总扇区数: {}", u32_at(17));
+    println!("FAT扇区数: {}", u16_at(21));
+    println!("启动扇区校验和: {:#010x}", u32_at(23));
+    if boot_sector[14] > 1 {
+        println!("主FAT校验和: {:#010x}", u32_at(39));
+    }
+    println!(
+        "卷标: {:?}",
+        String::from_utf8_lossy(&boot_sector[27..38]).trim_end_matches([' ', '\0'])
+    );
+    match boot_sector[38] {
+        0 => println!("格式版本: 0 (旧版镜像，按版本1处理)"),
+        version => println!("格式版本: {}", version),
+    }
+    println!("字节序: {}", if big_endian { "大端" } else { "小端" });
+    if boot_sector[43] != 0 {
+        println!("脏标记: 已设置（镜像正在使用，或上次没有正常关闭）");
+    }
+    println!("签名: {:02X} {:02X}", boot_sector[510], boot_sector[511]);
+    println!();
+    print_hex(&boot_sector, 0);
+    Ok(())
+}
+
+// 每行16字节的十六进制视图，右侧显示可打印的ASCII字符
+fn print_hex(data: &[u8], base_offset: u64) {
+    for (line, chunk) in data.chunks(16).enumerate() {
+        let hex: Vec<String> = chunk.iter().map(|byte| format!("{:02x}", byte)).collect();
+        let ascii: String = chunk
+            .iter()
+            .map(|&byte| {
+                if byte.is_ascii_graphic() || byte == b' ' {
+                    byte as char
+                } else {
+                    '.'
+                }
+            })
+            .collect();
+        println!(
+            "{:08x}  {:<47}  |{}|",
+            base_offset + (line * 16) as u64,
+            hex.join(" "),
+            ascii
+        );
+    }
+}
+
+// 读取要写入的数据：手动输入一行（不含换行符），或导入主机文件的完整内容
+fn read_input_data() -> io::Result<Vec<u8>> {
+    print!("数据来源 (1-手动输入, 2-导入主机文件): ");
+    io::stdout().flush()?;
+    let mut source = String::new();
+    io::stdin().read_line(&mut source)?;
+
+    if source.trim() == "2" {
+        print!("请输入主机文件路径: ");
+        io::stdout().flush()?;
+        let mut host_path = String::new();
+        io::stdin().read_line(&mut host_path)?;
+        return fs::read(host_path.trim());
+    }
+
+    // 手动输入只去掉回车产生的换行符，其余内容原样保存；
+    // 需要保留换行或写入二进制数据时请导入主机文件
+    print!("请输入要写入的数据: ");
+    io::stdout().flush()?;
+    let mut data = String::new();
+    io::stdin().read_line(&mut data)?;
+    let line = data
+        .strip_suffix('\n')
+        .map(|line| line.strip_suffix('\r').unwrap_or(line))
+        .unwrap_or(&data);
+    Ok(line.as_bytes().to_vec())
+}
 
 fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    if !args.is_empty() {
+        if let Err(e) = run_command(&args) {
+            eprintln!("错误: {}", e);
+            process::exit(1);
+        }
+        return;
+    }
+
+    interactive();
+}
+
+fn interactive() {
     println!("1. 创建/格式化磁盘镜像");
     println!("2. 写入文件（默认压缩方式）");
     println!("3. 写入文件（自定义压缩方式）");
@@ -13,6 +355,7 @@ fn main() {
     println!("5. 列出文件");
     println!("6. 删除文件");
     println!("7. 查看文件压缩统计");
+    println!("7b. 查看整个镜像的压缩统计");
     println!("8. 退出");
 
     let mut disk_image_path = String::new();
@@ -36,6 +379,13 @@ fn main() {
                     .expect("读取输入失败");
                 disk_image_path = disk_image_path.trim().to_string();
 
+                // 换用其他镜像前正常关闭当前镜像
+                if let Some(filesystem) = fs.take()
+                    && let Err(e) = filesystem.close()
+                {
+                    println!("关闭磁盘镜像失败: {}", e);
+                }
+
                 match FileSystem::get_or_create(&disk_image_path) {
                     Ok(filesystem) => {
                         println!("磁盘镜像格式化成功: {}", disk_image_path);
@@ -56,17 +406,16 @@ fn main() {
                 io::stdin().read_line(&mut filename).expect("读取输入失败");
                 filename = filename.trim().to_string();
 
-                print!("请输入要写入的数据: ");
-                io::stdout().flush().unwrap();
-                let mut data = String::new();
-                io::stdin().read_line(&mut data).expect("读取输入失败");
+                let data = match read_input_data() {
+                    Ok(data) => data,
+                    Err(e) => {
+                        println!("读取数据失败: {}", e);
+                        continue;
+                    }
+                };
 
-                match fs
-                    .as_mut()
-                    .unwrap()
-                    .write_file(&filename, data.as_bytes(), Some(2))
-                {
-                    Ok(_) => println!("文件写入成功（使用DEFLATE压缩）"),
+                match fs.as_mut().unwrap().write_file(&filename, &data, None) {
+                    Ok(entry) => println!("文件写入成功: {}", entry),
                     Err(e) => println!("文件写入失败: {}", e),
                 }
             }
@@ -82,16 +431,23 @@ fn main() {
                 io::stdin().read_line(&mut filename).expect("读取输入失败");
                 filename = filename.trim().to_string();
 
-                print!("请输入要写入的数据: ");
-                io::stdout().flush().unwrap();
-                let mut data = String::new();
-                io::stdin().read_line(&mut data).expect("读取输入失败");
+                let data = match read_input_data() {
+                    Ok(data) => data,
+                    Err(e) => {
+                        println!("读取数据失败: {}", e);
+                        continue;
+                    }
+                };
 
                 println!("请选择压缩方式:");
                 println!("0 - 不压缩");
                 println!("1 - RLE压缩");
                 println!("2 - DEFLATE压缩");
-                print!("选择 (0-2): ");
+                println!("3 - RLE2压缩（不可压缩的数据几乎不会变大）");
+                println!("4 - Brotli压缩（压缩率高，写入较慢）");
+                println!("5 - zstd压缩");
+                println!("250 - 智能选择（看起来随机的数据不压缩，其余DEFLATE）");
+                print!("选择 (0-5, 250): ");
                 io::stdout().flush().unwrap();
 
                 let mut compression_choice = String::new();
@@ -102,18 +458,10 @@ fn main() {
 
                 match fs.as_mut().unwrap().write_file_with_compression(
                     &filename,
-                    data.as_bytes(),
+                    &data,
                     compression_method,
                 ) {
-                    Ok(_) => {
-                        let method_name = match compression_method {
-                            0 => "不压缩",
-                            1 => "RLE压缩",
-                            2 => "DEFLATE压缩",
-                            _ => "未知压缩方式",
-                        };
-                        println!("文件写入成功（使用{}）", method_name);
-                    }
+                    Ok(entry) => println!("文件写入成功: {}", entry),
                     Err(e) => println!("文件写入失败: {}", e),
                 }
             }
@@ -131,8 +479,28 @@ fn main() {
 
                 match fs.as_mut().unwrap().read_file(&filename) {
                     Ok(data) => {
-                        let content = String::from_utf8_lossy(&data);
-                        println!("文件内容: {}", content);
+                        print!("输出到 (1-屏幕, 2-主机文件): ");
+                        io::stdout().flush().unwrap();
+                        let mut target = String::new();
+                        io::stdin().read_line(&mut target).expect("读取输入失败");
+
+                        if target.trim() == "2" {
+                            print!("请输入主机文件路径: ");
+                            io::stdout().flush().unwrap();
+                            let mut host_path = String::new();
+                            io::stdin().read_line(&mut host_path).expect("读取输入失败");
+
+                            // 原样写出字节，二进制文件不会被破坏
+                            match std::fs::write(host_path.trim(), &data) {
+                                Ok(_) => {
+                                    println!("已导出 {} 字节到 {}", data.len(), host_path.trim())
+                                }
+                                Err(e) => println!("导出文件失败: {}", e),
+                            }
+                        } else {
+                            let content = String::from_utf8_lossy(&data);
+                            println!("文件内容: {}", content);
+                        }
                     }
                     Err(e) => println!("读取文件失败: {}", e),
                 }
@@ -143,23 +511,14 @@ fn main() {
                     continue;
                 }
 
-                match fs.as_mut().unwrap().list_files() {
+                match fs.as_mut().unwrap().list_files_with_clusters() {
                     Ok(files) => {
                         if files.is_empty() {
                             println!("磁盘镜像中没有文件");
                         } else {
                             println!("文件列表:");
-                            for file in files {
-                                let compression_method = match file.compression_method {
-                                    0 => "无压缩",
-                                    1 => "RLE",
-                                    2 => "DEFLATE",
-                                    _ => "未知",
-                                };
-                                println!(
-                                    "  {} (原始大小: {} 字节, 压缩后: {} 字节, 方式: {})",
-                                    file.name, file.size, file.compressed_size, compression_method
-                                );
+                            for (file, clusters) in files {
+                                println!("  {} 占用簇: {}", file, clusters);
                             }
                         }
                     }
@@ -207,7 +566,45 @@ fn main() {
                     Err(e) => println!("获取压缩统计失败: {}", e),
                 }
             }
+            "7b" => {
+                if fs.is_none() {
+                    println!("请先创建或挂载磁盘镜像");
+                    continue;
+                }
+
+                match fs.as_mut().unwrap().image_compression_summary() {
+                    Ok(summary) => {
+                        println!(
+                            "{:<12} {:>6} {:>12} {:>12}",
+                            "方式", "文件数", "原始大小", "压缩后"
+                        );
+                        for (method, usage) in &summary.by_method {
+                            println!(
+                                "{:<12} {:>6} {:>12} {:>12}",
+                                method_name(*method),
+                                usage.files,
+                                usage.original_bytes,
+                                usage.compressed_bytes
+                            );
+                        }
+                        println!(
+                            "总计: 原始 {} 字节, 压缩后 {} 字节, 压缩率 {:.2}%",
+                            summary.total_original_bytes,
+                            summary.total_compressed_bytes,
+                            summary.ratio
+                        );
+                    }
+                    Err(e) => println!("获取压缩统计失败: {}", e),
+                }
+            }
             "8" => {
+                // process::exit 不会运行析构函数，必须先显式关闭
+                if let Some(filesystem) = fs.take()
+                    && let Err(e) = filesystem.close()
+                {
+                    println!("关闭磁盘镜像失败: {}", e);
+                    process::exit(1);
+                }
                 println!("退出程序");
                 process::exit(0);
             }